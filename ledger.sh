@@ -0,0 +1,62 @@
+#!/usr/bin/env bash
+# Deploys an ICRC-1/ICRC-2 ledger to the local replica for testing ticket purchases.
+# Usage: IC_VERSION=<ic commit> ./ledger.sh <buyer principal>
+# The current dfx identity becomes the minting account, so the buyer must be another principal.
+
+set -e
+
+LEDGER_ROOT=.ledger
+
+function download_ledger() {
+  local version=$1
+
+  mkdir -p "$LEDGER_ROOT"
+  curl -fsSL -o "$LEDGER_ROOT/ledger.wasm.gz" \
+      "https://download.dfinity.systems/ic/$version/canisters/ic-icrc1-ledger.wasm.gz"
+  curl -fsSL -o "$LEDGER_ROOT/ledger.did" \
+      "https://raw.githubusercontent.com/dfinity/ic/$version/rs/rosetta-api/icrc1/ledger/ledger.did"
+
+  cat > "$LEDGER_ROOT/dfx.json" <<EOF
+{
+  "canisters": {
+    "icrc1_ledger": {
+      "type": "custom",
+      "candid": "ledger.did",
+      "wasm": "ledger.wasm.gz"
+    }
+  },
+  "version": 1
+}
+EOF
+}
+
+function deploy_ledger() {
+  local minter=$1
+  local buyer=$2
+
+  (cd "$LEDGER_ROOT" && dfx deploy icrc1_ledger --argument "(variant { Init = record {
+      token_symbol = \"TICK\";
+      token_name = \"Test Ticket Token\";
+      minting_account = record { owner = principal \"$minter\" };
+      transfer_fee = 10_000;
+      metadata = vec {};
+      feature_flags = opt record { icrc2 = true };
+      initial_balances = vec { record { record { owner = principal \"$buyer\" }; 100_000_000_000 } };
+      archive_options = record {
+        num_blocks_to_archive = 1000;
+        trigger_threshold = 2000;
+        controller_id = principal \"$minter\";
+      };
+    }})")
+}
+
+if [ -z "$IC_VERSION" ] || [ -z "$1" ]; then
+  echo "Usage: IC_VERSION=<ic commit> $0 <buyer principal>" >&2
+  exit 1
+fi
+
+MINTER=$(dfx identity get-principal)
+BUYER=$1
+
+download_ledger "$IC_VERSION"
+deploy_ledger "$MINTER" "$BUYER"
@@ -0,0 +1,230 @@
+use crate::{Error, Memory, Record, Versioned, MEMORY_MANAGER};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::time::Duration;
+
+// Passwords shorter than this are rejected
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+// Sessions are valid for 24 hours after login
+const SESSION_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Interval between the sweeps that drop expired sessions
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Define a struct for the 'Session' returned by a successful login
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Session {
+    pub token: String,
+    pub user_id: u64,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+// Define a struct for the session record kept in stable memory (without the token itself)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StoredSession {
+    user_id: u64,
+    created_at: u64,
+    expires_at: u64,
+}
+
+impl Record for StoredSession {
+    const KIND: &'static str = "session";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 128;
+}
+
+thread_local! {
+    // Sessions are keyed by the SHA-256 digest of their token
+    static SESSION_STORAGE: RefCell<StableBTreeMap<[u8; 32], Versioned<StoredSession>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+}
+
+// Function to draw 32 random bytes from the management canister
+pub(crate) async fn random_bytes() -> Result<[u8; 32], Error> {
+    let (bytes,) = raw_rand().await.map_err(|(code, msg)| Error::NotCreated {
+        msg: format!("randomness unavailable: {:?} {}", code, msg),
+    })?;
+    bytes.try_into().map_err(|_| Error::NotCreated {
+        msg: "randomness unavailable: unexpected length".to_string(),
+    })
+}
+
+// Function to check that a password is acceptable before hashing it
+pub(crate) fn validate_password(password: &str) -> Result<(), Error> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "password must be at least {} characters long",
+                MIN_PASSWORD_LENGTH
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to hash a password with Argon2id and the given random salt, returning a PHC string
+pub(crate) fn hash_password(password: &str, salt: &[u8]) -> Result<String, Error> {
+    let salt = SaltString::encode_b64(salt).map_err(|e| Error::NotCreated {
+        msg: format!("password could not be hashed: {}", e),
+    })?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| Error::NotCreated {
+            msg: format!("password could not be hashed: {}", e),
+        })
+}
+
+// Function to check a password against a stored PHC string
+pub(crate) fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Function to compare a password with a legacy plaintext one without early exit
+pub(crate) fn verify_legacy_password(password: &str, stored: &str) -> bool {
+    let (a, b) = (password.as_bytes(), stored.as_bytes());
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Function to open a new session for a user from a random token
+pub(crate) fn create_session(user_id: u64, token_bytes: [u8; 32], now: u64) -> Session {
+    let session = StoredSession {
+        user_id,
+        created_at: now,
+        expires_at: now.saturating_add(SESSION_TTL_NANOS),
+    };
+
+    // Only the digest of the token is kept, so stable memory never holds a usable token
+    let key: [u8; 32] = Sha256::digest(token_bytes).into();
+    SESSION_STORAGE.with(|sessions| sessions.borrow_mut().insert(key, Versioned::new(&session)));
+
+    Session {
+        token: hex::encode(token_bytes),
+        user_id,
+        created_at: now,
+        expires_at: session.expires_at,
+    }
+}
+
+// Function to find the stable-memory key of a session token, if it is well formed
+fn session_key(token: &str) -> Option<[u8; 32]> {
+    let token_bytes: [u8; 32] = hex::decode(token).ok()?.try_into().ok()?;
+    Some(Sha256::digest(token_bytes).into())
+}
+
+// Function to check a session token, returning the ID of the user it was opened for; expired
+// sessions are dropped when presented
+pub(crate) fn check_session(token: &str, now: u64) -> Result<u64, Error> {
+    let invalid_session = || Error::Unauthorized {
+        msg: "session is invalid or has expired".to_string(),
+    };
+    let key = session_key(token).ok_or_else(invalid_session)?;
+    let session = SESSION_STORAGE
+        .with(|sessions| sessions.borrow().get(&key))
+        .ok_or_else(invalid_session)?
+        .decode()?;
+    if session.expires_at <= now {
+        SESSION_STORAGE.with(|sessions| sessions.borrow_mut().remove(&key));
+        return Err(invalid_session());
+    }
+    Ok(session.user_id)
+}
+
+// Function to end the session opened with the given token, returning whether it existed
+pub(crate) fn end_session(token: &str) -> bool {
+    session_key(token).is_some_and(|key| {
+        SESSION_STORAGE.with(|sessions| sessions.borrow_mut().remove(&key).is_some())
+    })
+}
+
+// Function to drop every session belonging to a user
+pub(crate) fn revoke_sessions(user_id: u64) {
+    SESSION_STORAGE.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        let keys: Vec<[u8; 32]> = sessions
+            .iter()
+            .filter(|(_, session)| session.decode().map_or(true, |s| s.user_id == user_id))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            sessions.remove(&key);
+        }
+    });
+}
+
+// Function to drop the sessions that expired at the given instant, and any that cannot be read
+fn expire_sessions(now: u64) {
+    SESSION_STORAGE.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        let expired: Vec<[u8; 32]> = sessions
+            .iter()
+            .filter(|(_, session)| session.decode().map_or(true, |s| s.expires_at <= now))
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            sessions.remove(&key);
+        }
+    });
+}
+
+// Function to start the periodic sweep of expired sessions; timers do not survive upgrades, so it
+// runs after install and after every upgrade
+pub(crate) fn schedule_upkeep() {
+    ic_cdk_timers::set_timer_interval(UPKEEP_INTERVAL, || expire_sessions(time()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_is_valid_until_it_expires() {
+        let session = create_session(7, [1; 32], 1_000);
+        assert_eq!(session.expires_at, 1_000 + SESSION_TTL_NANOS);
+        assert_eq!(
+            check_session(&session.token, session.expires_at - 1).ok(),
+            Some(7)
+        );
+        assert!(check_session(&session.token, session.expires_at).is_err());
+
+        // An expired session is dropped, so it stays invalid even at an earlier instant
+        assert!(check_session(&session.token, 1_000).is_err());
+    }
+
+    #[test]
+    fn ended_and_revoked_sessions_are_invalid() {
+        let first = create_session(8, [2; 32], 0);
+        let second = create_session(8, [3; 32], 0);
+        let other = create_session(9, [4; 32], 0);
+
+        assert!(end_session(&first.token));
+        assert!(!end_session(&first.token));
+        assert!(check_session(&first.token, 1).is_err());
+
+        revoke_sessions(8);
+        assert!(check_session(&second.token, 1).is_err());
+        assert_eq!(check_session(&other.token, 1).ok(), Some(9));
+    }
+
+    #[test]
+    fn malformed_tokens_are_invalid() {
+        assert!(check_session("", 0).is_err());
+        assert!(check_session("not hex", 0).is_err());
+        assert!(check_session(&hex::encode([5u8; 16]), 0).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use crate::{
+    _authenticated_caller, _event_ticket_ids, _get_event, _get_ticket, _validate_length, codes,
+    lifecycle,
+    roles::{self, Role},
+    Error, Event, Memory, Record, TicketStatus, Versioned, MEMORY_MANAGER, TICKET_STORAGE,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Doors open two hours before an event starts
+pub(crate) const DOORS_OPEN_BEFORE_NANOS: u64 = 2 * 60 * 60 * 1_000_000_000;
+
+// Longest accepted gate name, in bytes
+const MAX_GATE_LENGTH: usize = 32;
+
+// Define a struct for the admission of a ticket at the door
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CheckIn {
+    ticket_id: u64,
+    gate: String,
+    // Door staff who scanned the ticket
+    scanned_by: Principal,
+    checked_in_at: u64,
+}
+
+impl Record for CheckIn {
+    const KIND: &'static str = "check in";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 256;
+}
+
+// Define a struct for the live admission counts of an event
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CheckInStats {
+    admitted: u64,
+    // Valid tickets not admitted yet
+    remaining: u64,
+}
+
+thread_local! {
+    // Admissions keyed by event, so the admissions of one event are contiguous
+    static CHECK_IN_STORAGE: RefCell<StableBTreeMap<(u64, u64), Versioned<CheckIn>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+}
+
+// Function to check that the caller works the door of an event or manages it
+pub(crate) fn ensure_door_staff(event: &Event, caller: &Principal) -> Result<(), Error> {
+    let event_id = event.id;
+    if !roles::has_role(caller, &Role::DoorStaff { event_id })
+        && !roles::is_event_manager(event, caller)
+    {
+        return Err(Error::Unauthorized {
+            msg: format!("caller is not door staff for event id:{}", event_id),
+        });
+    }
+    Ok(())
+}
+
+// Function to remove the admission of a deleted ticket
+pub(crate) fn remove_check_in(event_id: u64, ticket_id: u64) {
+    CHECK_IN_STORAGE.with(|check_ins| check_ins.borrow_mut().remove(&(event_id, ticket_id)));
+}
+
+#[ic_cdk::update]
+fn check_in(event_id: u64, ticket_code: String, gate: String) -> Result<CheckIn, Error> {
+    let caller = _authenticated_caller()?;
+    _validate_length("gate", &gate, MAX_GATE_LENGTH)?;
+
+    // Retrieve the event, or return a NotFound error if not found
+    let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+
+    // Only door staff of the event, or its managers, may admit its ticket holders
+    ensure_door_staff(&event, &caller)?;
+
+    // The code must be signed for a ticket of the event; any other code is reported as invalid
+    let mut ticket = codes::verify(&ticket_code)?;
+    if ticket.event_id != event_id {
+        return Err(Error::NotFound {
+            msg: format!("ticket code is not valid for event id:{}", event_id),
+        });
+    }
+    let ticket_id = ticket.id;
+
+    // A ticket scanned before reports its first admission
+    if let Some(first) = CHECK_IN_STORAGE
+        .with(|check_ins| check_ins.borrow().get(&(event_id, ticket_id)))
+        .map(|first| first.decode())
+        .transpose()?
+    {
+        return Err(Error::AlreadyUsed {
+            msg: format!("ticket id:{} is already used", ticket_id),
+            checked_in_at: first.checked_in_at,
+            gate: first.gate,
+        });
+    }
+
+    // Doors are open from shortly before the event starts until it ends
+    let now = time();
+    if event.cancelled_at.is_some()
+        || now < event.starts_at.saturating_sub(DOORS_OPEN_BEFORE_NANOS)
+        || now >= event.ends_at
+    {
+        return Err(Error::Conflict {
+            msg: format!("event id:{} is not admitting", event_id),
+        });
+    }
+
+    // Mark the ticket used and record the admission in the same call, so a second scan always
+    // sees the first
+    lifecycle::transition(&mut ticket, TicketStatus::CheckedIn, caller)?;
+    let check_in = CheckIn {
+        ticket_id,
+        gate,
+        scanned_by: caller,
+        checked_in_at: now,
+    };
+    TICKET_STORAGE.with(|tickets| {
+        tickets
+            .borrow_mut()
+            .insert(ticket_id, Versioned::new(&ticket))
+    });
+    CHECK_IN_STORAGE.with(|check_ins| {
+        check_ins
+            .borrow_mut()
+            .insert((event_id, ticket_id), Versioned::new(&check_in))
+    });
+    Ok(check_in)
+}
+
+#[ic_cdk::query]
+fn check_in_stats(event_id: u64) -> Result<CheckInStats, Error> {
+    // Retrieve the event, or return a NotFound error if not found
+    let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+
+    // Only door staff of the event, or its managers, may follow its admissions
+    ensure_door_staff(&event, &ic_cdk::caller())?;
+
+    let admitted = CHECK_IN_STORAGE.with(|check_ins| {
+        check_ins
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .count()
+    });
+    let mut remaining = 0;
+    for ticket_id in _event_ticket_ids(event_id) {
+        if let Some(ticket) = _get_ticket(&ticket_id)? {
+            if ticket.status.is_valid() {
+                remaining += 1;
+            }
+        }
+    }
+    Ok(CheckInStats {
+        admitted: admitted as u64,
+        remaining,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_check_in_fits_its_slot() {
+        let check_in = CheckIn {
+            ticket_id: u64::MAX,
+            gate: "g".repeat(MAX_GATE_LENGTH),
+            scanned_by: Principal::from_slice(&[0xff; 29]),
+            checked_in_at: u64::MAX,
+        };
+        assert!(Versioned::try_new(&check_in).is_ok());
+    }
+}
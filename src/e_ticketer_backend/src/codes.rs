@@ -0,0 +1,251 @@
+use crate::lifecycle::TicketStatus;
+use crate::{_get_ticket, _get_user, auth, Error, Memory, Ticket, MEMORY_MANAGER, TICKET_STORAGE};
+use hmac::{Hmac, Mac};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use sha2::Sha256;
+use std::cell::RefCell;
+use std::time::Duration;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// An all-zero key marks a canister that has not drawn its key yet
+const UNSET_KEY: [u8; 32] = [0; 32];
+
+// Delay before drawing the key again when randomness is unavailable
+const KEY_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+// Define a struct for what door staff learn from a valid ticket code
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TicketAdmission {
+    ticket_id: u64,
+    event_id: u64,
+    tier_id: Option<u64>,
+    seat_id: Option<u64>,
+    status: TicketStatus,
+}
+
+thread_local! {
+    // Secret key that signs ticket codes, drawn from `raw_rand` once and kept across upgrades
+    static CODE_KEY: RefCell<Cell<[u8; 32], Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))), UNSET_KEY)
+            .expect("Cannot create the code key")
+    );
+
+    // Secret the nonces of ticket codes are derived from, drawn from `raw_rand`; it moves forward
+    // with every nonce and is then mixed with a fresh draw
+    static NONCE_SEED: RefCell<Cell<[u8; 32], Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38))), UNSET_KEY)
+            .expect("Cannot create the nonce seed")
+    );
+
+    // Nonce of each ticket, part of the MAC of its code and renewed whenever it changes holders
+    static CODE_NONCES: RefCell<StableBTreeMap<u64, [u8; 32], Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+    ));
+
+    // Whether a fresh draw is already on its way into the nonce seed
+    static RESEED_PENDING: RefCell<bool> = const { RefCell::new(false) };
+}
+
+// Function to get the code key, or None until it has been drawn
+fn key() -> Option<[u8; 32]> {
+    CODE_KEY.with(|key| Some(*key.borrow().get()).filter(|key| *key != UNSET_KEY))
+}
+
+// Function to get the nonce seed, or None until it has been drawn
+fn seed() -> Option<[u8; 32]> {
+    NONCE_SEED.with(|seed| Some(*seed.borrow().get()).filter(|seed| *seed != UNSET_KEY))
+}
+
+// Function to compute an HMAC-SHA256 tag over the given parts
+fn tag(key: &[u8; 32], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+// Function to give a ticket a fresh nonce, so every code issued for it before stops verifying; a
+// ticket renewed before the seed is drawn gets its nonce once it is
+pub(crate) fn renew_nonce(ticket_id: u64) {
+    let Some(seed) = seed() else {
+        CODE_NONCES.with(|nonces| nonces.borrow_mut().remove(&ticket_id));
+        return;
+    };
+    let nonce = tag(&seed, &[b"nonce", &ticket_id.to_be_bytes()]);
+    NONCE_SEED.with(|cell| {
+        cell.borrow_mut()
+            .set(tag(&seed, &[b"next"]))
+            .expect("Cannot set the nonce seed")
+    });
+    CODE_NONCES.with(|nonces| nonces.borrow_mut().insert(ticket_id, nonce));
+    schedule_reseed();
+}
+
+// Function to remove the nonce of a deleted ticket
+pub(crate) fn remove_nonce(ticket_id: u64) {
+    CODE_NONCES.with(|nonces| nonces.borrow_mut().remove(&ticket_id));
+}
+
+// Function to mix a fresh `raw_rand` draw into the nonce seed after it was used, unless a draw is
+// already on its way
+fn schedule_reseed() {
+    if RESEED_PENDING.with(|pending| pending.replace(true)) {
+        return;
+    }
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            let drawn = auth::random_bytes().await;
+            RESEED_PENDING.with(|pending| *pending.borrow_mut() = false);
+            match (drawn, seed()) {
+                (Ok(bytes), Some(seed)) => {
+                    NONCE_SEED.with(|cell| {
+                        cell.borrow_mut()
+                            .set(tag(&seed, &[b"reseed", &bytes]))
+                            .expect("Cannot set the nonce seed")
+                    });
+                }
+                (Ok(_), None) => (),
+                (Err(_), _) => {
+                    ic_cdk_timers::set_timer(KEY_RETRY_DELAY, schedule_reseed);
+                }
+            }
+        })
+    });
+}
+
+// Function to give a nonce to every ticket that has none, once the seed is drawn
+fn assign_missing_nonces() {
+    let ticket_ids: Vec<u64> = TICKET_STORAGE.with(|tickets| {
+        tickets
+            .borrow()
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|id| !CODE_NONCES.with(|nonces| nonces.borrow().contains_key(id)))
+            .collect()
+    });
+    for ticket_id in ticket_ids {
+        renew_nonce(ticket_id);
+    }
+}
+
+// Function to derive a secret for another purpose from the code key, so one drawn secret serves them
+// all; None until the code key has been drawn
+#[cfg(feature = "local-signing")]
+pub(crate) fn derived_key(label: &[u8]) -> Option<[u8; 32]> {
+    let key = key()?;
+    let mut mac = HmacSha256::new_from_slice(&key).expect("HMAC takes keys of any size");
+    mac.update(label);
+    Some(mac.finalize().into_bytes().into())
+}
+
+// Function to draw the code key and then the nonce seed right after install or upgrade, unless the
+// canister already has them
+pub(crate) fn schedule_key_setup(delay: Duration) {
+    if key().is_some() && seed().is_some() {
+        return;
+    }
+    ic_cdk_timers::set_timer(delay, || {
+        ic_cdk::spawn(async {
+            match auth::random_bytes().await {
+                Ok(bytes) if key().is_none() => {
+                    CODE_KEY.with(|key| {
+                        key.borrow_mut()
+                            .set(bytes)
+                            .expect("Cannot set the code key")
+                    });
+                    schedule_key_setup(Duration::ZERO);
+                }
+                // Tickets issued before the seed was drawn, or before codes had nonces, get one now
+                Ok(bytes) if seed().is_none() => {
+                    NONCE_SEED.with(|seed| {
+                        seed.borrow_mut()
+                            .set(bytes)
+                            .expect("Cannot set the nonce seed")
+                    });
+                    assign_missing_nonces();
+                }
+                Ok(_) => (),
+                Err(_) => schedule_key_setup(KEY_RETRY_DELAY),
+            }
+        })
+    });
+}
+
+// Function to compute the MAC binding a ticket to its event, holder and nonce; tickets waiting for
+// their first nonce are bound to their event and holder only
+fn mac(key: &[u8; 32], ticket: &Ticket) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(&ticket.id.to_be_bytes());
+    mac.update(&ticket.event_id.to_be_bytes());
+    mac.update(&ticket.user_id.to_be_bytes());
+    if let Some(nonce) = CODE_NONCES.with(|nonces| nonces.borrow().get(&ticket.id)) {
+        mac.update(&nonce);
+    }
+    mac
+}
+
+// Function to get the code of a ticket: its id and the hex MAC over its id, event, holder and nonce,
+// so a code stops verifying as soon as the ticket moves to another event or holder, even if it later
+// comes back
+fn code(ticket: &Ticket) -> Result<String, Error> {
+    let key = key().ok_or(Error::NotCreated {
+        msg: "ticket codes are not available yet, retry shortly".to_string(),
+    })?;
+    let tag = mac(&key, ticket).finalize().into_bytes();
+    Ok(format!("{}.{}", ticket.id, hex::encode(tag)))
+}
+
+// Function to find the ticket a code was issued for, rejecting codes that are malformed, forged or
+// issued before the ticket moved
+pub(crate) fn verify(ticket_code: &str) -> Result<Ticket, Error> {
+    let invalid = || Error::NotFound {
+        msg: "ticket code is not valid".to_string(),
+    };
+    let key = key().ok_or_else(invalid)?;
+    let (id, tag) = ticket_code.trim().split_once('.').ok_or_else(invalid)?;
+    let id: u64 = id.parse().map_err(|_| invalid())?;
+    let tag = hex::decode(tag).map_err(|_| invalid())?;
+
+    // The MAC is compared in constant time
+    let ticket = _get_ticket(&id)?.ok_or_else(invalid)?;
+    mac(&key, &ticket)
+        .verify_slice(&tag)
+        .map_err(|_| invalid())?;
+    Ok(ticket)
+}
+
+#[ic_cdk::query]
+fn get_ticket_code(id: u64) -> Result<String, Error> {
+    // Retrieve the ticket with the given ID, or return a NotFound error if not found
+    let ticket = _get_ticket(&id)?.ok_or(Error::NotFound {
+        msg: format!("ticket id:{} does not exist", id),
+    })?;
+
+    // Only the holder of the ticket may read its code
+    let is_holder =
+        _get_user(&ticket.user_id)?.is_some_and(|user| user.principal == ic_cdk::caller());
+    if !is_holder {
+        return Err(Error::Unauthorized {
+            msg: format!("caller does not hold ticket id:{}", id),
+        });
+    }
+    code(&ticket)
+}
+
+#[ic_cdk::query]
+fn verify_ticket_code(ticket_code: String) -> Result<TicketAdmission, Error> {
+    // Return what the code admits to, without the holder or the payment; scanners check its event
+    // and status
+    let ticket = verify(&ticket_code)?;
+    Ok(TicketAdmission {
+        ticket_id: ticket.id,
+        event_id: ticket.event_id,
+        tier_id: ticket.tier_id,
+        seat_id: ticket.seat_id,
+        status: ticket.status,
+    })
+}
@@ -0,0 +1,791 @@
+use crate::{
+    _authenticated_caller, _principal_key,
+    icrc7::{self, Refusal, Rejection, Value},
+    ledger::Account,
+    Memory, PrincipalKey, Record, Versioned, MEMORY_MANAGER,
+};
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Most approvals a token, or a holder's collection, may carry at once
+const MAX_APPROVALS: usize = 10;
+
+// Most approvals a single revoke call removes
+const MAX_REVOKE_APPROVALS: usize = 20;
+
+// Define a struct for an approval letting a spender transfer tokens
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ApprovalInfo {
+    spender: Account,
+    from_subaccount: Option<Vec<u8>>,
+    // The approval lapses at this instant, in nanoseconds since epoch
+    expires_at: Option<u64>,
+    memo: Option<Vec<u8>>,
+    created_at_time: u64,
+}
+
+// Define a struct for an approval of one token, as stored with the user who granted it, so it
+// lapses as soon as the token changes holders
+#[derive(Clone, Serialize, Deserialize, CandidType)]
+struct StoredApproval {
+    owner_user_id: u64,
+    info: ApprovalInfo,
+}
+
+impl Record for StoredApproval {
+    const KIND: &'static str = "token approval";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 384;
+}
+
+impl Record for ApprovalInfo {
+    const KIND: &'static str = "collection approval";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 384;
+}
+
+// Define a struct for an approval of one token
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TokenApproval {
+    token_id: Nat,
+    approval_info: ApprovalInfo,
+}
+
+// Define structs for the arguments of the ICRC-37 updates and queries
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct ApproveTokenArg {
+    token_id: Nat,
+    approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct ApproveCollectionArg {
+    approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct RevokeTokenApprovalArg {
+    spender: Option<Account>,
+    from_subaccount: Option<Vec<u8>>,
+    token_id: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct RevokeCollectionApprovalArg {
+    spender: Option<Account>,
+    from_subaccount: Option<Vec<u8>>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct IsApprovedArg {
+    spender: Account,
+    from_subaccount: Option<Vec<u8>>,
+    token_id: Nat,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct TransferFromArg {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    token_id: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// Define enums for the reasons the ICRC-37 updates fail
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) enum ApproveTokenError {
+    InvalidSpender,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) enum ApproveCollectionError {
+    InvalidSpender,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) enum RevokeTokenApprovalError {
+    ApprovalDoesNotExist,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) enum RevokeCollectionApprovalError {
+    ApprovalDoesNotExist,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) enum TransferFromError {
+    InvalidRecipient,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+// Define an enum for the failures every ICRC-37 update shares, converted into its own error type
+enum Failure {
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    Generic { error_code: Nat, message: String },
+}
+
+impl From<Rejection> for Failure {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::TooOld => Failure::TooOld,
+            Rejection::CreatedInFuture { ledger_time } => Failure::CreatedInFuture { ledger_time },
+            Rejection::Duplicate { duplicate_of } => Failure::Duplicate { duplicate_of },
+            Rejection::Generic(error) => {
+                let (error_code, message) = icrc7::rules_error(error);
+                Failure::Generic {
+                    error_code,
+                    message,
+                }
+            }
+        }
+    }
+}
+
+thread_local! {
+    // Approvals of single tokens, keyed by token and by spender
+    static TOKEN_APPROVALS: RefCell<StableBTreeMap<(u64, PrincipalKey), Versioned<StoredApproval>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+
+    // Approvals of every token of a holder, keyed by holder and by spender
+    static COLLECTION_APPROVALS: RefCell<StableBTreeMap<(PrincipalKey, PrincipalKey), Versioned<ApprovalInfo>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+}
+
+// Function to remove the approvals of a token that changed holders or was deleted
+pub(crate) fn clear_token_approvals(ticket_id: u64) {
+    TOKEN_APPROVALS.with(|approvals| {
+        let mut approvals = approvals.borrow_mut();
+        let keys: Vec<(u64, PrincipalKey)> = approvals
+            .range((ticket_id, PrincipalKey::default())..)
+            .take_while(|((token, _), _)| *token == ticket_id)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            approvals.remove(&key);
+        }
+    });
+}
+
+// Function to check whether an approval is still in force at the given instant
+fn is_live(info: &ApprovalInfo, now: u64) -> bool {
+    info.expires_at.is_none_or(|expires_at| expires_at > now)
+}
+
+// Function to list the approvals in force for a token held by the given user
+fn token_approvals(ticket_id: u64, owner_user_id: u64, now: u64) -> Vec<ApprovalInfo> {
+    TOKEN_APPROVALS.with(|approvals| {
+        approvals
+            .borrow()
+            .range((ticket_id, PrincipalKey::default())..)
+            .take_while(|((token, _), _)| *token == ticket_id)
+            .filter_map(|(_, approval)| approval.decode().ok())
+            .filter(|approval| {
+                approval.owner_user_id == owner_user_id && is_live(&approval.info, now)
+            })
+            .map(|approval| approval.info)
+            .collect()
+    })
+}
+
+// Function to list the collection approvals in force granted by a holder
+fn collection_approvals(owner: &Principal, now: u64) -> Vec<ApprovalInfo> {
+    let owner_key = _principal_key(owner);
+    COLLECTION_APPROVALS.with(|approvals| {
+        approvals
+            .borrow()
+            .range((owner_key, PrincipalKey::default())..)
+            .take_while(|((holder, _), _)| *holder == owner_key)
+            .filter_map(|(_, info)| info.decode().ok())
+            .filter(|info| is_live(info, now))
+            .collect()
+    })
+}
+
+// Function to check whether a spender account may transfer a token
+fn is_approved(ticket: &crate::Ticket, owner: &Principal, spender: &Account, now: u64) -> bool {
+    let matches = |info: &ApprovalInfo| {
+        info.spender.owner == spender.owner
+            && icrc7::is_default_subaccount(&info.spender.subaccount)
+                == icrc7::is_default_subaccount(&spender.subaccount)
+            && (icrc7::is_default_subaccount(&spender.subaccount)
+                || info.spender.subaccount == spender.subaccount)
+    };
+    token_approvals(ticket.id, ticket.user_id, now)
+        .iter()
+        .any(matches)
+        || collection_approvals(owner, now).iter().any(matches)
+}
+
+// Function to check the terms of an approval a holder grants
+fn check_approval(info: &ApprovalInfo, now: u64) -> Result<(), Failure> {
+    if info.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(Failure::Generic {
+            error_code: Nat::from(icrc7::RULES_ERROR_CODE),
+            message: "approval expires in the past".to_string(),
+        });
+    }
+    if !icrc7::is_default_subaccount(&info.from_subaccount) {
+        return Err(Failure::Generic {
+            error_code: Nat::from(icrc7::RULES_ERROR_CODE),
+            message: "only default accounts hold tickets".to_string(),
+        });
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn icrc37_metadata() -> Vec<(String, Value)> {
+    // Return the approval limits of the collection
+    vec![
+        (
+            "icrc37:max_approvals_per_token_or_collection".to_string(),
+            Value::Nat(Nat::from(MAX_APPROVALS)),
+        ),
+        (
+            "icrc37:max_revoke_approvals".to_string(),
+            Value::Nat(Nat::from(MAX_REVOKE_APPROVALS)),
+        ),
+    ]
+}
+
+#[ic_cdk::query]
+fn icrc37_max_approvals_per_token_or_collection() -> Option<Nat> {
+    Some(Nat::from(MAX_APPROVALS))
+}
+
+#[ic_cdk::query]
+fn icrc37_max_revoke_approvals() -> Option<Nat> {
+    Some(Nat::from(MAX_REVOKE_APPROVALS))
+}
+
+#[ic_cdk::update]
+fn icrc37_approve_tokens(
+    args: Vec<ApproveTokenArg>,
+) -> Vec<Option<Result<Nat, ApproveTokenError>>> {
+    if let Some((error_code, message)) = icrc7::batch_error(args.len()) {
+        return vec![Some(Err(ApproveTokenError::GenericBatchError {
+            error_code,
+            message,
+        }))];
+    }
+    let caller = match _authenticated_caller() {
+        Ok(caller) => caller,
+        Err(_) => {
+            return args
+                .iter()
+                .map(|_| Some(Err(ApproveTokenError::Unauthorized)))
+                .collect()
+        }
+    };
+    args.iter()
+        .map(|arg| Some(approve_token(caller, arg)))
+        .collect()
+}
+
+// Function to run one token approval
+fn approve_token(caller: Principal, arg: &ApproveTokenArg) -> Result<Nat, ApproveTokenError> {
+    let failure = |failure: Failure| match failure {
+        Failure::TooOld => ApproveTokenError::TooOld,
+        Failure::CreatedInFuture { ledger_time } => {
+            ApproveTokenError::CreatedInFuture { ledger_time }
+        }
+        Failure::Duplicate { .. } => ApproveTokenError::GenericError {
+            error_code: Nat::from(icrc7::RULES_ERROR_CODE),
+            message: "approval was already granted".to_string(),
+        },
+        Failure::Generic {
+            error_code,
+            message,
+        } => ApproveTokenError::GenericError {
+            error_code,
+            message,
+        },
+    };
+    let info = &arg.approval_info;
+    let hash = icrc7::screen(
+        "icrc37_approve_tokens",
+        &caller,
+        arg,
+        &info.memo,
+        Some(info.created_at_time),
+    )
+    .map_err(|rejection| failure(rejection.into()))?;
+
+    // Only the holder of a token may approve a spender for it, and not itself
+    let ticket = icrc7::token(&arg.token_id)
+        .ok()
+        .flatten()
+        .ok_or(ApproveTokenError::NonExistingTokenId)?;
+    if icrc7::holder(&ticket).ok().flatten() != Some(caller) {
+        return Err(ApproveTokenError::Unauthorized);
+    }
+    if info.spender.owner == caller {
+        return Err(ApproveTokenError::InvalidSpender);
+    }
+    let now = time();
+    check_approval(info, now).map_err(failure)?;
+
+    // Approving a spender again replaces its approval
+    let spender_key = _principal_key(&info.spender.owner);
+    let replaces = TOKEN_APPROVALS.with(|approvals| {
+        approvals
+            .borrow()
+            .get(&(ticket.id, spender_key))
+            .is_some_and(|approval| {
+                approval
+                    .decode()
+                    .is_ok_and(|approval| approval.owner_user_id == ticket.user_id)
+            })
+    });
+    if !replaces && token_approvals(ticket.id, ticket.user_id, now).len() >= MAX_APPROVALS {
+        return Err(ApproveTokenError::GenericError {
+            error_code: Nat::from(icrc7::RULES_ERROR_CODE),
+            message: format!("token carries {} approvals already", MAX_APPROVALS),
+        });
+    }
+    TOKEN_APPROVALS.with(|approvals| {
+        approvals.borrow_mut().insert(
+            (ticket.id, spender_key),
+            Versioned::new(&StoredApproval {
+                owner_user_id: ticket.user_id,
+                info: info.clone(),
+            }),
+        )
+    });
+    let index = icrc7::next_tx_index();
+    icrc7::remember(hash, &index, Some(info.created_at_time));
+    Ok(index)
+}
+
+#[ic_cdk::update]
+fn icrc37_approve_collection(
+    args: Vec<ApproveCollectionArg>,
+) -> Vec<Option<Result<Nat, ApproveCollectionError>>> {
+    if let Some((error_code, message)) = icrc7::batch_error(args.len()) {
+        return vec![Some(Err(ApproveCollectionError::GenericBatchError {
+            error_code,
+            message,
+        }))];
+    }
+    let caller = match _authenticated_caller() {
+        Ok(caller) => caller,
+        Err(error) => {
+            let (error_code, message) = icrc7::rules_error(error);
+            return args
+                .iter()
+                .map(|_| {
+                    Some(Err(ApproveCollectionError::GenericError {
+                        error_code: error_code.clone(),
+                        message: message.clone(),
+                    }))
+                })
+                .collect();
+        }
+    };
+    args.iter()
+        .map(|arg| Some(approve_collection(caller, arg)))
+        .collect()
+}
+
+// Function to run one collection approval
+fn approve_collection(
+    caller: Principal,
+    arg: &ApproveCollectionArg,
+) -> Result<Nat, ApproveCollectionError> {
+    let failure = |failure: Failure| match failure {
+        Failure::TooOld => ApproveCollectionError::TooOld,
+        Failure::CreatedInFuture { ledger_time } => {
+            ApproveCollectionError::CreatedInFuture { ledger_time }
+        }
+        Failure::Duplicate { .. } => ApproveCollectionError::GenericError {
+            error_code: Nat::from(icrc7::RULES_ERROR_CODE),
+            message: "approval was already granted".to_string(),
+        },
+        Failure::Generic {
+            error_code,
+            message,
+        } => ApproveCollectionError::GenericError {
+            error_code,
+            message,
+        },
+    };
+    let info = &arg.approval_info;
+    let hash = icrc7::screen(
+        "icrc37_approve_collection",
+        &caller,
+        arg,
+        &info.memo,
+        Some(info.created_at_time),
+    )
+    .map_err(|rejection| failure(rejection.into()))?;
+    if info.spender.owner == caller {
+        return Err(ApproveCollectionError::InvalidSpender);
+    }
+    let now = time();
+    check_approval(info, now).map_err(failure)?;
+
+    // Approving a spender again replaces its approval
+    let key = (_principal_key(&caller), _principal_key(&info.spender.owner));
+    let replaces = COLLECTION_APPROVALS.with(|approvals| approvals.borrow().contains_key(&key));
+    if !replaces && collection_approvals(&caller, now).len() >= MAX_APPROVALS {
+        return Err(ApproveCollectionError::GenericError {
+            error_code: Nat::from(icrc7::RULES_ERROR_CODE),
+            message: format!("collection carries {} approvals already", MAX_APPROVALS),
+        });
+    }
+    COLLECTION_APPROVALS.with(|approvals| approvals.borrow_mut().insert(key, Versioned::new(info)));
+    let index = icrc7::next_tx_index();
+    icrc7::remember(hash, &index, Some(info.created_at_time));
+    Ok(index)
+}
+
+#[ic_cdk::update]
+fn icrc37_revoke_token_approvals(
+    args: Vec<RevokeTokenApprovalArg>,
+) -> Vec<Option<Result<Nat, RevokeTokenApprovalError>>> {
+    if let Some((error_code, message)) = icrc7::batch_error(args.len()) {
+        return vec![Some(Err(RevokeTokenApprovalError::GenericBatchError {
+            error_code,
+            message,
+        }))];
+    }
+    let caller = match _authenticated_caller() {
+        Ok(caller) => caller,
+        Err(_) => {
+            return args
+                .iter()
+                .map(|_| Some(Err(RevokeTokenApprovalError::Unauthorized)))
+                .collect()
+        }
+    };
+    args.iter()
+        .map(|arg| Some(revoke_token_approvals(caller, arg)))
+        .collect()
+}
+
+// Function to run one token approval revocation
+fn revoke_token_approvals(
+    caller: Principal,
+    arg: &RevokeTokenApprovalArg,
+) -> Result<Nat, RevokeTokenApprovalError> {
+    let hash = icrc7::screen(
+        "icrc37_revoke_token_approvals",
+        &caller,
+        arg,
+        &arg.memo,
+        arg.created_at_time,
+    )
+    .map_err(|rejection| match Failure::from(rejection) {
+        Failure::TooOld => RevokeTokenApprovalError::TooOld,
+        Failure::CreatedInFuture { ledger_time } => {
+            RevokeTokenApprovalError::CreatedInFuture { ledger_time }
+        }
+        Failure::Duplicate { .. } => RevokeTokenApprovalError::ApprovalDoesNotExist,
+        Failure::Generic {
+            error_code,
+            message,
+        } => RevokeTokenApprovalError::GenericError {
+            error_code,
+            message,
+        },
+    })?;
+
+    // Only the holder of a token may revoke its approvals
+    let ticket = icrc7::token(&arg.token_id)
+        .ok()
+        .flatten()
+        .ok_or(RevokeTokenApprovalError::NonExistingTokenId)?;
+    if icrc7::holder(&ticket).ok().flatten() != Some(caller)
+        || !icrc7::is_default_subaccount(&arg.from_subaccount)
+    {
+        return Err(RevokeTokenApprovalError::Unauthorized);
+    }
+
+    // Revoke the given spender, or every spender when none is given
+    match &arg.spender {
+        Some(spender) => {
+            let key = (ticket.id, _principal_key(&spender.owner));
+            let removed = TOKEN_APPROVALS.with(|approvals| approvals.borrow_mut().remove(&key));
+            if removed.is_none_or(|approval| {
+                approval
+                    .decode()
+                    .is_ok_and(|approval| approval.owner_user_id != ticket.user_id)
+            }) {
+                return Err(RevokeTokenApprovalError::ApprovalDoesNotExist);
+            }
+        }
+        None => {
+            if token_approvals(ticket.id, ticket.user_id, time()).is_empty() {
+                return Err(RevokeTokenApprovalError::ApprovalDoesNotExist);
+            }
+            clear_token_approvals(ticket.id);
+        }
+    }
+    let index = icrc7::next_tx_index();
+    icrc7::remember(hash, &index, arg.created_at_time);
+    Ok(index)
+}
+
+#[ic_cdk::update]
+fn icrc37_revoke_collection_approvals(
+    args: Vec<RevokeCollectionApprovalArg>,
+) -> Vec<Option<Result<Nat, RevokeCollectionApprovalError>>> {
+    if let Some((error_code, message)) = icrc7::batch_error(args.len()) {
+        return vec![Some(Err(
+            RevokeCollectionApprovalError::GenericBatchError {
+                error_code,
+                message,
+            },
+        ))];
+    }
+    let caller = ic_cdk::caller();
+    args.iter()
+        .map(|arg| Some(revoke_collection_approvals(caller, arg)))
+        .collect()
+}
+
+// Function to run one collection approval revocation
+fn revoke_collection_approvals(
+    caller: Principal,
+    arg: &RevokeCollectionApprovalArg,
+) -> Result<Nat, RevokeCollectionApprovalError> {
+    let hash = icrc7::screen(
+        "icrc37_revoke_collection_approvals",
+        &caller,
+        arg,
+        &arg.memo,
+        arg.created_at_time,
+    )
+    .map_err(|rejection| match Failure::from(rejection) {
+        Failure::TooOld => RevokeCollectionApprovalError::TooOld,
+        Failure::CreatedInFuture { ledger_time } => {
+            RevokeCollectionApprovalError::CreatedInFuture { ledger_time }
+        }
+        Failure::Duplicate { .. } => RevokeCollectionApprovalError::ApprovalDoesNotExist,
+        Failure::Generic {
+            error_code,
+            message,
+        } => RevokeCollectionApprovalError::GenericError {
+            error_code,
+            message,
+        },
+    })?;
+
+    // Revoke the given spender, or every spender when none is given
+    let owner_key = _principal_key(&caller);
+    let keys: Vec<(PrincipalKey, PrincipalKey)> = match &arg.spender {
+        Some(spender) => vec![(owner_key, _principal_key(&spender.owner))],
+        None => COLLECTION_APPROVALS.with(|approvals| {
+            approvals
+                .borrow()
+                .range((owner_key, PrincipalKey::default())..)
+                .take_while(|((holder, _), _)| *holder == owner_key)
+                .map(|(key, _)| key)
+                .collect()
+        }),
+    };
+    let removed = COLLECTION_APPROVALS.with(|approvals| {
+        let mut approvals = approvals.borrow_mut();
+        keys.iter()
+            .filter(|key| approvals.remove(key).is_some())
+            .count()
+    });
+    if removed == 0 {
+        return Err(RevokeCollectionApprovalError::ApprovalDoesNotExist);
+    }
+    let index = icrc7::next_tx_index();
+    icrc7::remember(hash, &index, arg.created_at_time);
+    Ok(index)
+}
+
+#[ic_cdk::query]
+fn icrc37_is_approved(args: Vec<IsApprovedArg>) -> Result<Vec<bool>, icrc7::QueryBatchError> {
+    icrc7::ensure_query_batch(args.len())?;
+
+    // A spender is approved for a token through the token or through its holder's collection
+    let now = time();
+    Ok(args
+        .iter()
+        .map(|arg| {
+            let Some(ticket) = icrc7::token(&arg.token_id).ok().flatten() else {
+                return false;
+            };
+            let Some(owner) = icrc7::holder(&ticket).ok().flatten() else {
+                return false;
+            };
+            icrc7::is_default_subaccount(&arg.from_subaccount)
+                && is_approved(&ticket, &owner, &arg.spender, now)
+        })
+        .collect())
+}
+
+#[ic_cdk::query]
+fn icrc37_get_token_approvals(
+    token_id: Nat,
+    prev: Option<TokenApproval>,
+    take: Option<Nat>,
+) -> Vec<TokenApproval> {
+    // Page through the approvals in force for a token, in spender order, starting after `prev`
+    let Some(ticket) = icrc7::token(&token_id).ok().flatten() else {
+        return vec![];
+    };
+    let after = prev.map(|prev| _principal_key(&prev.approval_info.spender.owner));
+    token_approvals(ticket.id, ticket.user_id, time())
+        .into_iter()
+        .filter(|info| {
+            after
+                .as_ref()
+                .is_none_or(|after| _principal_key(&info.spender.owner) > *after)
+        })
+        .take(icrc7::take_value(take))
+        .map(|approval_info| TokenApproval {
+            token_id: token_id.clone(),
+            approval_info,
+        })
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc37_get_collection_approvals(
+    owner: Account,
+    prev: Option<ApprovalInfo>,
+    take: Option<Nat>,
+) -> Vec<ApprovalInfo> {
+    // Page through the collection approvals in force granted by an account, in spender order,
+    // starting after `prev`
+    if !icrc7::is_default_subaccount(&owner.subaccount) {
+        return vec![];
+    }
+    let after = prev.map(|prev| _principal_key(&prev.spender.owner));
+    collection_approvals(&owner.owner, time())
+        .into_iter()
+        .filter(|info| {
+            after
+                .as_ref()
+                .is_none_or(|after| _principal_key(&info.spender.owner) > *after)
+        })
+        .take(icrc7::take_value(take))
+        .collect()
+}
+
+#[ic_cdk::update]
+fn icrc37_transfer_from(args: Vec<TransferFromArg>) -> Vec<Option<Result<Nat, TransferFromError>>> {
+    if let Some((error_code, message)) = icrc7::batch_error(args.len()) {
+        return vec![Some(Err(TransferFromError::GenericBatchError {
+            error_code,
+            message,
+        }))];
+    }
+    let caller = match _authenticated_caller() {
+        Ok(caller) => caller,
+        Err(_) => {
+            return args
+                .iter()
+                .map(|_| Some(Err(TransferFromError::Unauthorized)))
+                .collect()
+        }
+    };
+
+    // Transfer each token on its own; a failed transfer does not undo the others
+    args.iter()
+        .map(|arg| Some(transfer_from(caller, arg)))
+        .collect()
+}
+
+// Function to run one `icrc37_transfer_from`, which goes through the same rules as
+// `transfer_ticket`
+fn transfer_from(caller: Principal, arg: &TransferFromArg) -> Result<Nat, TransferFromError> {
+    let hash = icrc7::screen(
+        "icrc37_transfer_from",
+        &caller,
+        arg,
+        &arg.memo,
+        arg.created_at_time,
+    )
+    .map_err(|rejection| match Failure::from(rejection) {
+        Failure::TooOld => TransferFromError::TooOld,
+        Failure::CreatedInFuture { ledger_time } => {
+            TransferFromError::CreatedInFuture { ledger_time }
+        }
+        Failure::Duplicate { duplicate_of } => TransferFromError::Duplicate { duplicate_of },
+        Failure::Generic {
+            error_code,
+            message,
+        } => TransferFromError::GenericError {
+            error_code,
+            message,
+        },
+    })?;
+
+    // The token must be held by the `from` account, and the caller approved to move it
+    let ticket = icrc7::token(&arg.token_id)
+        .ok()
+        .flatten()
+        .ok_or(TransferFromError::NonExistingTokenId)?;
+    let owner = icrc7::holder(&ticket)
+        .ok()
+        .flatten()
+        .ok_or(TransferFromError::NonExistingTokenId)?;
+    let spender = Account {
+        owner: caller,
+        subaccount: arg.spender_subaccount.clone(),
+    };
+    if !icrc7::is_account_of(&arg.from, &owner) || !is_approved(&ticket, &owner, &spender, time()) {
+        return Err(TransferFromError::Unauthorized);
+    }
+
+    icrc7::send(ticket, &arg.to, caller).map_err(|refusal| match refusal {
+        Refusal::InvalidRecipient => TransferFromError::InvalidRecipient,
+        Refusal::Generic(error) => {
+            let (error_code, message) = icrc7::rules_error(error);
+            TransferFromError::GenericError {
+                error_code,
+                message,
+            }
+        }
+    })?;
+    let index = icrc7::next_tx_index();
+    icrc7::remember(hash, &index, arg.created_at_time);
+    Ok(index)
+}
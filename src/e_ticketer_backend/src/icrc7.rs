@@ -0,0 +1,680 @@
+use crate::{
+    _authenticated_caller, _get_event, _get_ticket, _get_user, _get_user_id_by_principal,
+    _user_ticket_ids, ledger::Account, transfers, Error, Memory, Ticket, TicketStatus,
+    DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE, MEMORY_MANAGER, TICKET_STORAGE,
+};
+use candid::{CandidType, Encode, Nat, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+// Largest batch a single query or update call accepts
+pub(crate) const MAX_QUERY_BATCH_SIZE: usize = 100;
+pub(crate) const MAX_UPDATE_BATCH_SIZE: usize = 20;
+
+// Longest accepted memo, in bytes
+pub(crate) const MAX_MEMO_SIZE: usize = 32;
+
+// Transactions carrying a creation time are deduplicated for a day, with two minutes of clock drift
+// allowed
+const TX_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT_NANOS: u64 = 2 * 60 * 1_000_000_000;
+
+// Marks a supply that has not been counted yet
+const UNCOUNTED_SUPPLY: u64 = u64::MAX;
+
+// Define an enum for the ICRC-3 values metadata is expressed in
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum Value {
+    Nat(Nat),
+    Int(candid::Int),
+    Text(String),
+    Blob(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+// Define a struct for a standard the canister implements
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct SupportedStandard {
+    name: String,
+    url: String,
+}
+
+// Define a struct for the arguments of one `icrc7_transfer`
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    token_id: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// Define an enum for the reasons an `icrc7_transfer` fails
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) enum TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+// Metadata of one token, as named values
+pub(crate) type TokenMetadata = Vec<(String, Value)>;
+
+// Define an enum for the reason an ICRC-7 or ICRC-37 batch query fails as a whole
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) enum QueryBatchError {
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+// Define an enum for the checks every ICRC update runs before it looks at the token
+pub(crate) enum Rejection {
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    Generic(Error),
+}
+
+// Define an enum for the reasons a token cannot move to an account
+pub(crate) enum Refusal {
+    InvalidRecipient,
+    Generic(Error),
+}
+
+// Error codes of the generic errors: the ticket rules refused the call, or the call was malformed
+pub(crate) const RULES_ERROR_CODE: u64 = 1;
+pub(crate) const BATCH_ERROR_CODE: u64 = 2;
+
+thread_local! {
+    // Index of the next ICRC-7 or ICRC-37 transaction
+    static TX_COUNTER: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))), 0)
+            .expect("Cannot create the transaction counter")
+    );
+
+    // Transactions that carried a creation time, by hash, with their index and creation time, kept
+    // for the deduplication window
+    static RECENT_TRANSACTIONS: RefCell<StableBTreeMap<[u8; 32], (u64, u64), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    // The same transactions keyed by creation time and hash, so those past the window are found
+    // without scanning the rest
+    static RECENT_TRANSACTIONS_BY_TIME: RefCell<StableBTreeMap<(u64, [u8; 32]), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+    ));
+
+    // Number of tickets that are tokens, kept in step with every status change
+    static TOKEN_SUPPLY: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41))), UNCOUNTED_SUPPLY)
+            .expect("Cannot create the token supply")
+    );
+}
+
+// Function to check whether a ticket is a token: it admits, or it was used and is kept as a memento
+fn is_token(ticket: &Ticket) -> bool {
+    is_token_status(ticket.status)
+}
+
+// Function to check whether tickets in a status are tokens
+fn is_token_status(status: TicketStatus) -> bool {
+    status.is_valid() || status == TicketStatus::CheckedIn
+}
+
+// Function to keep the token supply in step with a ticket moving between statuses, where None
+// stands for a ticket that is created or deleted
+pub(crate) fn count_status_change(from: Option<TicketStatus>, to: Option<TicketStatus>) {
+    let was_token = from.is_some_and(is_token_status);
+    let is_token = to.is_some_and(is_token_status);
+    if was_token == is_token {
+        return;
+    }
+    TOKEN_SUPPLY.with(|supply| {
+        let mut supply = supply.borrow_mut();
+        let current = *supply.get();
+        if current == UNCOUNTED_SUPPLY {
+            return;
+        }
+        let updated = if is_token {
+            current + 1
+        } else {
+            current.saturating_sub(1)
+        };
+        supply.set(updated).expect("Cannot set the token supply");
+    });
+}
+
+// Function to count the tokens once and index the deduplicated transactions by time, for canisters
+// installed before the supply was kept or the transactions were indexed
+pub(crate) fn rebuild_indexes() {
+    if TOKEN_SUPPLY.with(|supply| *supply.borrow().get()) == UNCOUNTED_SUPPLY {
+        let supply = count_tokens();
+        TOKEN_SUPPLY.with(|cell| {
+            cell.borrow_mut()
+                .set(supply)
+                .expect("Cannot set the token supply")
+        });
+    }
+    let indexed = RECENT_TRANSACTIONS_BY_TIME.with(|by_time| by_time.borrow().len());
+    if RECENT_TRANSACTIONS.with(|recent| recent.borrow().len()) != indexed {
+        RECENT_TRANSACTIONS.with(|recent| {
+            RECENT_TRANSACTIONS_BY_TIME.with(|by_time| {
+                let mut by_time = by_time.borrow_mut();
+                for (hash, (_, created_at)) in recent.borrow().iter() {
+                    by_time.insert((created_at, hash), ());
+                }
+            })
+        });
+    }
+}
+
+// Function to get the ticket a token id stands for, or None if there is no such token
+pub(crate) fn token(token_id: &Nat) -> Result<Option<Ticket>, Error> {
+    let Ok(ticket_id) = u64::try_from(token_id.0.clone()) else {
+        return Ok(None);
+    };
+    Ok(_get_ticket(&ticket_id)?.filter(is_token))
+}
+
+// Function to get the principal holding a ticket
+pub(crate) fn holder(ticket: &Ticket) -> Result<Option<Principal>, Error> {
+    Ok(_get_user(&ticket.user_id)?.map(|user| user.principal))
+}
+
+// Function to check whether a subaccount is the default one, which is the only one holding tickets
+pub(crate) fn is_default_subaccount(subaccount: &Option<Vec<u8>>) -> bool {
+    subaccount
+        .as_ref()
+        .is_none_or(|subaccount| subaccount.iter().all(|byte| *byte == 0))
+}
+
+// Function to check whether an account is the default account of a principal
+pub(crate) fn is_account_of(account: &Account, principal: &Principal) -> bool {
+    account.owner == *principal && is_default_subaccount(&account.subaccount)
+}
+
+// Function to get the number of items a paged query returns
+pub(crate) fn take_value(take: Option<Nat>) -> usize {
+    let take = take
+        .and_then(|take| u32::try_from(take.0).ok())
+        .unwrap_or(DEFAULT_PAGE_SIZE);
+    take.clamp(1, MAX_PAGE_SIZE) as usize
+}
+
+// Function to refuse a query batch larger than the canister serves
+pub(crate) fn ensure_query_batch(length: usize) -> Result<(), QueryBatchError> {
+    if length > MAX_QUERY_BATCH_SIZE {
+        return Err(QueryBatchError::GenericBatchError {
+            error_code: Nat::from(BATCH_ERROR_CODE),
+            message: format!(
+                "batch of {} exceeds the limit of {}",
+                length, MAX_QUERY_BATCH_SIZE
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to get the generic error an update reports for a refusal of the ticket rules
+pub(crate) fn rules_error(error: Error) -> (Nat, String) {
+    (Nat::from(RULES_ERROR_CODE), error.message().to_string())
+}
+
+// Function to get the error an update batch larger than the canister serves fails with, if it is
+pub(crate) fn batch_error(length: usize) -> Option<(Nat, String)> {
+    (length > MAX_UPDATE_BATCH_SIZE).then(|| {
+        (
+            Nat::from(BATCH_ERROR_CODE),
+            format!(
+                "batch of {} exceeds the limit of {}",
+                length, MAX_UPDATE_BATCH_SIZE
+            ),
+        )
+    })
+}
+
+// Function to get the index of the next transaction
+pub(crate) fn next_tx_index() -> Nat {
+    let index = TX_COUNTER
+        .with(|counter| {
+            let current = *counter.borrow().get();
+            counter.borrow_mut().set(current + 1)
+        })
+        .expect("Cannot increment the transaction counter");
+    Nat::from(index)
+}
+
+// Function to run the checks every ICRC update runs on its memo and creation time, returning the
+// hash it is deduplicated under when it carries a creation time
+pub(crate) fn screen<T: CandidType>(
+    method: &str,
+    caller: &Principal,
+    arg: &T,
+    memo: &Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> Result<Option<[u8; 32]>, Rejection> {
+    if memo.as_ref().is_some_and(|memo| memo.len() > MAX_MEMO_SIZE) {
+        return Err(Rejection::Generic(Error::InvalidInput {
+            msg: format!("memo is longer than {} bytes", MAX_MEMO_SIZE),
+        }));
+    }
+    let Some(created_at_time) = created_at_time else {
+        return Ok(None);
+    };
+    let now = time();
+    if created_at_time < now.saturating_sub(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS) {
+        return Err(Rejection::TooOld);
+    }
+    if created_at_time > now.saturating_add(PERMITTED_DRIFT_NANOS) {
+        return Err(Rejection::CreatedInFuture { ledger_time: now });
+    }
+
+    // The same call by the same caller within the window is the same transaction
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_bytes());
+    hasher.update(caller.as_slice());
+    hasher.update(Encode!(arg).expect("arguments are always encodable"));
+    let hash: [u8; 32] = hasher.finalize().into();
+    match RECENT_TRANSACTIONS.with(|recent| recent.borrow().get(&hash)) {
+        Some((index, _)) => Err(Rejection::Duplicate {
+            duplicate_of: Nat::from(index),
+        }),
+        None => Ok(Some(hash)),
+    }
+}
+
+// Function to remember a deduplicated transaction, forgetting those older than the window
+pub(crate) fn remember(hash: Option<[u8; 32]>, index: &Nat, created_at_time: Option<u64>) {
+    let (Some(hash), Some(created_at_time)) = (hash, created_at_time) else {
+        return;
+    };
+    let oldest = time().saturating_sub(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS);
+    RECENT_TRANSACTIONS.with(|recent| {
+        RECENT_TRANSACTIONS_BY_TIME.with(|by_time| {
+            let mut recent = recent.borrow_mut();
+            let mut by_time = by_time.borrow_mut();
+
+            // Only the transactions created before the window are visited
+            let expired: Vec<(u64, [u8; 32])> = by_time
+                .range(..(oldest, [0; 32]))
+                .map(|(key, _)| key)
+                .collect();
+            for key in expired {
+                by_time.remove(&key);
+                recent.remove(&key.1);
+            }
+
+            let index = u64::try_from(index.0.clone()).expect("indexes are u64");
+            recent.insert(hash, (index, created_at_time));
+            by_time.insert((created_at_time, hash), ());
+        })
+    });
+}
+
+// Function to move a token to an account under the ticket transfer rules; only the default account
+// of a registered user can hold a ticket
+pub(crate) fn send(ticket: Ticket, to: &Account, actor: Principal) -> Result<Ticket, Refusal> {
+    if !is_default_subaccount(&to.subaccount) {
+        return Err(Refusal::InvalidRecipient);
+    }
+    let to_user_id = _get_user_id_by_principal(&to.owner).ok_or(Refusal::InvalidRecipient)?;
+    if to_user_id == ticket.user_id {
+        return Err(Refusal::InvalidRecipient);
+    }
+    let event = _get_event(&ticket.event_id)
+        .map_err(Refusal::Generic)?
+        .ok_or(Refusal::Generic(Error::NotFound {
+            msg: format!("event id:{} does not exist", ticket.event_id),
+        }))?;
+    transfers::ensure_transferable(&event, &ticket, time()).map_err(Refusal::Generic)?;
+    transfers::reassign(ticket, to_user_id, actor).map_err(Refusal::Generic)
+}
+
+// Function to describe a token
+fn token_metadata(ticket: &Ticket) -> Result<TokenMetadata, Error> {
+    let mut metadata = vec![
+        (
+            "event_id".to_string(),
+            Value::Nat(Nat::from(ticket.event_id)),
+        ),
+        (
+            "status".to_string(),
+            Value::Text(format!("{:?}", ticket.status)),
+        ),
+    ];
+    if let Some(tier_id) = ticket.tier_id {
+        metadata.push(("tier_id".to_string(), Value::Nat(Nat::from(tier_id))));
+    }
+    if let Some(event) = _get_event(&ticket.event_id)? {
+        metadata.extend([
+            (
+                "icrc7:name".to_string(),
+                Value::Text(format!("{} #{}", event.name, ticket.id)),
+            ),
+            ("location".to_string(), Value::Text(event.location)),
+            (
+                "starts_at".to_string(),
+                Value::Nat(Nat::from(event.starts_at)),
+            ),
+            ("ends_at".to_string(), Value::Nat(Nat::from(event.ends_at))),
+        ]);
+    }
+    Ok(metadata)
+}
+
+// Function to get the number of tokens, counting them if the supply has not been counted yet
+fn total_supply() -> u64 {
+    match TOKEN_SUPPLY.with(|supply| *supply.borrow().get()) {
+        UNCOUNTED_SUPPLY => count_tokens(),
+        supply => supply,
+    }
+}
+
+// Function to count the tokens by decoding every ticket
+fn count_tokens() -> u64 {
+    TICKET_STORAGE.with(|tickets| {
+        tickets
+            .borrow()
+            .iter()
+            .filter(|(_, ticket)| ticket.decode().is_ok_and(|ticket| is_token(&ticket)))
+            .count() as u64
+    })
+}
+
+#[ic_cdk::query]
+fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    // Return the token standards the canister implements
+    [
+        ("ICRC-7", "ICRC-7"),
+        ("ICRC-10", "ICRC-10"),
+        ("ICRC-37", "ICRC-37"),
+    ]
+    .into_iter()
+    .map(|(name, path)| SupportedStandard {
+        name: name.to_string(),
+        url: format!("https://github.com/dfinity/ICRC/tree/main/ICRCs/{}", path),
+    })
+    .collect()
+}
+
+#[ic_cdk::query]
+fn icrc7_collection_metadata() -> Vec<(String, Value)> {
+    // Return the description of the collection and its limits
+    let mut metadata = vec![
+        ("icrc7:symbol".to_string(), Value::Text(icrc7_symbol())),
+        ("icrc7:name".to_string(), Value::Text(icrc7_name())),
+        (
+            "icrc7:total_supply".to_string(),
+            Value::Nat(icrc7_total_supply()),
+        ),
+    ];
+    if let Some(description) = icrc7_description() {
+        metadata.push(("icrc7:description".to_string(), Value::Text(description)));
+    }
+    let limits = [
+        ("icrc7:max_query_batch_size", icrc7_max_query_batch_size()),
+        ("icrc7:max_update_batch_size", icrc7_max_update_batch_size()),
+        ("icrc7:default_take_value", icrc7_default_take_value()),
+        ("icrc7:max_take_value", icrc7_max_take_value()),
+        ("icrc7:max_memo_size", icrc7_max_memo_size()),
+        ("icrc7:tx_window", icrc7_tx_window()),
+        ("icrc7:permitted_drift", icrc7_permitted_drift()),
+    ];
+    for (key, value) in limits {
+        if let Some(value) = value {
+            metadata.push((key.to_string(), Value::Nat(value)));
+        }
+    }
+    metadata
+}
+
+#[ic_cdk::query]
+fn icrc7_symbol() -> String {
+    "TICKET".to_string()
+}
+
+#[ic_cdk::query]
+fn icrc7_name() -> String {
+    "e-ticketer tickets".to_string()
+}
+
+#[ic_cdk::query]
+fn icrc7_description() -> Option<String> {
+    Some("Event tickets issued by e-ticketer; each token is one ticket".to_string())
+}
+
+#[ic_cdk::query]
+fn icrc7_logo() -> Option<String> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_total_supply() -> Nat {
+    Nat::from(total_supply())
+}
+
+#[ic_cdk::query]
+fn icrc7_supply_cap() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_max_query_batch_size() -> Option<Nat> {
+    Some(Nat::from(MAX_QUERY_BATCH_SIZE))
+}
+
+#[ic_cdk::query]
+fn icrc7_max_update_batch_size() -> Option<Nat> {
+    Some(Nat::from(MAX_UPDATE_BATCH_SIZE))
+}
+
+#[ic_cdk::query]
+fn icrc7_default_take_value() -> Option<Nat> {
+    Some(Nat::from(DEFAULT_PAGE_SIZE))
+}
+
+#[ic_cdk::query]
+fn icrc7_max_take_value() -> Option<Nat> {
+    Some(Nat::from(MAX_PAGE_SIZE))
+}
+
+#[ic_cdk::query]
+fn icrc7_max_memo_size() -> Option<Nat> {
+    Some(Nat::from(MAX_MEMO_SIZE))
+}
+
+#[ic_cdk::query]
+fn icrc7_atomic_batch_transfers() -> Option<bool> {
+    // Each transfer of a batch succeeds or fails on its own
+    Some(false)
+}
+
+#[ic_cdk::query]
+fn icrc7_tx_window() -> Option<Nat> {
+    Some(Nat::from(TX_WINDOW_NANOS))
+}
+
+#[ic_cdk::query]
+fn icrc7_permitted_drift() -> Option<Nat> {
+    Some(Nat::from(PERMITTED_DRIFT_NANOS))
+}
+
+#[ic_cdk::query]
+fn icrc7_token_metadata(
+    token_ids: Vec<Nat>,
+) -> Result<Vec<Option<TokenMetadata>>, QueryBatchError> {
+    ensure_query_batch(token_ids.len())?;
+
+    // Describe each token, or return None for ids that are not tokens
+    Ok(token_ids
+        .iter()
+        .map(|token_id| match token(token_id) {
+            Ok(Some(ticket)) => token_metadata(&ticket).ok(),
+            _ => None,
+        })
+        .collect())
+}
+
+#[ic_cdk::query]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Result<Vec<Option<Account>>, QueryBatchError> {
+    ensure_query_batch(token_ids.len())?;
+
+    // Return the default account of each token's holder
+    Ok(token_ids
+        .iter()
+        .map(|token_id| {
+            let ticket = token(token_id).ok().flatten()?;
+            let owner = holder(&ticket).ok().flatten()?;
+            Some(Account {
+                owner,
+                subaccount: None,
+            })
+        })
+        .collect())
+}
+
+#[ic_cdk::query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Result<Vec<Nat>, QueryBatchError> {
+    ensure_query_batch(accounts.len())?;
+
+    // Count the tokens of each account; only default accounts of users hold tickets
+    Ok(accounts
+        .iter()
+        .map(|account| {
+            let user_id = match is_default_subaccount(&account.subaccount) {
+                true => _get_user_id_by_principal(&account.owner),
+                false => None,
+            };
+            let balance = user_id.map_or(0, |user_id| {
+                _user_ticket_ids(user_id)
+                    .iter()
+                    .filter(|ticket_id| {
+                        _get_ticket(ticket_id)
+                            .is_ok_and(|ticket| ticket.is_some_and(|t| is_token(&t)))
+                    })
+                    .count()
+            });
+            Nat::from(balance)
+        })
+        .collect())
+}
+
+#[ic_cdk::query]
+fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    // Page through the tokens in ascending id order, starting after `prev`
+    let start = prev
+        .map(|prev| u64::try_from(prev.0).map_or(u64::MAX, |prev| prev.saturating_add(1)))
+        .unwrap_or(0);
+    TICKET_STORAGE.with(|tickets| {
+        tickets
+            .borrow()
+            .range(start..)
+            .filter(|(_, ticket)| ticket.decode().is_ok_and(|ticket| is_token(&ticket)))
+            .take(take_value(take))
+            .map(|(ticket_id, _)| Nat::from(ticket_id))
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    // Page through the tokens of an account in ascending id order, starting after `prev`
+    let Some(user_id) = is_default_subaccount(&account.subaccount)
+        .then(|| _get_user_id_by_principal(&account.owner))
+        .flatten()
+    else {
+        return vec![];
+    };
+    let after = prev.and_then(|prev| u64::try_from(prev.0).ok());
+    _user_ticket_ids(user_id)
+        .into_iter()
+        .filter(|ticket_id| after.is_none_or(|after| *ticket_id > after))
+        .filter(|ticket_id| {
+            _get_ticket(ticket_id).is_ok_and(|ticket| ticket.is_some_and(|t| is_token(&t)))
+        })
+        .take(take_value(take))
+        .map(Nat::from)
+        .collect()
+}
+
+#[ic_cdk::update]
+fn icrc7_transfer(args: Vec<TransferArg>) -> Vec<Option<Result<Nat, TransferError>>> {
+    if let Some((error_code, message)) = batch_error(args.len()) {
+        return vec![Some(Err(TransferError::GenericBatchError {
+            error_code,
+            message,
+        }))];
+    }
+    let caller = match _authenticated_caller() {
+        Ok(caller) => caller,
+        Err(_) => {
+            return args
+                .iter()
+                .map(|_| Some(Err(TransferError::Unauthorized)))
+                .collect()
+        }
+    };
+
+    // Transfer each token on its own; a failed transfer does not undo the others
+    args.iter().map(|arg| Some(transfer(caller, arg))).collect()
+}
+
+// Function to run one `icrc7_transfer`, which goes through the same rules as `transfer_ticket`
+fn transfer(caller: Principal, arg: &TransferArg) -> Result<Nat, TransferError> {
+    let hash = screen(
+        "icrc7_transfer",
+        &caller,
+        arg,
+        &arg.memo,
+        arg.created_at_time,
+    )
+    .map_err(|rejection| match rejection {
+        Rejection::TooOld => TransferError::TooOld,
+        Rejection::CreatedInFuture { ledger_time } => {
+            TransferError::CreatedInFuture { ledger_time }
+        }
+        Rejection::Duplicate { duplicate_of } => TransferError::Duplicate { duplicate_of },
+        Rejection::Generic(error) => {
+            let (error_code, message) = rules_error(error);
+            TransferError::GenericError {
+                error_code,
+                message,
+            }
+        }
+    })?;
+
+    // Only the holder may transfer a token, from its default account
+    let ticket = token(&arg.token_id)
+        .ok()
+        .flatten()
+        .ok_or(TransferError::NonExistingTokenId)?;
+    if !is_default_subaccount(&arg.from_subaccount)
+        || holder(&ticket).ok().flatten() != Some(caller)
+    {
+        return Err(TransferError::Unauthorized);
+    }
+
+    send(ticket, &arg.to, caller).map_err(|refusal| match refusal {
+        Refusal::InvalidRecipient => TransferError::InvalidRecipient,
+        Refusal::Generic(error) => {
+            let (error_code, message) = rules_error(error);
+            TransferError::GenericError {
+                error_code,
+                message,
+            }
+        }
+    })?;
+    let index = next_tx_index();
+    remember(hash, &index, arg.created_at_time);
+    Ok(index)
+}
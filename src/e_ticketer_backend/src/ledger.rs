@@ -0,0 +1,272 @@
+use crate::{_authenticated_caller, _principal_key, Error, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::call::call;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Define a struct for an ICRC-1 account
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+// Define a struct for the arguments of an ICRC-2 `icrc2_transfer_from` call
+#[derive(CandidType, Serialize, Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// Define an enum for the errors an ICRC-2 ledger returns from `icrc2_transfer_from`
+#[derive(CandidType, Deserialize)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// Define a struct for the arguments of an ICRC-1 `icrc1_transfer` call
+#[derive(CandidType, Serialize, Deserialize)]
+struct TransferArgs {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// Define an enum for the errors an ICRC-1 ledger returns from `icrc1_transfer`
+#[derive(CandidType, Deserialize)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+thread_local! {
+    // Ledgers that tiers may be priced in, with the time each was added
+    static LEDGER_STORAGE: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+}
+
+// Function to check whether tiers may be priced in a ledger
+pub(crate) fn is_configured(ledger_id: &Principal) -> bool {
+    LEDGER_STORAGE.with(|ledgers| ledgers.borrow().contains_key(&_principal_key(ledger_id)))
+}
+
+// Function to pull an amount from an account into this canister's default account, returning the
+// block index of the transfer; the account must have approved this canister for the amount and fee
+pub(crate) async fn transfer_from(
+    ledger_id: Principal,
+    from: Principal,
+    amount: u64,
+    memo: Vec<u8>,
+) -> Result<Nat, Error> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: from,
+            subaccount: None,
+        },
+        to: Account {
+            owner: ic_cdk::id(),
+            subaccount: None,
+        },
+        amount: Nat::from(amount),
+        fee: None,
+        memo: Some(memo),
+        created_at_time: Some(time()),
+    };
+
+    let (result,): (Result<Nat, TransferFromError>,) =
+        call(ledger_id, "icrc2_transfer_from", (args,))
+            .await
+            .map_err(|(code, msg)| Error::LedgerUnavailable {
+                msg: format!("ledger {} rejected the call: {:?} {}", ledger_id, code, msg),
+            })?;
+
+    // Map the ledger's errors to the ones callers can act on
+    result.map_err(|error| match error {
+        TransferFromError::InsufficientFunds { balance } => Error::InsufficientFunds {
+            msg: format!("balance of {} e8s does not cover {} e8s", balance, amount),
+        },
+        TransferFromError::InsufficientAllowance { allowance } => Error::InsufficientAllowance {
+            msg: format!(
+                "allowance of {} e8s does not cover {} e8s and the fee",
+                allowance, amount
+            ),
+        },
+        TransferFromError::BadFee { expected_fee } => Error::PaymentFailed {
+            msg: format!("ledger expects a fee of {} e8s", expected_fee),
+        },
+        TransferFromError::BadBurn { min_burn_amount } => Error::PaymentFailed {
+            msg: format!("ledger expects a burn of at least {} e8s", min_burn_amount),
+        },
+        TransferFromError::TooOld | TransferFromError::CreatedInFuture { .. } => {
+            Error::PaymentFailed {
+                msg: "ledger rejected the transfer time".to_string(),
+            }
+        }
+        TransferFromError::Duplicate { duplicate_of } => Error::PaymentFailed {
+            msg: format!("transfer duplicates block {}", duplicate_of),
+        },
+        TransferFromError::TemporarilyUnavailable => Error::LedgerUnavailable {
+            msg: format!("ledger {} is temporarily unavailable", ledger_id),
+        },
+        TransferFromError::GenericError {
+            error_code,
+            message,
+        } => Error::PaymentFailed {
+            msg: format!("ledger error {}: {}", error_code, message),
+        },
+    })
+}
+
+// Function to get the fee a ledger charges for a transfer, in e8s
+pub(crate) async fn fee(ledger_id: Principal) -> Result<u64, Error> {
+    let (fee,): (Nat,) = call(ledger_id, "icrc1_fee", ())
+        .await
+        .map_err(|(code, msg)| Error::LedgerUnavailable {
+            msg: format!("ledger {} rejected the call: {:?} {}", ledger_id, code, msg),
+        })?;
+    u64::try_from(fee.0.clone()).map_err(|_| Error::PaymentFailed {
+        msg: format!("ledger {} charges a fee of {} e8s", ledger_id, fee),
+    })
+}
+
+// Function to send an amount from this canister's default account to a principal, paying the given
+// fee on top, and return the block index of the transfer. A transfer retried with the same arguments
+// and creation time within the ledger's deduplication window returns the block of the first one
+pub(crate) async fn transfer(
+    ledger_id: Principal,
+    to: Principal,
+    amount: u64,
+    fee: u64,
+    memo: Vec<u8>,
+    created_at_time: u64,
+) -> Result<Nat, Error> {
+    let args = TransferArgs {
+        from_subaccount: None,
+        to: Account {
+            owner: to,
+            subaccount: None,
+        },
+        amount: Nat::from(amount),
+        fee: Some(Nat::from(fee)),
+        memo: Some(memo),
+        created_at_time: Some(created_at_time),
+    };
+
+    let (result,): (Result<Nat, TransferError>,) = call(ledger_id, "icrc1_transfer", (args,))
+        .await
+        .map_err(|(code, msg)| Error::LedgerUnavailable {
+            msg: format!("ledger {} rejected the call: {:?} {}", ledger_id, code, msg),
+        })?;
+
+    // A duplicate means the same transfer already went through, so its block is returned
+    if let Err(TransferError::Duplicate { duplicate_of }) = result {
+        return Ok(duplicate_of);
+    }
+
+    // Map the ledger's errors to the ones callers can act on
+    result.map_err(|error| match error {
+        TransferError::InsufficientFunds { balance } => Error::InsufficientFunds {
+            msg: format!(
+                "canister balance of {} e8s does not cover {} e8s and the fee",
+                balance, amount
+            ),
+        },
+        TransferError::BadFee { expected_fee } => Error::PaymentFailed {
+            msg: format!("ledger expects a fee of {} e8s", expected_fee),
+        },
+        TransferError::BadBurn { min_burn_amount } => Error::PaymentFailed {
+            msg: format!("ledger expects a burn of at least {} e8s", min_burn_amount),
+        },
+        TransferError::TooOld | TransferError::CreatedInFuture { .. } => Error::PaymentFailed {
+            msg: "ledger rejected the transfer time".to_string(),
+        },
+        TransferError::Duplicate { duplicate_of } => Error::PaymentFailed {
+            msg: format!("transfer duplicates block {}", duplicate_of),
+        },
+        TransferError::TemporarilyUnavailable => Error::LedgerUnavailable {
+            msg: format!("ledger {} is temporarily unavailable", ledger_id),
+        },
+        TransferError::GenericError {
+            error_code,
+            message,
+        } => Error::PaymentFailed {
+            msg: format!("ledger error {}: {}", error_code, message),
+        },
+    })
+}
+
+#[ic_cdk::query]
+fn list_ledgers() -> Vec<Principal> {
+    // Return every ledger tiers may be priced in
+    LEDGER_STORAGE.with(|ledgers| {
+        ledgers
+            .borrow()
+            .iter()
+            .map(|(key, _)| Principal::from_slice(key.as_slice()))
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+fn add_ledger(ledger_id: Principal) -> Result<String, Error> {
+    // Only admins may choose the ledgers tickets are paid on
+    let caller = _authenticated_caller()?;
+    if !crate::roles::is_admin(&caller) {
+        return Err(Error::Unauthorized {
+            msg: "only admins can configure ledgers".to_string(),
+        });
+    }
+
+    LEDGER_STORAGE.with(|ledgers| {
+        ledgers
+            .borrow_mut()
+            .insert(_principal_key(&ledger_id), time())
+    });
+    Ok(format!("ledger {} added", ledger_id))
+}
+
+#[ic_cdk::update]
+fn remove_ledger(ledger_id: Principal) -> Result<String, Error> {
+    // Only admins may choose the ledgers tickets are paid on
+    let caller = _authenticated_caller()?;
+    if !crate::roles::is_admin(&caller) {
+        return Err(Error::Unauthorized {
+            msg: "only admins can configure ledgers".to_string(),
+        });
+    }
+
+    // Tiers already priced in the ledger stop selling until it is added again
+    match LEDGER_STORAGE.with(|ledgers| ledgers.borrow_mut().remove(&_principal_key(&ledger_id))) {
+        Some(_) => Ok(format!("ledger {} removed", ledger_id)),
+        None => Err(Error::NotFound {
+            msg: format!("ledger {} is not configured", ledger_id),
+        }),
+    }
+}
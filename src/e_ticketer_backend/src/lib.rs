@@ -1,101 +1,216 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+mod auth;
+mod checkin;
+mod codes;
+mod icrc37;
+mod icrc7;
+mod ledger;
+mod lifecycle;
+mod migrations;
+mod refunds;
+mod resale;
+mod reservations;
+mod roles;
+mod seating;
+mod signing;
+mod tiers;
+mod transfers;
+mod waitlist;
+
+use auth::Session;
+use candid::{Nat, Principal};
+use checkin::{CheckIn, CheckInStats};
+use codes::TicketAdmission;
 use ic_cdk::api::time;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::{Cell, DefaultMemoryImpl, StableBTreeMap};
+use icrc37::{
+    ApprovalInfo, ApproveCollectionArg, ApproveCollectionError, ApproveTokenArg, ApproveTokenError,
+    IsApprovedArg, RevokeCollectionApprovalArg, RevokeCollectionApprovalError,
+    RevokeTokenApprovalArg, RevokeTokenApprovalError, TokenApproval, TransferFromArg,
+    TransferFromError,
+};
+use icrc7::{QueryBatchError, SupportedStandard, TokenMetadata, TransferArg, TransferError, Value};
+use ledger::Account;
+use lifecycle::{StatusChange, TicketStatus};
+use migrations::{Record, SchemaVersion, Versioned};
+use refunds::{CancellationReport, RefundPolicy};
+use resale::{Payout, ResaleListing, ResaleTerms};
+use reservations::Reservation;
+use roles::{Role, RoleGrant};
+use seating::{EventSeating, SeatMapPage, SeatPage, SectionPayload, Venue, VenuePayload};
+use sha2::{Digest, Sha256};
+use signing::RevocationPage;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::time::Duration;
+use tiers::{TicketTier, TierPayload};
+use transfers::{TicketTransfer, TransferRules};
+use waitlist::{WaitlistEntry, WaitlistStatus};
 
 // Define type aliases for convenience
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
+type PrincipalKey = Blob<29>;
+// Relates a record, or a pair of records, to ticket IDs
+type TicketRelation<K> = StableBTreeMap<(K, u64), (), Memory>;
+
+// Page sizes for event listings
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 100;
+
+// Maximum number of events a single listing call inspects, so sparse filters stay within limits
+const MAX_SCANNED_EVENTS: usize = 1_000;
+
+// Longest accepted text fields, in bytes, so every record fits in its stable-memory slot
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_DESCRIPTION_LENGTH: usize = 480;
+const MAX_LOCATION_LENGTH: usize = 100;
+const MAX_TIMEZONE_LENGTH: usize = 64;
+const MAX_EMAIL_LENGTH: usize = 254;
 
 // Define a struct for the 'Event'
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct Event {
     id: u64,
+    owner: Principal,
     name: String,
     description: String,
-    date: String,
-    start_time: String,
+    // Start and end of the event, in nanoseconds since epoch
+    starts_at: u64,
+    ends_at: u64,
+    // IANA timezone the event is held in, e.g. "Europe/Zurich"
+    timezone: String,
     location: String,
-    attendee_ids: Vec<u64>,
-    ticket_ids: Vec<u64>,
+    capacity: u32,
+    // Whether tickets may be moved from this event to another one
+    exchangeable: bool,
+    // Refund granted to attendees who cancel their own ticket
+    refund_policy: RefundPolicy,
+    // Whether, until when and how often holders may pass their tickets on
+    transfer_rules: TransferRules,
+    // When the event was cancelled; cancelled events sell no more tickets
+    cancelled_at: Option<u64>,
     created_at: u64,
     updated_at: Option<u64>,
 }
 
 // Define a struct for the 'User'
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct User {
     id: u64,
+    principal: Principal,
+    name: String,
+    email: String,
+    // Plaintext password of users created before hashing; cleared on their next login
+    password: Option<String>,
+    // Argon2id PHC string; never returned to callers
+    password_hash: Option<String>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for the public view of a 'User', without credentials
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct UserProfile {
+    id: u64,
+    principal: Principal,
     name: String,
     email: String,
-    password: String,
-    event_ids: Vec<u64>,
-    ticket_ids: Vec<u64>,
     created_at: u64,
     updated_at: Option<u64>,
 }
 
+impl From<User> for UserProfile {
+    fn from(user: User) -> Self {
+        UserProfile {
+            id: user.id,
+            principal: user.principal,
+            name: user.name,
+            email: user.email,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+        }
+    }
+}
+
 // Define a struct for the 'Ticket'
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Ticket {
     id: u64,
     event_id: u64,
     user_id: u64,
+    // Tier the ticket was issued in, or None for tickets of events without tiers
+    tier_id: Option<u64>,
+    // Price paid for the ticket in e8s of the tier's currency by its last buyer, kept when the tier is
+    // repriced; refunds are capped by it
+    price_paid_e8s: u64,
+    // Price the ticket was first sold at, which resale price caps are based on
+    face_value_e8s: u64,
+    // Index of the ledger block that paid for the ticket, or None for tickets issued without payment
+    payment_block_index: Option<Nat>,
+    // Ledger the ticket was paid on and the principal that paid, which refunds are sent back to
+    payment_ledger_id: Option<Principal>,
+    paid_by: Option<Principal>,
+    status: TicketStatus,
+    // Seat of the venue the ticket admits to, for events with reserved seating
+    seat_id: Option<u64>,
+    // Amount sent back to the buyer and the index of the ledger block that sent it, once refunded
+    refunded_e8s: u64,
+    refund_block_index: Option<Nat>,
     created_at: u64,
     updated_at: Option<u64>,
 }
 
-// Implement the 'Storable' trait for 'Event', 'User', and 'Ticket'
-impl Storable for Event {
-    // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-    // Conversion from bytes
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
-}
+// Implement the 'Record' trait for 'Event', 'User', and 'Ticket', which are stored in versioned
+// envelopes
+impl Record for Event {
+    const KIND: &'static str = "event";
+    // Version 1 added the owner, the capacity and the exchange flag, version 2 replaced the free-form
+    // date strings with typed instants, version 3 moved attendee and ticket membership into the
+    // relation maps, version 4 added the refund policy and the cancellation time, and version 5 the
+    // transfer rules
+    const VERSION: u32 = 5;
+    const MAX_SIZE: u32 = 1024;
 
-impl Storable for User {
-    // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-    // Conversion from bytes
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+    fn migrations() -> &'static [migrations::Migration] {
+        migrations::EVENT_MIGRATIONS
     }
-}
 
-impl Storable for Ticket {
-    // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-    // Conversion from bytes
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+    fn unversioned_version(bytes: &[u8]) -> u32 {
+        migrations::unversioned_event_version(bytes)
     }
 }
 
-// Implement the 'BoundedStorable' trait for 'Event', 'User', and 'Ticket'
-impl BoundedStorable for Event {
+impl Record for User {
+    const KIND: &'static str = "user";
+    // Version 1 bound the user to a principal, and version 2 moved event and ticket membership into
+    // the relation maps
+    const VERSION: u32 = 2;
     const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
-}
 
-impl BoundedStorable for User {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+    fn migrations() -> &'static [migrations::Migration] {
+        migrations::USER_MIGRATIONS
+    }
+
+    fn unversioned_version(bytes: &[u8]) -> u32 {
+        migrations::unversioned_user_version(bytes)
+    }
 }
 
-impl BoundedStorable for Ticket {
+impl Record for Ticket {
+    const KIND: &'static str = "ticket";
+    // Version 2 added the tier and the price paid, version 3 the payment block index, version 4 the
+    // payment source, the status and the refund, version 5 the seat, and version 6 the face value
+    const VERSION: u32 = 6;
     const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+
+    fn migrations() -> &'static [migrations::Migration] {
+        migrations::TICKET_MIGRATIONS
+    }
 }
 
 // Define thread-local static variables for memory management and storage
@@ -109,20 +224,63 @@ thread_local! {
             .expect("Cannot create a counter")
     );
 
-    static EVENT_STORAGE: RefCell<StableBTreeMap<u64, Event, Memory>> =
+    static EVENT_STORAGE: RefCell<StableBTreeMap<u64, Versioned<Event>, Memory>> =
         RefCell::new(StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
     ));
 
-    static USER_STORAGE: RefCell<StableBTreeMap<u64, User, Memory>> =
+    static USER_STORAGE: RefCell<StableBTreeMap<u64, Versioned<User>, Memory>> =
         RefCell::new(StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
     ));
 
-    static TICKET_STORAGE: RefCell<StableBTreeMap<u64, Ticket, Memory>> =
+    static TICKET_STORAGE: RefCell<StableBTreeMap<u64, Versioned<Ticket>, Memory>> =
         RefCell::new(StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
     ));
+
+    // Maps each principal to the id of the user it created
+    static USER_PRINCIPAL_INDEX: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+
+    // Memory 5 holds the login sessions, in the auth module
+
+    // Relates each event to the tickets sold for it
+    static EVENT_TICKETS: RefCell<TicketRelation<u64>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+
+    // Relates each user to the tickets they hold
+    static USER_TICKETS: RefCell<TicketRelation<u64>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    // Relates each user and event to the tickets the user holds for that event
+    static USER_EVENT_TICKETS: RefCell<TicketRelation<(u64, u64)>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    // Maps the digest of each normalized email to the id of the user registered with it
+    static USER_EMAIL_INDEX: RefCell<StableBTreeMap<[u8; 32], u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+
+    // Relates each tier to the tickets sold in it
+    static TIER_TICKETS: RefCell<TicketRelation<u64>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    // Purchases waiting on the ledger, by hold ID; the canister is stopped before upgrades, so
+    // none survive one
+    static PENDING_PURCHASES: RefCell<BTreeMap<u64, PendingPurchase>> =
+        const { RefCell::new(BTreeMap::new()) };
 }
 
 // Define structs for payload data (used in update calls)
@@ -130,9 +288,14 @@ thread_local! {
 struct EventPayload {
     name: String,
     description: String,
-    date: String,
-    start_time: String,
+    starts_at: u64,
+    ends_at: u64,
+    timezone: String,
     location: String,
+    capacity: u32,
+    exchangeable: bool,
+    refund_policy: RefundPolicy,
+    transfer_rules: TransferRules,
 }
 
 #[derive(candid::CandidType, Serialize, Deserialize, Default)]
@@ -146,21 +309,248 @@ struct UserPayload {
 struct TicketPayload {
     event_id: u64,
     user_id: u64,
+    // Tier to issue the ticket in; required for events that define tiers
+    tier_id: Option<u64>,
+}
+
+// Define a struct for a payment a ticket was issued against
+#[derive(Clone)]
+struct Payment {
+    block_index: Nat,
+    ledger_id: Principal,
+    paid_by: Principal,
+}
+
+// Define a struct for a purchase waiting on the ledger
+struct PendingPurchase {
+    event_id: u64,
+    tier_id: u64,
+    user_id: u64,
+    seat_id: Option<u64>,
+}
+
+// Define a struct holding a seat for a purchase until it completes or fails, releasing the seat
+// when dropped
+struct PurchaseHold {
+    id: u64,
+}
+
+impl PurchaseHold {
+    fn new(event_id: u64, tier_id: u64, user_id: u64, seat_id: Option<u64>) -> Self {
+        let id = PENDING_PURCHASES.with(|purchases| {
+            let mut purchases = purchases.borrow_mut();
+            let id = purchases.last_key_value().map_or(0, |(id, _)| id + 1);
+            purchases.insert(
+                id,
+                PendingPurchase {
+                    event_id,
+                    tier_id,
+                    user_id,
+                    seat_id,
+                },
+            );
+            id
+        });
+        PurchaseHold { id }
+    }
+}
+
+impl Drop for PurchaseHold {
+    fn drop(&mut self) {
+        PENDING_PURCHASES.with(|purchases| purchases.borrow_mut().remove(&self.id));
+    }
+}
+
+fn _pending_purchases(matches: impl Fn(&PendingPurchase) -> bool) -> usize {
+    // Helper function to count the purchases waiting on the ledger that match a condition
+    PENDING_PURCHASES.with(|purchases| {
+        purchases
+            .borrow()
+            .values()
+            .filter(|purchase| matches(purchase))
+            .count()
+    })
+}
+
+// Define an enum for where an event stands in time
+#[derive(candid::CandidType, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum EventStatus {
+    Upcoming,
+    Ongoing,
+    Ended,
+}
+
+// Define a struct for the optional filters of an event listing
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct EventFilter {
+    // Case-insensitive substring of the location
+    location: Option<String>,
+    // Only events starting at or after this instant
+    starts_after: Option<u64>,
+    // Only events starting at or before this instant
+    starts_before: Option<u64>,
+    // Only events owned by this principal
+    organizer: Option<Principal>,
+    status: Option<EventStatus>,
+}
+
+// Define a struct for one page of an event listing
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct EventPage {
+    events: Vec<Event>,
+    // Cursor to pass to the next call, or None once every event has been listed
+    next_cursor: Option<u64>,
+}
+
+// Define an enum for how deleting an event or user treats its tickets
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum DeletePolicy {
+    // Refuse the deletion while any ticket still references the record
+    Restrict,
+    // Cancel every ticket and strip its references from the other side
+    Cascade,
+}
+
+// Define a struct reporting exactly what a deletion removed or changed
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct DeletionReport {
+    deleted_event_ids: Vec<u64>,
+    deleted_user_ids: Vec<u64>,
+    deleted_ticket_ids: Vec<u64>,
+    updated_event_ids: Vec<u64>,
+    updated_user_ids: Vec<u64>,
+    // Refunds sent by a cascade over paid tickets; until they are all settled nothing is deleted,
+    // and the caller calls again for the next batch
+    refunds: CancellationReport,
+}
+
+// Function to get the caller of a mutating call, rejecting the anonymous principal
+fn _authenticated_caller() -> Result<Principal, Error> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "anonymous principal cannot perform this call".to_string(),
+        });
+    }
+    Ok(caller)
+}
+
+fn _principal_key(principal: &Principal) -> PrincipalKey {
+    // Helper function to convert a principal into a stable-memory key
+    PrincipalKey::try_from(principal.as_slice()).expect("principal is at most 29 bytes")
+}
+
+// Function to check that the caller owns the given user
+fn _ensure_user_owner(user: &User, caller: &Principal) -> Result<(), Error> {
+    if user.principal != *caller {
+        return Err(Error::Unauthorized {
+            msg: format!("caller does not own user id:{}", user.id),
+        });
+    }
+    Ok(())
+}
+
+// Function to check that the caller holds the given ticket or manages its event
+fn _ensure_ticket_owner(ticket: &Ticket, caller: &Principal) -> Result<(), Error> {
+    let is_holder = _get_user(&ticket.user_id)?.is_some_and(|user| user.principal == *caller);
+    let is_organizer =
+        _get_event(&ticket.event_id)?.is_some_and(|event| roles::is_event_manager(&event, caller));
+    if !is_holder && !is_organizer {
+        return Err(Error::Unauthorized {
+            msg: format!("caller does not own ticket id:{}", ticket.id),
+        });
+    }
+    Ok(())
 }
 
 // Define the Candid interface
 #[ic_cdk::query]
 fn get_all_events() -> Vec<Event> {
-    // Retrieve all events from the storage and return them as a Vec
-    let events_map: Vec<(u64, Event)> =
-        EVENT_STORAGE.with(|events| events.borrow().iter().collect());
-    events_map.into_iter().map(|(_, event)| event).collect()
+    // Deprecated: only returns the first page of events, use `list_events` to page through all
+    list_events(None, DEFAULT_PAGE_SIZE, EventFilter::default())
+        .map(|page| page.events)
+        .unwrap_or_default()
+}
+
+#[ic_cdk::query]
+fn list_events(
+    cursor: Option<u64>,
+    page_size: u32,
+    filter: EventFilter,
+) -> Result<EventPage, Error> {
+    // Clamp the page size, using the default when none is given
+    let page_size = match page_size {
+        0 => DEFAULT_PAGE_SIZE,
+        size => size.min(MAX_PAGE_SIZE),
+    } as usize;
+    let location = filter
+        .location
+        .as_ref()
+        .map(|location| location.to_lowercase());
+    let now = time();
+
+    // Scan events in ID order, starting after the cursor
+    let start = cursor.map_or(Bound::Unbounded, Bound::Excluded);
+    EVENT_STORAGE.with(|events| {
+        let events = events.borrow();
+        let mut page = vec![];
+        let mut last_scanned = None;
+        for (scanned, (id, event)) in events.range((start, Bound::Unbounded)).enumerate() {
+            // Stop when the page is full or the scan budget is spent; the caller resumes here
+            if page.len() == page_size || scanned == MAX_SCANNED_EVENTS {
+                return Ok(EventPage {
+                    events: page,
+                    next_cursor: last_scanned,
+                });
+            }
+            last_scanned = Some(id);
+
+            let event = event.decode()?;
+            if _matches_filter(&event, &filter, location.as_deref(), now) {
+                page.push(event);
+            }
+        }
+
+        // Every remaining event was scanned
+        Ok(EventPage {
+            events: page,
+            next_cursor: None,
+        })
+    })
+}
+
+fn _matches_filter(event: &Event, filter: &EventFilter, location: Option<&str>, now: u64) -> bool {
+    // Helper function to check an event against every filter that is set
+    location.is_none_or(|location| event.location.to_lowercase().contains(location))
+        && filter
+            .starts_after
+            .is_none_or(|after| event.starts_at >= after)
+        && filter
+            .starts_before
+            .is_none_or(|before| event.starts_at <= before)
+        && filter
+            .organizer
+            .is_none_or(|organizer| event.owner == organizer)
+        && filter
+            .status
+            .is_none_or(|status| _event_status(event, now) == status)
+}
+
+fn _event_status(event: &Event, now: u64) -> EventStatus {
+    // Helper function to place an event relative to the given instant
+    if now < event.starts_at {
+        EventStatus::Upcoming
+    } else if now < event.ends_at {
+        EventStatus::Ongoing
+    } else {
+        EventStatus::Ended
+    }
 }
 
 #[ic_cdk::query]
 fn get_event(id: u64) -> Result<Event, Error> {
     // Retrieve a specific event by ID and return it, or return a NotFound error if not found
-    match _get_event(&id) {
+    match _get_event(&id)? {
         Some(event) => Ok(event),
         None => Err(Error::NotFound {
             msg: format!("event id:{} does not exist", id),
@@ -168,13 +558,115 @@ fn get_event(id: u64) -> Result<Event, Error> {
     }
 }
 
-fn _get_event(id: &u64) -> Option<Event> {
+fn _get_event(id: &u64) -> Result<Option<Event>, Error> {
     // Helper function to get an event from the storage based on the provided ID
-    EVENT_STORAGE.with(|events| events.borrow().get(id))
+    EVENT_STORAGE.with(|events| {
+        events
+            .borrow()
+            .get(id)
+            .map(|event| event.decode())
+            .transpose()
+    })
+}
+
+// Function to check that an event ends after it starts and names a known IANA timezone
+fn _validate_schedule(payload: &EventPayload) -> Result<(), Error> {
+    if payload.ends_at <= payload.starts_at {
+        return Err(Error::InvalidInput {
+            msg: "event must end after it starts".to_string(),
+        });
+    }
+    if payload.timezone.parse::<chrono_tz::Tz>().is_err() {
+        return Err(Error::InvalidInput {
+            msg: format!("{} is not an IANA timezone", payload.timezone),
+        });
+    }
+    refunds::validate_policy(&payload.refund_policy)
+}
+
+// Function to check that the text fields of an event fit in its record
+fn _validate_event_text(payload: &EventPayload) -> Result<(), Error> {
+    _validate_length("name", &payload.name, MAX_NAME_LENGTH)?;
+    _validate_length("description", &payload.description, MAX_DESCRIPTION_LENGTH)?;
+    _validate_length("location", &payload.location, MAX_LOCATION_LENGTH)?;
+    _validate_length("timezone", &payload.timezone, MAX_TIMEZONE_LENGTH)
+}
+
+// Function to check that the text fields of a user fit in its record
+fn _validate_user_text(payload: &UserPayload) -> Result<(), Error> {
+    _validate_length("name", &payload.name, MAX_NAME_LENGTH)?;
+    _validate_length("email", &payload.email, MAX_EMAIL_LENGTH)
+}
+
+fn _validate_length(field: &str, value: &str, max: usize) -> Result<(), Error> {
+    // Helper function to reject a text field longer than its limit
+    if value.len() > max {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "{} is {} bytes long, at most {} are allowed",
+                field,
+                value.len(),
+                max
+            ),
+        });
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn remaining_capacity(event_id: u64) -> Result<u32, Error> {
+    // Retrieve the event with the given ID, or return a NotFound error if not found
+    let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+
+    Ok(_remaining_capacity(&event))
+}
+
+fn _remaining_capacity(event: &Event) -> u32 {
+    // Helper function to count the tickets that can still be sold for an event
+    let sold = _seats_taken(event.id);
+    event
+        .capacity
+        .saturating_sub(sold.try_into().unwrap_or(u32::MAX))
+}
+
+fn _seats_taken(event_id: u64) -> usize {
+    // Helper function to count the tickets sold for an event and the purchases still being paid for
+    _count_seats(_event_ticket_ids(event_id))
+        + _pending_purchases(|purchase| purchase.event_id == event_id)
+}
+
+fn _count_seats(ticket_ids: Vec<u64>) -> usize {
+    // Helper function to count the tickets that still take a seat; a ticket that fails to decode is
+    // counted, so a bad record never frees a seat
+    ticket_ids
+        .into_iter()
+        .filter(|ticket_id| match _get_ticket(ticket_id) {
+            Ok(ticket) => ticket.is_some_and(|ticket| ticket.status.holds_seat()),
+            Err(_) => true,
+        })
+        .count()
+}
+
+fn _ensure_on_sale(event: &Event) -> Result<(), Error> {
+    // Helper function to refuse sales for a cancelled event
+    if event.cancelled_at.is_some() {
+        return Err(Error::Conflict {
+            msg: format!("event id:{} is cancelled", event.id),
+        });
+    }
+    Ok(())
 }
 
 #[ic_cdk::update]
 fn create_event(payload: EventPayload) -> Result<Event, Error> {
+    // Only organizers may create events, and the caller becomes the owner of the event
+    let owner = _authenticated_caller()?;
+    roles::ensure_organizer(&owner)?;
+    _validate_schedule(&payload)?;
+    _validate_event_text(&payload)?;
+
     // Increment the global ID counter to get a new ID for the event
     let id = ID_COUNTER
         .with(|counter| {
@@ -186,19 +678,25 @@ fn create_event(payload: EventPayload) -> Result<Event, Error> {
     // Create a new Event with the provided payload and the generated ID
     let event = Event {
         id,
+        owner,
         name: payload.name.clone(),
         description: payload.description,
-        date: payload.date,
-        start_time: payload.start_time,
+        starts_at: payload.starts_at,
+        ends_at: payload.ends_at,
+        timezone: payload.timezone,
         location: payload.location,
-        attendee_ids: vec![],
-        ticket_ids: vec![],
+        capacity: payload.capacity,
+        exchangeable: payload.exchangeable,
+        refund_policy: payload.refund_policy,
+        transfer_rules: payload.transfer_rules,
+        cancelled_at: None,
         created_at: time(),
         updated_at: None,
     };
 
     // Insert the new event into the storage
-    match EVENT_STORAGE.with(|events| events.borrow_mut().insert(id, event.clone())) {
+    let record = Versioned::try_new(&event)?;
+    match EVENT_STORAGE.with(|events| events.borrow_mut().insert(id, record)) {
         None => Ok(event),
         Some(_) => Err(Error::NotCreated {
             msg: format!("event {} could not be created", payload.name),
@@ -208,66 +706,264 @@ fn create_event(payload: EventPayload) -> Result<Event, Error> {
 
 #[ic_cdk::update]
 fn update_event(id: u64, payload: EventPayload) -> Result<Event, Error> {
+    let caller = _authenticated_caller()?;
+
     // Retrieve the existing event with the given ID, or return a NotFound error if not found
-    let event = _get_event(&id).ok_or(Error::NotFound {
+    let event = _get_event(&id)?.ok_or(Error::NotFound {
         msg: format!("event id:{} does not exist", id),
     })?;
 
+    // Only the organizer who owns the event, or an admin, may update it
+    roles::ensure_event_manager(&event, &caller)?;
+    _validate_schedule(&payload)?;
+    _validate_event_text(&payload)?;
+
+    // The capacity cannot drop below the number of tickets already sold
+    let sold = _seats_taken(id);
+    if (payload.capacity as usize) < sold {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "event id:{} already sold {} tickets, capacity cannot be lowered to {}",
+                id, sold, payload.capacity
+            ),
+        });
+    }
+
     // Create an updated event based on the provided payload
     let updated_event = Event {
         id,
+        owner: event.owner,
         name: payload.name,
         description: payload.description,
-        date: payload.date,
-        start_time: payload.start_time,
+        starts_at: payload.starts_at,
+        ends_at: payload.ends_at,
+        timezone: payload.timezone,
         location: payload.location,
-        attendee_ids: event.attendee_ids,
-        ticket_ids: event.ticket_ids,
+        capacity: payload.capacity,
+        exchangeable: payload.exchangeable,
+        refund_policy: payload.refund_policy,
+        transfer_rules: payload.transfer_rules,
+        cancelled_at: event.cancelled_at,
         created_at: event.created_at,
         updated_at: Some(time()),
     };
 
     // Insert the updated event into the storage
-    match EVENT_STORAGE.with(|events| events.borrow_mut().insert(id, updated_event.clone())) {
-        Some(_) => Ok(updated_event),
-        None => Err(Error::NotCreated {
+    let record = Versioned::try_new(&updated_event)?;
+    if EVENT_STORAGE
+        .with(|events| events.borrow_mut().insert(id, record))
+        .is_none()
+    {
+        return Err(Error::NotCreated {
             msg: format!("event id:{} could not be updated", id),
-        }),
+        });
+    }
+
+    // Offer the seats a larger capacity adds to the users on the waitlist
+    if updated_event.capacity > event.capacity {
+        waitlist::promote(id);
     }
+    Ok(updated_event)
 }
 
 #[ic_cdk::update]
-fn delete_event(id: u64) -> Result<String, Error> {
+async fn delete_event(id: u64, policy: DeletePolicy) -> Result<DeletionReport, Error> {
+    let caller = _authenticated_caller()?;
+
+    // A cascade over paid tickets first refunds them in batches, cancelling the event the way
+    // `cancel_event` does, and deletes the event on the call that finds them all settled
+    let mut refunds = CancellationReport::default();
+    if let DeletePolicy::Cascade = policy {
+        let event = _ensure_event_deletable(id, &caller)?;
+        let tickets = _load_tickets(&_event_ticket_ids(id))?;
+        if tickets.iter().any(_is_unrefunded_payment) {
+            refunds = refunds::cancel_and_refund(event, caller).await?;
+            if !refunds.is_settled() {
+                return Ok(DeletionReport {
+                    refunds,
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    // Waiting on the ledger let other calls in, so everything is checked again before deleting
+    let mut report = _delete_event(id, policy, caller)?;
+    report.refunds = refunds;
+    Ok(report)
+}
+
+// Function to check that an event exists, that the caller may delete it, and that none of its
+// tickets is waiting on the ledger
+fn _ensure_event_deletable(id: u64, caller: &Principal) -> Result<Event, Error> {
     // Check if the event with the given ID exists, or return a NotFound error if not found
-    _get_event(&id).ok_or(Error::NotFound {
+    let event = _get_event(&id)?.ok_or(Error::NotFound {
         msg: format!("event id:{} does not exist", id),
     })?;
 
-    // Remove the event with the given ID from the storage
+    // Only the organizer who owns the event, or an admin, may delete it
+    roles::ensure_event_manager(&event, caller)?;
+
+    // Refuse the deletion while a ticket of the event is being paid for or refunded
+    if _pending_purchases(|purchase| purchase.event_id == id) > 0 {
+        return Err(Error::Conflict {
+            msg: format!("event id:{} has purchases in progress", id),
+        });
+    }
+    if refunds::pending_for_event(id) > 0 {
+        return Err(Error::Conflict {
+            msg: format!("event id:{} has refunds in progress", id),
+        });
+    }
+    if resale::pending_for_event(id) > 0 {
+        return Err(Error::Conflict {
+            msg: format!("event id:{} has resales in progress", id),
+        });
+    }
+    if waitlist::pending_for_event(id) > 0 {
+        return Err(Error::Conflict {
+            msg: format!("event id:{} has waitlist offers being paid for", id),
+        });
+    }
+    if reservations::pending_for_event(id) > 0 {
+        return Err(Error::Conflict {
+            msg: format!("event id:{} has reservations being paid for", id),
+        });
+    }
+    Ok(event)
+}
+
+// Function to delete an event and, under a cascade, its tickets, once no paid ticket is left to
+// refund
+fn _delete_event(
+    id: u64,
+    policy: DeletePolicy,
+    caller: Principal,
+) -> Result<DeletionReport, Error> {
+    _ensure_event_deletable(id, &caller)?;
+
+    // Refuse the deletion while tickets exist, unless they should be cancelled with it
+    let ticket_ids = _event_ticket_ids(id);
+    if let DeletePolicy::Restrict = policy {
+        if !ticket_ids.is_empty() {
+            return Err(Error::Conflict {
+                msg: format!("event id:{} still has {} tickets", id, ticket_ids.len()),
+            });
+        }
+    }
+
+    // Load every ticket before writing anything, so a record that fails to decode changes nothing
+    let tickets = _load_tickets(&ticket_ids)?;
+    _ensure_no_paid_tickets(&tickets)?;
+
+    // Apply the writes: cancelled tickets and their relations, then the event itself
+    let mut report = DeletionReport::default();
+    for ticket in &tickets {
+        TICKET_STORAGE.with(|storage| storage.borrow_mut().remove(&ticket.id));
+        _unlink_ticket(ticket);
+        lifecycle::remove_history(ticket.id);
+        transfers::remove_history(ticket.id);
+        resale::withdraw(ticket);
+        icrc37::clear_token_approvals(ticket.id);
+        checkin::remove_check_in(ticket.event_id, ticket.id);
+        codes::remove_nonce(ticket.id);
+        icrc7::count_status_change(Some(ticket.status), None);
+        report.deleted_ticket_ids.push(ticket.id);
+        if !report.updated_user_ids.contains(&ticket.user_id) {
+            report.updated_user_ids.push(ticket.user_id);
+        }
+    }
+    // Relations left behind by tickets that no longer exist go with the event
+    EVENT_TICKETS.with(|relation| {
+        let mut relation = relation.borrow_mut();
+        for ticket_id in &ticket_ids {
+            relation.remove(&(id, *ticket_id));
+        }
+    });
+    tiers::remove_event_tiers(id);
+    resale::remove_event(id);
+    waitlist::remove_event(id);
+    reservations::remove_event(id);
+    seating::remove_event(id);
+    signing::remove_event(id, &ticket_ids);
+    roles::remove_event(id);
     EVENT_STORAGE.with(|events| events.borrow_mut().remove(&id));
+    report.deleted_event_ids.push(id);
 
-    // Return Ok indicating a successful deletion
-    Ok(format!("event id: {} deleted", id))
+    // Return the report of everything the deletion touched
+    Ok(report)
 }
 
 #[ic_cdk::query]
-fn get_user(id: u64) -> Result<User, Error> {
+fn get_user(id: u64) -> Result<UserProfile, Error> {
     // Retrieve a specific user by ID and return it, or return a NotFound error if not found
-    match _get_user(&id) {
-        Some(user) => Ok(user),
+    match _get_user(&id)? {
+        Some(user) => Ok(user.into()),
         None => Err(Error::NotFound {
             msg: format!("user id:{} does not exist", id),
         }),
     }
 }
 
-fn _get_user(id: &u64) -> Option<User> {
+fn _get_user(id: &u64) -> Result<Option<User>, Error> {
     // Helper function to get a user from the storage based on the provided ID
-    USER_STORAGE.with(|users| users.borrow().get(id))
+    USER_STORAGE.with(|users| users.borrow().get(id).map(|user| user.decode()).transpose())
+}
+
+fn _get_user_id_by_principal(principal: &Principal) -> Option<u64> {
+    // Helper function to find the user bound to the provided principal
+    USER_PRINCIPAL_INDEX.with(|index| index.borrow().get(&_principal_key(principal)))
+}
+
+fn _email_key(email: &str) -> [u8; 32] {
+    // Helper function to derive the index key of an email, ignoring case and surrounding whitespace
+    Sha256::digest(email.trim().to_lowercase()).into()
+}
+
+fn _get_user_id_by_email(email: &str) -> Option<u64> {
+    // Helper function to find the user registered with the provided email
+    USER_EMAIL_INDEX.with(|index| index.borrow().get(&_email_key(email)))
+}
+
+fn _unindex_email(email: &str, user_id: u64) {
+    // Helper function to drop an email from the index, unless it belongs to another user
+    let key = _email_key(email);
+    USER_EMAIL_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if index.get(&key) == Some(user_id) {
+            index.remove(&key);
+        }
+    });
+}
+
+// Function to check that an email is not registered to another user
+fn _ensure_email_available(email: &str, user_id: Option<u64>) -> Result<(), Error> {
+    match _get_user_id_by_email(email) {
+        Some(existing_id) if Some(existing_id) != user_id => Err(Error::Conflict {
+            msg: format!("email {} is already registered", email),
+        }),
+        _ => Ok(()),
+    }
 }
 
 #[ic_cdk::update]
-fn create_user(payload: UserPayload) -> Result<User, Error> {
+async fn create_user(payload: UserPayload) -> Result<UserProfile, Error> {
+    let principal = _authenticated_caller()?;
+    _validate_user_text(&payload)?;
+    auth::validate_password(&payload.password)?;
+
+    // Hash the password with a fresh random salt before touching any state
+    let salt = auth::random_bytes().await?;
+    let password_hash = auth::hash_password(&payload.password, &salt[..16])?;
+
+    // The new user is bound to the calling principal, which may only own one user
+    if let Some(existing_id) = _get_user_id_by_principal(&principal) {
+        return Err(Error::NotCreated {
+            msg: format!("caller is already registered as user id:{}", existing_id),
+        });
+    }
+    _ensure_email_available(&payload.email, None)?;
+
     // Increment the global ID counter to get a new ID for the user
     let id = ID_COUNTER
         .with(|counter| {
@@ -279,18 +975,25 @@ fn create_user(payload: UserPayload) -> Result<User, Error> {
     // Create a new User with the provided payload and the generated ID
     let user = User {
         id,
+        principal,
         name: payload.name,
         email: payload.email,
-        password: payload.password,
-        event_ids: vec![],
-        ticket_ids: vec![],
+        password: None,
+        password_hash: Some(password_hash),
         created_at: time(),
         updated_at: None,
     };
 
     // Insert the new user into the storage
-    match USER_STORAGE.with(|users| users.borrow_mut().insert(id, user.clone())) {
-        None => Ok(user),
+    let record = Versioned::try_new(&user)?;
+    match USER_STORAGE.with(|users| users.borrow_mut().insert(id, record)) {
+        None => {
+            // Record which user the principal and the email belong to
+            USER_PRINCIPAL_INDEX
+                .with(|index| index.borrow_mut().insert(_principal_key(&principal), id));
+            USER_EMAIL_INDEX.with(|index| index.borrow_mut().insert(_email_key(&user.email), id));
+            Ok(user.into())
+        }
         Some(_) => Err(Error::NotCreated {
             msg: format!("user id:{} could not be created", id),
         }),
@@ -298,51 +1001,325 @@ fn create_user(payload: UserPayload) -> Result<User, Error> {
 }
 
 #[ic_cdk::update]
-fn update_user(id: u64, payload: UserPayload) -> Result<User, Error> {
+async fn update_user(id: u64, payload: UserPayload) -> Result<UserProfile, Error> {
+    let caller = _authenticated_caller()?;
+    _validate_user_text(&payload)?;
+    auth::validate_password(&payload.password)?;
+
+    // Hash the new password with a fresh random salt before touching any state
+    let salt = auth::random_bytes().await?;
+    let password_hash = auth::hash_password(&payload.password, &salt[..16])?;
+
     // Retrieve the existing user with the given ID, or return a NotFound error if not found
-    let user = _get_user(&id).ok_or(Error::NotFound {
+    let user = _get_user(&id)?.ok_or(Error::NotFound {
         msg: format!("user id:{} does not exist", id),
     })?;
 
+    // Only the principal bound to the user may update it, and not to another user's email
+    _ensure_user_owner(&user, &caller)?;
+    _ensure_email_available(&payload.email, Some(id))?;
+
     // Create an updated user based on the provided payload
     let updated_user = User {
         id,
+        principal: user.principal,
         name: payload.name,
         email: payload.email,
-        password: payload.password,
-        event_ids: user.event_ids,
-        ticket_ids: user.ticket_ids,
+        password: None,
+        password_hash: Some(password_hash),
         created_at: user.created_at,
         updated_at: Some(time()),
     };
 
     // Insert the updated user into the storage
-    match USER_STORAGE.with(|users| users.borrow_mut().insert(id, updated_user.clone())) {
-        None => Ok(updated_user),
-        Some(_) => Err(Error::NotCreated {
+    let record = Versioned::try_new(&updated_user)?;
+    match USER_STORAGE.with(|users| users.borrow_mut().insert(id, record)) {
+        Some(_) => {
+            // Move the email index entry to the new email
+            _unindex_email(&user.email, id);
+            USER_EMAIL_INDEX.with(|index| {
+                index
+                    .borrow_mut()
+                    .insert(_email_key(&updated_user.email), id)
+            });
+
+            // Sessions opened with the previous password are no longer valid
+            auth::revoke_sessions(id);
+            Ok(updated_user.into())
+        }
+        None => Err(Error::NotCreated {
             msg: format!("user id:{} could not be updated", id),
         }),
     }
 }
 
 #[ic_cdk::update]
-fn delete_user(id: u64) -> Result<String, Error> {
+async fn delete_user(id: u64, policy: DeletePolicy) -> Result<DeletionReport, Error> {
+    let caller = _authenticated_caller()?;
+
+    // A cascade over paid tickets first refunds them in batches, as much as each event's policy
+    // grants a holder cancelling them, and deletes the user on the call that finds them all settled
+    let mut refunds = CancellationReport::default();
+    if let DeletePolicy::Cascade = policy {
+        _ensure_user_deletable(id, &caller)?;
+        let paid: Vec<Ticket> = _load_tickets(&_user_ticket_ids(id))?
+            .into_iter()
+            .filter(_is_unrefunded_payment)
+            .collect();
+        if !paid.is_empty() {
+            refunds = refunds::release_batch(paid, caller).await?;
+            if !refunds.is_settled() {
+                return Ok(DeletionReport {
+                    refunds,
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    // Waiting on the ledger let other calls in, so everything is checked again before deleting
+    let mut report = _delete_user(id, policy, caller)?;
+    report.refunds = refunds;
+    Ok(report)
+}
+
+// Function to check that a user exists, that the caller may delete it, and that none of its tickets
+// is waiting on the ledger
+fn _ensure_user_deletable(id: u64, caller: &Principal) -> Result<User, Error> {
     // Check if the user with the given ID exists, or return a NotFound error if not found
-    _get_user(&id).ok_or(Error::NotFound {
+    let user = _get_user(&id)?.ok_or(Error::NotFound {
         msg: format!("user id:{} does not exist", id),
     })?;
 
-    // Remove the user with the given ID from the storage
+    // Only the principal bound to the user may delete it
+    _ensure_user_owner(&user, caller)?;
+
+    // Refuse the deletion while the user is paying for a ticket or being refunded
+    if _pending_purchases(|purchase| purchase.user_id == id) > 0 {
+        return Err(Error::Conflict {
+            msg: format!("user id:{} has purchases in progress", id),
+        });
+    }
+    if refunds::pending_for_user(id) > 0 {
+        return Err(Error::Conflict {
+            msg: format!("user id:{} has refunds in progress", id),
+        });
+    }
+    if resale::pending_for_user(id) > 0 {
+        return Err(Error::Conflict {
+            msg: format!("user id:{} has resales in progress", id),
+        });
+    }
+    if waitlist::pending_for_user(id) > 0 {
+        return Err(Error::Conflict {
+            msg: format!("user id:{} has waitlist offers being paid for", id),
+        });
+    }
+    if reservations::pending_for_user(id) > 0 {
+        return Err(Error::Conflict {
+            msg: format!("user id:{} has reservations being paid for", id),
+        });
+    }
+    Ok(user)
+}
+
+// Function to delete a user and, under a cascade, its tickets, once no paid ticket is left to
+// refund
+fn _delete_user(id: u64, policy: DeletePolicy, caller: Principal) -> Result<DeletionReport, Error> {
+    let user = _ensure_user_deletable(id, &caller)?;
+
+    // Refuse the deletion while the user holds tickets, unless they should be cancelled with it
+    let ticket_ids = _user_ticket_ids(id);
+    if let DeletePolicy::Restrict = policy {
+        if !ticket_ids.is_empty() {
+            return Err(Error::Conflict {
+                msg: format!("user id:{} still holds {} tickets", id, ticket_ids.len()),
+            });
+        }
+    }
+
+    // Load every ticket before writing anything, so a record that fails to decode changes nothing
+    let tickets = _load_tickets(&ticket_ids)?;
+    _ensure_no_paid_tickets(&tickets)?;
+
+    // Apply the writes: cancelled tickets and their relations, then the user itself
+    let mut report = DeletionReport::default();
+    for ticket in &tickets {
+        TICKET_STORAGE.with(|storage| storage.borrow_mut().remove(&ticket.id));
+        _unlink_ticket(ticket);
+        lifecycle::remove_history(ticket.id);
+        transfers::remove_history(ticket.id);
+        resale::withdraw(ticket);
+        icrc37::clear_token_approvals(ticket.id);
+        checkin::remove_check_in(ticket.event_id, ticket.id);
+        codes::remove_nonce(ticket.id);
+        icrc7::count_status_change(Some(ticket.status), None);
+        signing::revoke(ticket);
+        report.deleted_ticket_ids.push(ticket.id);
+        if !report.updated_event_ids.contains(&ticket.event_id) {
+            report.updated_event_ids.push(ticket.event_id);
+        }
+    }
+    // Relations left behind by tickets that no longer exist go with the user
+    USER_TICKETS.with(|relation| {
+        let mut relation = relation.borrow_mut();
+        for ticket_id in &ticket_ids {
+            relation.remove(&(id, *ticket_id));
+        }
+    });
+
+    // Remove the user with the given ID and its principal binding from the storage
     USER_STORAGE.with(|users| users.borrow_mut().remove(&id));
+    USER_PRINCIPAL_INDEX.with(|index| index.borrow_mut().remove(&_principal_key(&user.principal)));
+    _unindex_email(&user.email, id);
+    auth::revoke_sessions(id);
+    report.deleted_user_ids.push(id);
+
+    // Drop the user's reservations, take it off every waitlist and offer the seats its tickets freed
+    // to the next users
+    reservations::remove_user(id);
+    waitlist::remove_user(id);
+    for event_id in &report.updated_event_ids {
+        waitlist::promote(*event_id);
+    }
+
+    // Return the report of everything the deletion touched
+    Ok(report)
+}
+
+#[ic_cdk::update]
+async fn login(email: String, password: String) -> Result<Session, Error> {
+    let caller = _authenticated_caller()?;
+    let invalid_credentials = || Error::Unauthorized {
+        msg: "invalid email or password".to_string(),
+    };
+
+    // Find the user registered with the given email
+    let user_id = _get_user_id_by_email(&email).ok_or_else(invalid_credentials)?;
+    let user = _get_user(&user_id)?.ok_or_else(invalid_credentials)?;
+
+    // Verify the password against the stored hash, or against the legacy plaintext password
+    let verified = match (&user.password_hash, &user.password) {
+        (Some(password_hash), _) => auth::verify_password(&password, password_hash),
+        (None, Some(legacy)) => auth::verify_legacy_password(&password, legacy),
+        (None, None) => false,
+    };
+    if !verified {
+        return Err(invalid_credentials());
+    }
+
+    // Users from the first release are bound to no principal, and their plaintext password may have
+    // leaked, so only an admin can bind them to one; any other user only logs in from its own
+    // principal
+    if user.principal == migrations::UNCLAIMED_PRINCIPAL {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "user id:{} must be claimed through an admin before logging in",
+                user_id
+            ),
+        });
+    }
+    _ensure_user_owner(&user, &caller)?;
+
+    // Users still holding a plaintext password get it re-hashed now that it is known to be correct
+    let password_hash = match user.password_hash {
+        Some(_) => None,
+        None => {
+            let salt = auth::random_bytes().await?;
+            Some(auth::hash_password(&password, &salt[..16])?)
+        }
+    };
+    let token = auth::random_bytes().await?;
+
+    // Reload the user, which may have changed while waiting for randomness; a password changed
+    // meanwhile is no longer the one just verified
+    let mut updated_user = _get_user(&user_id)?.ok_or_else(invalid_credentials)?;
+    if updated_user.password != user.password
+        || updated_user.password_hash != user.password_hash
+        || updated_user.principal != user.principal
+    {
+        return Err(invalid_credentials());
+    }
+
+    // Store the re-hashed password
+    if password_hash.is_some() {
+        updated_user.password = None;
+        updated_user.password_hash = password_hash;
+        let record = Versioned::try_new(&updated_user)?;
+        USER_STORAGE.with(|users| users.borrow_mut().insert(user_id, record));
+    }
+
+    // Open a session identified by the random token
+    Ok(auth::create_session(user_id, token, time()))
+}
+
+#[ic_cdk::query]
+fn get_session_user(token: String) -> Result<UserProfile, Error> {
+    // Find the user the session was opened for, unless it expired or the user was deleted
+    let user_id = auth::check_session(&token, time())?;
+    match _get_user(&user_id)? {
+        Some(user) => Ok(user.into()),
+        None => Err(Error::Unauthorized {
+            msg: "session is invalid or has expired".to_string(),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn logout(token: String) -> Result<String, Error> {
+    // End the session, or return an Unauthorized error if it is not open
+    auth::check_session(&token, time())?;
+    auth::end_session(&token);
+    Ok("session ended".to_string())
+}
+
+#[ic_cdk::update]
+fn claim_user(id: u64, principal: Principal) -> Result<UserProfile, Error> {
+    // Only admins may bind a user from the first release to the principal of its owner, once they
+    // have confirmed who the owner is
+    if !roles::is_admin(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: "only admins can claim users".to_string(),
+        });
+    }
+    if principal == Principal::anonymous() {
+        return Err(Error::InvalidInput {
+            msg: "users cannot be bound to the anonymous principal".to_string(),
+        });
+    }
+
+    // Retrieve the user with the given ID, or return a NotFound error if not found
+    let mut user = _get_user(&id)?.ok_or(Error::NotFound {
+        msg: format!("user id:{} does not exist", id),
+    })?;
+    if user.principal != migrations::UNCLAIMED_PRINCIPAL {
+        return Err(Error::Conflict {
+            msg: format!("user id:{} is already claimed", id),
+        });
+    }
+    if let Some(existing_id) = _get_user_id_by_principal(&principal) {
+        return Err(Error::Conflict {
+            msg: format!(
+                "principal {} is already registered as user id:{}",
+                principal, existing_id
+            ),
+        });
+    }
 
-    // Return Ok indicating a successful deletion
-    Ok(format!("user id: {} deleted", id))
+    // Bind the user to the principal, which may then log in with the legacy password or set a new
+    // one
+    user.principal = principal;
+    user.updated_at = Some(time());
+    let record = Versioned::try_new(&user)?;
+    USER_STORAGE.with(|users| users.borrow_mut().insert(id, record));
+    USER_PRINCIPAL_INDEX.with(|index| index.borrow_mut().insert(_principal_key(&principal), id));
+    Ok(user.into())
 }
 
 #[ic_cdk::query]
 fn get_ticket(id: u64) -> Result<Ticket, Error> {
     // Retrieve a specific ticket by ID and return it, or return a NotFound error if not found
-    match _get_ticket(&id) {
+    match _get_ticket(&id)? {
         Some(ticket) => Ok(ticket),
         None => Err(Error::NotFound {
             msg: format!("ticket id:{} does not exist", id),
@@ -350,13 +1327,225 @@ fn get_ticket(id: u64) -> Result<Ticket, Error> {
     }
 }
 
-fn _get_ticket(id: &u64) -> Option<Ticket> {
+fn _get_ticket(id: &u64) -> Result<Option<Ticket>, Error> {
     // Helper function to get a ticket from the storage based on the provided ID
-    TICKET_STORAGE.with(|tickets| tickets.borrow().get(id))
+    TICKET_STORAGE.with(|tickets| {
+        tickets
+            .borrow()
+            .get(id)
+            .map(|ticket| ticket.decode())
+            .transpose()
+    })
 }
 
 #[ic_cdk::update]
-fn create_ticket(payload: TicketPayload) -> Result<Ticket, AssociationError> {
+fn create_ticket(payload: TicketPayload) -> Result<Ticket, Error> {
+    let caller = _authenticated_caller()?;
+
+    // Retrieve the event and the user, or return a NotFound error if either is missing
+    let event = _get_event(&payload.event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", payload.event_id),
+    })?;
+    let user = _get_user(&payload.user_id)?.ok_or(Error::NotFound {
+        msg: format!("user id:{} does not exist", payload.user_id),
+    })?;
+
+    // Only the attendee the ticket is issued to, or a manager of the event, may create it
+    let is_manager = roles::is_event_manager(&event, &caller);
+    if user.principal != caller && !is_manager {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "caller cannot issue a ticket for user id:{} to event id:{}",
+                user.id, event.id
+            ),
+        });
+    }
+
+    // Refuse the sale if the event is cancelled, has reserved seating or has no seats left
+    _ensure_on_sale(&event)?;
+    seating::ensure_general_admission(event.id)?;
+    if _remaining_capacity(&event) == 0 {
+        return Err(Error::SoldOut {
+            msg: format!("event id:{} is sold out", event.id),
+        });
+    }
+
+    // Refuse the sale if the requested tier has no tickets left or is not on sale
+    let now = time();
+    let tier = _resolve_tier(event.id, payload.tier_id)?;
+    if let Some(tier) = &tier {
+        tiers::ensure_on_sale(tier, now)?;
+
+        // Attendees pay for priced tiers through `purchase_ticket`; managers may issue them for free
+        if tier.price_e8s > 0 && !is_manager {
+            return Err(Error::Unauthorized {
+                msg: format!("tier id:{} is paid, use purchase_ticket", tier.id),
+            });
+        }
+    }
+
+    // Create the ticket, issued without payment
+    _mint_ticket(&payload, None, 0, None, TicketStatus::Issued, caller)
+}
+
+#[ic_cdk::update]
+async fn purchase_ticket(event_id: u64, tier_id: u64) -> Result<Ticket, Error> {
+    let caller = _authenticated_caller()?;
+
+    // The ticket is issued to the user bound to the caller
+    let user_id = _get_user_id_by_principal(&caller).ok_or(Error::NotFound {
+        msg: "caller is not registered as a user".to_string(),
+    })?;
+
+    // Retrieve the event, or return a NotFound error if not found
+    let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+
+    // Refuse the sale if the event is cancelled or has reserved seating, the event or the tier has no
+    // tickets left, or the tier is not on sale
+    _ensure_on_sale(&event)?;
+    seating::ensure_general_admission(event_id)?;
+    if _remaining_capacity(&event) == 0 {
+        return Err(Error::SoldOut {
+            msg: format!("event id:{} is sold out", event_id),
+        });
+    }
+    let tier = _resolve_tier(event_id, Some(tier_id))?.expect("a tier id is given");
+    tiers::ensure_on_sale(&tier, time())?;
+
+    // Hold a seat while the payment is in flight, so concurrent purchases cannot oversell; the event
+    // and the user cannot be deleted while the hold exists
+    let hold = PurchaseHold::new(event_id, tier_id, user_id, None);
+
+    // Pull the price from the buyer's account
+    let payment = _collect_payment(caller, &tier, 1).await?;
+
+    // Only now that the payment went through is the held seat turned into a ticket, unless the event
+    // was cancelled meanwhile
+    let payload = TicketPayload {
+        event_id,
+        user_id,
+        tier_id: Some(tier_id),
+    };
+    let ticket = _ensure_event_on_sale(event_id).and_then(|()| {
+        _mint_ticket(
+            &payload,
+            None,
+            tier.price_e8s,
+            payment.clone(),
+            TicketStatus::Issued,
+            caller,
+        )
+    });
+    drop(hold);
+    _return_payment_on_failure(ticket, payment, tier.price_e8s).await
+}
+
+// Function to check, after waiting on the ledger, that an event still exists and is on sale
+fn _ensure_event_on_sale(event_id: u64) -> Result<(), Error> {
+    let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+    _ensure_on_sale(&event)
+}
+
+// Function to send a payment back to its payer when what it paid for could not be issued; the
+// canister pays the ledger fee
+async fn _return_payment_on_failure<T>(
+    issued: Result<T, Error>,
+    payment: Option<Payment>,
+    amount_e8s: u64,
+) -> Result<T, Error> {
+    let (error, payment) = match (issued, payment) {
+        (Err(error), Some(payment)) if amount_e8s > 0 => (error, payment),
+        (issued, _) => return issued,
+    };
+    let memo = payment.block_index.to_string().into_bytes();
+    let returned = match ledger::fee(payment.ledger_id).await {
+        Ok(fee) => {
+            ledger::transfer(
+                payment.ledger_id,
+                payment.paid_by,
+                amount_e8s,
+                fee,
+                memo,
+                time(),
+            )
+            .await
+        }
+        Err(error) => Err(error),
+    };
+    Err(Error::PaymentFailed {
+        msg: match returned {
+            Ok(block_index) => format!(
+                "the payment in block {} was returned in block {} because the ticket could not be issued: {:?}",
+                payment.block_index, block_index, error
+            ),
+            Err(_) => format!(
+                "the payment in block {} could not be returned after the ticket could not be issued: {:?}",
+                payment.block_index, error
+            ),
+        },
+    })
+}
+
+// Function to pull the price of a number of tickets of a tier from the buyer's account; free tiers
+// skip the ledger
+async fn _collect_payment(
+    buyer: Principal,
+    tier: &TicketTier,
+    quantity: u64,
+) -> Result<Option<Payment>, Error> {
+    let amount_e8s = tier.price_e8s.saturating_mul(quantity);
+    match tier.ledger_id {
+        _ if amount_e8s == 0 => Ok(None),
+        Some(ledger_id) if ledger::is_configured(&ledger_id) => {
+            let memo = [tier.event_id.to_be_bytes(), tier.id.to_be_bytes()].concat();
+            let block_index = ledger::transfer_from(ledger_id, buyer, amount_e8s, memo).await?;
+            Ok(Some(Payment {
+                block_index,
+                ledger_id,
+                paid_by: buyer,
+            }))
+        }
+        _ => Err(Error::PaymentFailed {
+            msg: format!("tier id:{} is not priced in a configured ledger", tier.id),
+        }),
+    }
+}
+
+// Function to issue a reserved ticket once it is paid for, recording the payment it was bought with
+fn _issue_reserved_ticket(
+    mut ticket: Ticket,
+    price_paid_e8s: u64,
+    payment: Option<&Payment>,
+    actor: Principal,
+) -> Result<Ticket, Error> {
+    lifecycle::transition(&mut ticket, TicketStatus::Issued, actor)?;
+    ticket.price_paid_e8s = price_paid_e8s;
+    ticket.face_value_e8s = price_paid_e8s;
+    ticket.payment_ledger_id = payment.map(|payment| payment.ledger_id);
+    ticket.paid_by = payment.map(|payment| payment.paid_by);
+    ticket.payment_block_index = payment.map(|payment| payment.block_index.clone());
+    TICKET_STORAGE.with(|tickets| {
+        tickets
+            .borrow_mut()
+            .insert(ticket.id, Versioned::new(&ticket))
+    });
+    Ok(ticket)
+}
+
+// Function to create and store a ticket in the given status, relating it to its event, holder, tier
+// and seat and recording who issued it
+fn _mint_ticket(
+    payload: &TicketPayload,
+    seat_id: Option<u64>,
+    price_paid_e8s: u64,
+    payment: Option<Payment>,
+    status: TicketStatus,
+    actor: Principal,
+) -> Result<Ticket, Error> {
     // Increment the global ID counter to get a new ID for the ticket
     let id = ID_COUNTER
         .with(|counter| {
@@ -365,283 +1554,394 @@ fn create_ticket(payload: TicketPayload) -> Result<Ticket, AssociationError> {
         })
         .expect("Cannot increment Ids");
 
-    // Create a new Ticket with the provided payload and the generated ID
+    // Refuse ids that are already stored, which would mean the counter and storage disagree
+    if _get_ticket(&id)?.is_some() {
+        return Err(Error::NotCreated {
+            msg: format!("ticket id:{} already exists", id),
+        });
+    }
+
+    // Create a new Ticket with the generated ID
     let ticket = Ticket {
         id,
         event_id: payload.event_id,
         user_id: payload.user_id,
+        tier_id: payload.tier_id,
+        price_paid_e8s,
+        face_value_e8s: price_paid_e8s,
+        payment_ledger_id: payment.as_ref().map(|payment| payment.ledger_id),
+        paid_by: payment.as_ref().map(|payment| payment.paid_by),
+        payment_block_index: payment.map(|payment| payment.block_index),
+        status,
+        seat_id,
+        refunded_e8s: 0,
+        refund_block_index: None,
         created_at: time(),
         updated_at: None,
     };
 
-    // Insert the new ticket into the storage
-    TICKET_STORAGE.with(|tickets| tickets.borrow_mut().insert(id, ticket.clone()));
-
-    // Call helper functions to associate the ticket with the event and user
-    match add_event_attendee(payload.event_id, payload.user_id) {
-        Ok(_) => (),
-        Err(_) => {
-            return Err(AssociationError::Err {
-                msg: format!("Could not add attendee to event id:{} ", payload.event_id),
-                ticket: ticket.clone(),
-            })
-        }
-    }
+    // Relate the ticket to the event, the user, the tier and the seat, which another ticket may hold,
+    // and store it
+    _link_ticket(&ticket)?;
+    TICKET_STORAGE.with(|tickets| tickets.borrow_mut().insert(id, Versioned::new(&ticket)));
+    lifecycle::record(id, None, ticket.status, actor);
+    codes::renew_nonce(id);
+
+    // Return the newly created ticket
+    Ok(ticket)
+}
 
-    match add_user_ticket(payload.user_id, id) {
-        Ok(_) => (),
-        Err(_) => {
-            return Err(AssociationError::Err {
+// Function to find the tier a ticket of an event is issued in: the requested tier, which must belong
+// to the event and have a ticket left, or no tier for events that define none
+fn _resolve_tier(event_id: u64, tier_id: Option<u64>) -> Result<Option<TicketTier>, Error> {
+    match tier_id {
+        Some(tier_id) => {
+            let tier = tiers::get_tier(event_id, tier_id)?.ok_or(Error::NotFound {
                 msg: format!(
-                    "Could not add ticket id:{} to user id:{} ",
-                    id, payload.user_id
+                    "tier id:{} does not exist for event id:{}",
+                    tier_id, event_id
                 ),
-                ticket: ticket.clone(),
-            })
+            })?;
+            tiers::ensure_available(&tier)?;
+            Ok(Some(tier))
         }
+        None if tiers::event_tiers(event_id)?.is_empty() => Ok(None),
+        None => Err(Error::InvalidInput {
+            msg: format!(
+                "event id:{} sells tickets by tier, a tier id is required",
+                event_id
+            ),
+        }),
     }
+}
 
-    match add_event_ticket(payload.event_id, id) {
-        Ok(_) => (),
-        Err(_) => {
-            return Err(AssociationError::Err {
-                msg: format!(
-                    "Could not add ticket id:{} to event id:{} ",
-                    id, payload.event_id
-                ),
-                ticket: ticket.clone(),
-            })
+// Function to relate a ticket to its event, holder, tier and seat, or return a Conflict error and
+// relate it to nothing if another ticket holds its seat
+fn _link_ticket(ticket: &Ticket) -> Result<(), Error> {
+    seating::link_seat(ticket)?;
+    EVENT_TICKETS.with(|relation| {
+        relation
+            .borrow_mut()
+            .insert((ticket.event_id, ticket.id), ())
+    });
+    USER_TICKETS.with(|relation| {
+        relation
+            .borrow_mut()
+            .insert((ticket.user_id, ticket.id), ())
+    });
+    USER_EVENT_TICKETS.with(|relation| {
+        relation
+            .borrow_mut()
+            .insert(((ticket.user_id, ticket.event_id), ticket.id), ())
+    });
+    if let Some(tier_id) = ticket.tier_id {
+        TIER_TICKETS.with(|relation| relation.borrow_mut().insert((tier_id, ticket.id), ()));
+    }
+    Ok(())
+}
+
+// Function to remove the relations of a ticket to its event, holder, tier and seat
+fn _unlink_ticket(ticket: &Ticket) {
+    EVENT_TICKETS.with(|relation| relation.borrow_mut().remove(&(ticket.event_id, ticket.id)));
+    USER_TICKETS.with(|relation| relation.borrow_mut().remove(&(ticket.user_id, ticket.id)));
+    USER_EVENT_TICKETS.with(|relation| {
+        relation
+            .borrow_mut()
+            .remove(&((ticket.user_id, ticket.event_id), ticket.id))
+    });
+    if let Some(tier_id) = ticket.tier_id {
+        TIER_TICKETS.with(|relation| relation.borrow_mut().remove(&(tier_id, ticket.id)));
+    }
+    seating::unlink_seat(ticket);
+}
+
+// Function to check whether a ticket was paid for and can still be used, so deleting it would drop
+// its buyer's payment without a refund or a record of it; a checked-in ticket was used
+fn _is_unrefunded_payment(ticket: &Ticket) -> bool {
+    ticket.payment_block_index.is_some() && ticket.status.is_valid()
+}
+
+// Function to refuse deleting paid tickets that were not refunded
+fn _ensure_no_paid_tickets(tickets: &[Ticket]) -> Result<(), Error> {
+    let paid = tickets
+        .iter()
+        .filter(|ticket| _is_unrefunded_payment(ticket))
+        .count();
+    if paid > 0 {
+        return Err(Error::Conflict {
+            msg: format!(
+                "{} paid tickets are still valid and must be refunded first",
+                paid
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn _load_tickets(ticket_ids: &[u64]) -> Result<Vec<Ticket>, Error> {
+    // Helper function to load the tickets with the given IDs, skipping any that no longer exist
+    let mut tickets = vec![];
+    for ticket_id in ticket_ids {
+        if let Some(ticket) = _get_ticket(ticket_id)? {
+            tickets.push(ticket);
         }
     }
+    Ok(tickets)
+}
 
-    // Return the ID of the newly created ticket
-    Ok(ticket)
+fn _event_ticket_ids(event_id: u64) -> Vec<u64> {
+    // Helper function to list the IDs of the tickets sold for an event
+    EVENT_TICKETS.with(|relation| {
+        relation
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|((_, ticket_id), _)| ticket_id)
+            .collect()
+    })
+}
+
+fn _user_event_ticket_ids(user_id: u64, event_id: u64) -> Vec<u64> {
+    // Helper function to list the IDs of the tickets a user holds for an event
+    let key = (user_id, event_id);
+    USER_EVENT_TICKETS.with(|relation| {
+        relation
+            .borrow()
+            .range((key, 0)..=(key, u64::MAX))
+            .map(|((_, ticket_id), _)| ticket_id)
+            .collect()
+    })
+}
+
+fn _user_ticket_ids(user_id: u64) -> Vec<u64> {
+    // Helper function to list the IDs of the tickets a user holds
+    USER_TICKETS.with(|relation| {
+        relation
+            .borrow()
+            .range((user_id, 0)..=(user_id, u64::MAX))
+            .map(|((_, ticket_id), _)| ticket_id)
+            .collect()
+    })
 }
 
 #[ic_cdk::update]
 fn update_ticket(id: u64, payload: TicketPayload) -> Result<Ticket, Error> {
+    let caller = _authenticated_caller()?;
+
     // Retrieve the existing ticket with the given ID, or return a NotFound error if not found
-    let ticket = _get_ticket(&id).ok_or(Error::NotFound {
+    let ticket = _get_ticket(&id)?.ok_or(Error::NotFound {
         msg: format!("ticket id:{} does not exist", id),
     })?;
 
-    // Create an updated ticket based on the provided payload
-    let updated_ticket = Ticket {
-        id,
+    // Only valid tickets can be moved, and a ticket changing holders becomes Transferred
+    if !ticket.status.is_valid() || refunds::is_pending(id) || resale::is_pending(id) {
+        return Err(Error::Conflict {
+            msg: format!(
+                "ticket id:{} is {:?} and cannot be moved",
+                id, ticket.status
+            ),
+        });
+    }
+
+    // Load the current and the requested event, keyed by ID so each is loaded once, and check
+    // that the requested holder exists
+    let mut events: BTreeMap<u64, Event> = BTreeMap::new();
+    for event_id in [ticket.event_id, payload.event_id] {
+        let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+            msg: format!("event id:{} does not exist", event_id),
+        })?;
+        events.insert(event_id, event);
+    }
+    if _get_user(&payload.user_id)?.is_none() {
+        return Err(Error::NotFound {
+            msg: format!("user id:{} does not exist", payload.user_id),
+        });
+    }
+
+    // Only a manager of both the current and the requested event may reassign the ticket
+    for event in events.values() {
+        roles::ensure_event_manager(event, &caller)?;
+    }
+
+    // Moving a ticket to another event requires both events to allow exchanges and the requested
+    // event to have a seat left
+    if payload.event_id != ticket.event_id {
+        for event in events.values() {
+            if !event.exchangeable {
+                return Err(Error::Conflict {
+                    msg: format!("tickets for event id:{} cannot be exchanged", event.id),
+                });
+            }
+        }
+        _ensure_on_sale(&events[&payload.event_id])?;
+        seating::ensure_general_admission(payload.event_id)?;
+        if _remaining_capacity(&events[&payload.event_id]) == 0 {
+            return Err(Error::SoldOut {
+                msg: format!("event id:{} is sold out", payload.event_id),
+            });
+        }
+    }
+
+    // The ticket keeps its tier unless another one is requested, and a ticket moved to another
+    // event takes a tier of that event, which must have a ticket left
+    let tier_id = match payload.tier_id {
+        Some(tier_id) => Some(tier_id),
+        None if payload.event_id == ticket.event_id => ticket.tier_id,
+        None => None,
+    };
+    if payload.event_id != ticket.event_id || tier_id != ticket.tier_id {
+        // A seated ticket keeps its seat, and with it its event and the seat's tier
+        if let Some(seat_id) = ticket.seat_id {
+            return Err(Error::Conflict {
+                msg: format!(
+                    "ticket id:{} is for seat id:{} and can only change holders",
+                    id, seat_id
+                ),
+            });
+        }
+        _resolve_tier(payload.event_id, tier_id)?;
+    }
+
+    // Nothing to move if neither the event, the holder nor the tier changes
+    if payload.event_id == ticket.event_id
+        && payload.user_id == ticket.user_id
+        && tier_id == ticket.tier_id
+    {
+        return Ok(ticket);
+    }
+
+    // Create an updated ticket based on the provided payload; the price paid and its payment are kept
+    let mut updated_ticket = Ticket {
         event_id: payload.event_id,
         user_id: payload.user_id,
-        created_at: ticket.created_at,
+        tier_id,
         updated_at: Some(time()),
+        ..ticket.clone()
     };
-
-    // Call helper functions to associate the ticket with the event and user
     if payload.user_id != ticket.user_id {
-        match add_user_ticket(payload.user_id, id) {
-            Ok(_) => (),
-            Err(_) => {
-                return Err(Error::NotCreated {
-                    msg: format!(
-                        "Could not add ticket id:{} to user id:{} ",
-                        id, payload.user_id
-                    ),
-                })
-            }
-        }
+        lifecycle::ensure_transition(&ticket, TicketStatus::Transferred)?;
     }
 
-    if payload.event_id != ticket.event_id {
-        match add_event_ticket(payload.event_id, id) {
-            Ok(_) => (),
-            Err(_) => {
-                return Err(Error::NotCreated {
-                    msg: format!(
-                        "Could not add ticket id:{} to event id:{} ",
-                        id, payload.event_id
-                    ),
-                })
-            }
-        }
+    // Move the relations from the previous event and holder to the new ones before anything is
+    // recorded, so a ticket whose seat is taken is left related as it was
+    _unlink_ticket(&ticket);
+    if let Err(err) = _link_ticket(&updated_ticket) {
+        let _ = _link_ticket(&ticket);
+        return Err(err);
     }
 
-    // Insert the updated ticket into the storage
-    match TICKET_STORAGE.with(|tickets| tickets.borrow_mut().insert(id, updated_ticket.clone())) {
-        Some(_) => Ok(updated_ticket),
-        None => Err(Error::NotCreated {
-            msg: format!("ticket id:{} could not be updated", id),
-        }),
+    if payload.user_id != ticket.user_id {
+        lifecycle::transition(&mut updated_ticket, TicketStatus::Transferred, caller)?;
+        transfers::record(id, ticket.user_id, payload.user_id, caller);
+        icrc37::clear_token_approvals(id);
+        codes::renew_nonce(id);
+    }
+    // Signatures name the event and tier, so those handed out for the ticket as it was stop
+    // admitting
+    signing::revoke(&ticket);
+
+    // Store the ticket; a ticket moved to another event frees a place the waitlist of the previous
+    // event is offered
+    TICKET_STORAGE.with(|tickets| {
+        tickets
+            .borrow_mut()
+            .insert(id, Versioned::new(&updated_ticket))
+    });
+    resale::withdraw(&ticket);
+    if payload.event_id != ticket.event_id {
+        waitlist::promote(ticket.event_id);
     }
+
+    Ok(updated_ticket)
 }
 
 #[ic_cdk::update]
 fn delete_ticket(id: u64) -> Result<String, Error> {
-    // Retrieve the ticket ID from the payload
-    let ticket_id = id;
+    let caller = _authenticated_caller()?;
 
     // Retrieve the ticket with the given ID, or return a NotFound error if not found
-    let ticket = _get_ticket(&ticket_id).ok_or(Error::NotFound {
-        msg: format!("ticket id:{} does not exist", ticket_id),
+    let ticket = _get_ticket(&id)?.ok_or(Error::NotFound {
+        msg: format!("ticket id:{} does not exist", id),
     })?;
 
-    // Retrieve the user with the given ID, or return a NotFound error if not found
-    let user_id = ticket.user_id;
-    let mut user = _get_user(&user_id).ok_or(Error::NotFound {
-        msg: format!("user id:{} does not exist", user_id),
-    })?;
+    // Only the holder of the ticket or a manager of its event may cancel it
+    _ensure_ticket_owner(&ticket, &caller)?;
 
-    // Remove the ticket ID from the user's ticket IDs
-    user.ticket_ids.retain(|&id| id != ticket_id);
+    // The ticket is kept, cancelled, so its history stays readable
+    _cancel_unpaid_ticket(ticket, caller)?;
+    Ok(format!("ticket id: {} cancelled", id))
+}
 
-    // Update the user in the storage
-    match USER_STORAGE.with(|users| users.borrow_mut().insert(user_id, user)) {
-        Some(_) => (),
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("user id:{} could not be updated", user_id),
-            })
-        }
+// Function to cancel a ticket without a refund; paid tickets are cancelled with `cancel_ticket`
+// instead, so their payment is never lost track of
+fn _cancel_unpaid_ticket(mut ticket: Ticket, actor: Principal) -> Result<Ticket, Error> {
+    if ticket.payment_block_index.is_some() {
+        return Err(Error::Conflict {
+            msg: format!("ticket id:{} was paid for, use cancel_ticket", ticket.id),
+        });
     }
-    // Retrieve the event with the given ID, or return a NotFound error if not found
-    let event_id = ticket.event_id;
-    let mut event = _get_event(&event_id).ok_or(Error::NotFound {
-        msg: format!("event id:{} does not exist", event_id),
-    })?;
+    lifecycle::transition(&mut ticket, TicketStatus::Cancelled, actor)?;
+    TICKET_STORAGE.with(|tickets| {
+        tickets
+            .borrow_mut()
+            .insert(ticket.id, Versioned::new(&ticket))
+    });
 
-    // Remove the ticket ID from the event's ticket IDs
-    event.ticket_ids.retain(|&id| id != ticket_id);
+    // Offer the freed seat to the next user on the waitlist
+    waitlist::promote(ticket.event_id);
+    Ok(ticket)
+}
 
-    // Update the event in the storage
-    match EVENT_STORAGE.with(|events| events.borrow_mut().insert(event_id, event)) {
-        Some(_) => (),
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("event id:{} could not be updated", event_id),
-            })
-        }
+#[ic_cdk::query]
+fn get_event_attendees(id: u64) -> Result<Vec<UserProfile>, Error> {
+    // Check that the event with the given ID exists, or return a NotFound error if not found
+    if _get_event(&id)?.is_none() {
+        return Err(Error::NotFound {
+            msg: format!("event id:{} does not exist", id),
+        });
     }
-    // Delete the ticket from the storage
-    match TICKET_STORAGE.with(|tickets| tickets.borrow_mut().remove(&ticket_id)) {
-        Some(_) => (),
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("ticket id:{} could not be deleted from event", ticket_id),
-            })
+
+    // Collect the holders of the event's valid tickets, listing each attendee once
+    let mut attendee_ids = BTreeSet::new();
+    for ticket_id in _event_ticket_ids(id) {
+        if let Some(ticket) = _get_ticket(&ticket_id)? {
+            if ticket.status.holds_seat() {
+                attendee_ids.insert(ticket.user_id);
+            }
         }
     }
-    // Return Ok indicating a successful deletion
-    Ok(format!("ticket id: {} deleted", ticket_id))
-}
-
-#[ic_cdk::query]
-fn get_event_attendees(id: u64) -> Result<Vec<User>, Error> {
-    // Retrieve the event with the given ID, or return a NotFound error if not found
-    let event = _get_event(&id).ok_or(Error::NotFound {
-        msg: format!("event id:{} does not exist", id),
-    })?;
 
     // Initialize a vector to store the attendees
     let mut attendees = vec![];
 
     // Iterate over the attendee IDs of the event and retrieve the corresponding users
-    for attendee_id in event.attendee_ids {
-        let attendee = _get_user(&attendee_id).ok_or(Error::NotFound {
+    for attendee_id in attendee_ids {
+        let attendee = _get_user(&attendee_id)?.ok_or(Error::NotFound {
             msg: format!("user id:{} does not exist", attendee_id),
         })?;
 
         // Add the attendee to the vector
-        attendees.push(attendee);
+        attendees.push(attendee.into());
     }
 
     // Return the vector of attendees
     Ok(attendees)
 }
 
-// Function to add an attendee to an event
-fn add_event_attendee(event_id: u64, user_id: u64) -> Result<(), Error> {
-    // Retrieve the event with the given ID, or return a NotFound error if not found
-    let event = _get_event(&event_id).ok_or(Error::NotFound {
-        msg: format!("event id:{} does not exist", event_id),
-    })?;
-
-    // Retrieve the user with the given ID, or return a NotFound error if not found
-    let user = _get_user(&user_id).ok_or(Error::NotFound {
-        msg: format!("user id:{} does not exist", user_id),
-    })?;
-
-    // Clone the current attendee IDs and add the new user ID
-    let mut attendees = event.attendee_ids.clone();
-    attendees.push(user.id);
-
-    // Create an updated event with the new attendee IDs
-    let updated_event = Event {
-        id: event.id,
-        name: event.name,
-        description: event.description,
-        date: event.date,
-        start_time: event.start_time,
-        location: event.location,
-        attendee_ids: attendees,
-        ticket_ids: event.ticket_ids,
-        created_at: event.created_at,
-        updated_at: Some(time()),
-    };
-
-    // Update the event in the storage
-    EVENT_STORAGE.with(|events| events.borrow_mut().insert(event.id, updated_event));
-
-    // Return Ok indicating a successful update
-    Ok(())
-}
-
-// Function to add a ticket to an event
-fn add_event_ticket(event_id: u64, ticket_id: u64) -> Result<(), Error> {
-    // Retrieve the event with the given ID, or return a NotFound error if not found
-    let event = _get_event(&event_id).ok_or(Error::NotFound {
-        msg: format!("event id:{} does not exist", event_id),
-    })?;
-
-    // Retrieve the ticket with the given ID, or return a NotFound error if not found
-    let ticket = _get_ticket(&ticket_id).ok_or(Error::NotFound {
-        msg: format!("ticket id:{} does not exist", ticket_id),
-    })?;
-
-    // Clone the current ticket IDs and add the new ticket ID
-    let mut tickets = event.ticket_ids.clone();
-    tickets.push(ticket.id);
-
-    // Create an updated event with the new ticket IDs
-    let updated_event = Event {
-        id: event.id,
-        name: event.name,
-        description: event.description,
-        date: event.date,
-        start_time: event.start_time,
-        location: event.location,
-        attendee_ids: event.attendee_ids,
-        ticket_ids: tickets,
-        created_at: event.created_at,
-        updated_at: Some(time()),
-    };
-
-    // Update the event in the storage
-    EVENT_STORAGE.with(|events| events.borrow_mut().insert(event.id, updated_event));
-
-    // Return Ok indicating a successful update
-    Ok(())
-}
-
 #[ic_cdk::query]
 fn get_user_tickets(id: u64) -> Result<Vec<Ticket>, Error> {
-    // Retrieve the user with the given ID, or return a NotFound error if not found
-    let user = _get_user(&id).ok_or(Error::NotFound {
-        msg: format!("user id:{} does not exist", id),
-    })?;
+    // Check that the user with the given ID exists, or return a NotFound error if not found
+    if _get_user(&id)?.is_none() {
+        return Err(Error::NotFound {
+            msg: format!("user id:{} does not exist", id),
+        });
+    }
 
     // Initialize a vector to store the user's tickets
     let mut tickets = vec![];
 
     // Iterate over the ticket IDs of the user and retrieve the corresponding tickets
-    for ticket_id in user.ticket_ids {
-        let ticket = _get_ticket(&ticket_id).ok_or(Error::NotFound {
+    for ticket_id in _user_ticket_ids(id) {
+        let ticket = _get_ticket(&ticket_id)?.ok_or(Error::NotFound {
             msg: format!("ticket id:{} does not exist", ticket_id),
         })?;
 
@@ -660,17 +1960,19 @@ fn get_user_tickets(id: u64) -> Result<Vec<Ticket>, Error> {
 
 #[ic_cdk::query]
 fn get_event_tickets(id: u64) -> Result<Vec<Ticket>, Error> {
-    // Retrieve the event with the given ID, or return a NotFound error if not found
-    let event = _get_event(&id).ok_or(Error::NotFound {
-        msg: format!("event id:{} does not exist", id),
-    })?;
+    // Check that the event with the given ID exists, or return a NotFound error if not found
+    if _get_event(&id)?.is_none() {
+        return Err(Error::NotFound {
+            msg: format!("event id:{} does not exist", id),
+        });
+    }
 
     // Initialize a vector to store the event's tickets
     let mut tickets = vec![];
 
     // Iterate over the ticket IDs of the event and retrieve the corresponding tickets
-    for ticket_id in event.ticket_ids {
-        let ticket = _get_ticket(&ticket_id).ok_or(Error::NotFound {
+    for ticket_id in _event_ticket_ids(id) {
+        let ticket = _get_ticket(&ticket_id)?.ok_or(Error::NotFound {
             msg: format!("ticket id:{} does not exist", ticket_id),
         })?;
 
@@ -682,109 +1984,162 @@ fn get_event_tickets(id: u64) -> Result<Vec<Ticket>, Error> {
     Ok(tickets)
 }
 
-// Function to add a ticket to a user's tickets
-fn add_user_ticket(user_id: u64, ticket_id: u64) -> Result<(), Error> {
-    // Retrieve the user with the given ID, or return a NotFound error if not found
-    let user = _get_user(&user_id).ok_or(Error::NotFound {
-        msg: format!("user id:{} does not exist", user_id),
-    })?;
-
-    // Retrieve the ticket with the given ID, or return a NotFound error if not found
-    let ticket = _get_ticket(&ticket_id).ok_or(Error::NotFound {
-        msg: format!("ticket id:{} does not exist", ticket_id),
-    })?;
-
-    // Clone the current ticket IDs and add the new ticket ID
-    let mut tickets = user.ticket_ids.clone();
-    tickets.push(ticket.id);
-
-    // Create an updated user with the new ticket IDs
-    let updated_user = User {
-        id: user.id,
-        name: user.name,
-        email: user.email,
-        password: user.password,
-        event_ids: user.event_ids,
-        ticket_ids: tickets,
-        created_at: user.created_at,
-        updated_at: Some(time()),
-    };
-
-    // Update the user in the storage
-    USER_STORAGE.with(|users| users.borrow_mut().insert(user.id, updated_user));
-
-    // Return Ok indicating a successful update
-    Ok(())
-}
-
 #[ic_cdk::update]
 fn remove_user_ticket(payload: TicketPayload) -> Result<String, Error> {
+    let caller = _authenticated_caller()?;
+
     // Retrieve the event ID and user ID from the payload
     let event_id = payload.event_id;
     let user_id = payload.user_id;
 
     // Retrieve the user with the given ID, or return a NotFound error if not found
-    let user = _get_user(&user_id).ok_or(Error::NotFound {
+    let user = _get_user(&user_id)?.ok_or(Error::NotFound {
         msg: format!("user id:{} does not exist", user_id),
     })?;
 
-    // Find the ticket with the given event ID that belongs to the user
-    let ticket_id = user.ticket_ids.iter().find(|&&ticket_id| {
-        let ticket = _get_ticket(&ticket_id);
-        ticket.is_some() && ticket.unwrap().event_id == event_id
-    });
-
-    // If the ticket is not found, return a NotFound error
-    let ticket_id = ticket_id.ok_or(Error::NotFound {
+    // Only the principal bound to the user may remove its tickets
+    _ensure_user_owner(&user, &caller)?;
+
+    // Find a valid ticket with the given event ID that belongs to the user, or return a NotFound
+    // error
+    let mut ticket = None;
+    for ticket_id in _user_event_ticket_ids(user.id, event_id) {
+        if let Some(candidate) = _get_ticket(&ticket_id)? {
+            if candidate.status.is_valid() {
+                ticket = Some(candidate);
+                break;
+            }
+        }
+    }
+    let ticket = ticket.ok_or(Error::NotFound {
         msg: format!(
             "No ticket found for event id:{} for user id:{}",
             event_id, user_id
         ),
     })?;
 
-    // Clone the current ticket IDs and remove the specified ticket ID
-    let mut tickets = user.ticket_ids.clone();
-    tickets.retain(|&id| id != *ticket_id);
+    // Cancel the ticket, which stays in the user's tickets with its history
+    let ticket = _cancel_unpaid_ticket(ticket, caller)?;
+    Ok(format!(
+        "ticket id: {} for event id: {} cancelled",
+        ticket.id, event_id
+    ))
+}
 
-    // Create an updated user with the modified ticket IDs
-    let updated_user = User {
-        id: user.id,
-        name: user.name,
-        email: user.email,
-        password: user.password,
-        event_ids: user.event_ids,
-        ticket_ids: tickets,
-        created_at: user.created_at,
-        updated_at: Some(time()),
-    };
+// Define an Error enum for handling errors
+#[derive(candid::CandidType, Debug, Deserialize, Serialize)]
+enum Error {
+    NotFound {
+        msg: String,
+    },
+    NotCreated {
+        msg: String,
+    },
+    Unauthorized {
+        msg: String,
+    },
+    SoldOut {
+        msg: String,
+    },
+    InvalidInput {
+        msg: String,
+    },
+    Conflict {
+        msg: String,
+    },
+    DecodeFailed {
+        msg: String,
+    },
+    // The buyer's ledger balance does not cover the price
+    InsufficientFunds {
+        msg: String,
+    },
+    // The buyer has not approved this canister for the price and the ledger fee
+    InsufficientAllowance {
+        msg: String,
+    },
+    // The ledger refused the transfer for another reason
+    PaymentFailed {
+        msg: String,
+    },
+    // The ledger could not be reached or is temporarily unavailable; retrying may succeed
+    LedgerUnavailable {
+        msg: String,
+    },
+    // The ticket was already admitted, at the given time and gate
+    AlreadyUsed {
+        msg: String,
+        checked_in_at: u64,
+        gate: String,
+    },
+}
 
-    // Update the user in the storage
-    match USER_STORAGE.with(|users| users.borrow_mut().insert(user.id, updated_user)) {
-        Some(_) => (),
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("user id:{} could not be deleted", user.id),
-            })
+impl Error {
+    // Function to get the message an error carries
+    fn message(&self) -> &str {
+        match self {
+            Error::NotFound { msg }
+            | Error::NotCreated { msg }
+            | Error::Unauthorized { msg }
+            | Error::SoldOut { msg }
+            | Error::InvalidInput { msg }
+            | Error::Conflict { msg }
+            | Error::DecodeFailed { msg }
+            | Error::InsufficientFunds { msg }
+            | Error::InsufficientAllowance { msg }
+            | Error::PaymentFailed { msg }
+            | Error::LedgerUnavailable { msg }
+            | Error::AlreadyUsed { msg, .. } => msg,
         }
     }
+}
 
-    Ok(format!(
-        "ticket id: {} for event id: {} deleted",
-        ticket_id, event_id
-    ))
+#[ic_cdk::init]
+fn init() {
+    // Start counting the token supply
+    icrc7::rebuild_indexes();
+
+    // Draw the key that signs ticket codes and fetch the key that verifies signed tickets
+    codes::schedule_key_setup(Duration::ZERO);
+    signing::schedule_key_fetch(Duration::ZERO);
+
+    // Start sweeping lapsed resale listings and retrying failed payouts, and expired sessions
+    resale::schedule_upkeep();
+    auth::schedule_upkeep();
+
+    // Start releasing reservations that were not confirmed in time
+    reservations::schedule_release();
 }
 
-// Define an Error enum for handling errors
-#[derive(candid::CandidType, Deserialize, Serialize)]
-enum Error {
-    NotFound { msg: String },
-    NotCreated { msg: String },
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    // Rewrite every record in its current schema version, in batches, so older records are
+    // migrated only once; until then, reads run the same migrations on the fly
+    migrations::start_migration();
+
+    // Count the token supply and index the deduplicated transactions and the seat positions, once
+    icrc7::rebuild_indexes();
+    seating::index_seat_positions();
+
+    // Canisters installed before tickets had codes or signatures set up their keys now
+    codes::schedule_key_setup(Duration::ZERO);
+    signing::schedule_key_fetch(Duration::ZERO);
+
+    // Start sweeping lapsed resale listings and retrying failed payouts, and expired sessions
+    resale::schedule_upkeep();
+    auth::schedule_upkeep();
+
+    // Start releasing reservations that were not confirmed in time
+    reservations::schedule_release();
+
+    // Restart the expiry of the waitlist offers made before the upgrade
+    waitlist::schedule_expiries();
 }
 
-// Define an Error enum for handling errors
-#[derive(candid::CandidType, Deserialize, Serialize)]
-enum AssociationError {
-    Err { msg: String, ticket: Ticket },
+#[ic_cdk::query]
+fn schema_version() -> SchemaVersion {
+    // Return the schema version this build writes for each kind of record
+    migrations::schema_version()
 }
 
 // Candid generator for exporting the Candid interface
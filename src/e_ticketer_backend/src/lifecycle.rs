@@ -0,0 +1,276 @@
+use crate::{
+    _authenticated_caller, _ensure_ticket_owner, _event_ticket_ids, _get_event, _get_ticket, icrc7,
+    refunds, resale, reservations, roles, signing, waitlist, Error, Memory, Record, Ticket,
+    Versioned, MEMORY_MANAGER, TICKET_STORAGE,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Largest number of tickets a single `expire_tickets` call expires; the caller calls again for the rest
+const EXPIRY_BATCH_SIZE: usize = 200;
+
+// Define an enum for where a ticket stands in its lifecycle
+#[derive(
+    candid::CandidType, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub(crate) enum TicketStatus {
+    // Holds a seat while it is being paid for
+    Reserved,
+    // Valid for admission
+    #[default]
+    Issued,
+    // Admitted at the door
+    CheckedIn,
+    // Valid for admission, after moving to another holder
+    Transferred,
+    // Cancelled without a refund
+    Cancelled,
+    // Cancelled, with its refund sent back to the buyer
+    Refunded,
+    // Neither used nor cancelled before its event ended
+    Expired,
+}
+
+impl TicketStatus {
+    // Function to check whether a ticket in this status takes a seat of its event and tier
+    pub(crate) fn holds_seat(&self) -> bool {
+        matches!(
+            self,
+            TicketStatus::Reserved
+                | TicketStatus::Issued
+                | TicketStatus::Transferred
+                | TicketStatus::CheckedIn
+        )
+    }
+
+    // Function to check whether a ticket in this status admits its holder
+    pub(crate) fn is_valid(&self) -> bool {
+        matches!(self, TicketStatus::Issued | TicketStatus::Transferred)
+    }
+
+    // Function to check whether a ticket may move from this status to another one
+    fn can_become(&self, to: TicketStatus) -> bool {
+        use TicketStatus::*;
+        match self {
+            Reserved => matches!(to, Issued | Cancelled | Expired),
+            Issued | Transferred => {
+                matches!(to, CheckedIn | Transferred | Cancelled | Refunded | Expired)
+            }
+            CheckedIn | Cancelled | Refunded | Expired => false,
+        }
+    }
+}
+
+// Define a struct for one transition in the history of a ticket
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct StatusChange {
+    // None for the transition that created the ticket
+    from: Option<TicketStatus>,
+    to: TicketStatus,
+    actor: Principal,
+    at: u64,
+}
+
+impl Record for StatusChange {
+    const KIND: &'static str = "status change";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 128;
+}
+
+thread_local! {
+    // Transitions of each ticket, keyed by ticket and by position in its history
+    static TICKET_HISTORY: RefCell<StableBTreeMap<(u64, u32), Versioned<StatusChange>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+}
+
+// Function to check that a ticket may move to the given status; a ticket being refunded, resold or
+// paid for only moves once the refund, sale or payment completes
+pub(crate) fn ensure_transition(ticket: &Ticket, to: TicketStatus) -> Result<(), Error> {
+    if refunds::is_pending(ticket.id) {
+        return Err(Error::Conflict {
+            msg: format!("ticket id:{} is being refunded", ticket.id),
+        });
+    }
+    if resale::is_pending(ticket.id) {
+        return Err(Error::Conflict {
+            msg: format!("ticket id:{} is being resold", ticket.id),
+        });
+    }
+    if waitlist::is_pending(ticket.id) || reservations::is_pending(ticket.id) {
+        return Err(Error::Conflict {
+            msg: format!("ticket id:{} is being paid for", ticket.id),
+        });
+    }
+    if !ticket.status.can_become(to) {
+        return Err(Error::Conflict {
+            msg: format!(
+                "ticket id:{} is {:?} and cannot become {:?}",
+                ticket.id, ticket.status, to
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to move a ticket to the given status and record the transition, withdrawing any resale
+// listing or waitlist offer made for the ticket as it was and revoking its signatures; the caller
+// stores the ticket
+pub(crate) fn transition(
+    ticket: &mut Ticket,
+    to: TicketStatus,
+    actor: Principal,
+) -> Result<(), Error> {
+    ensure_transition(ticket, to)?;
+    let from = ticket.status;
+    ticket.status = to;
+    ticket.updated_at = Some(time());
+    record(ticket.id, Some(from), to, actor);
+    resale::withdraw(ticket);
+    waitlist::withdraw_offer(ticket);
+    signing::revoke(ticket);
+    Ok(())
+}
+
+// Function to append a transition to the history of a ticket and count it in the token supply
+pub(crate) fn record(
+    ticket_id: u64,
+    from: Option<TicketStatus>,
+    to: TicketStatus,
+    actor: Principal,
+) {
+    icrc7::count_status_change(from, Some(to));
+    TICKET_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let position = history
+            .range((ticket_id, 0)..=(ticket_id, u32::MAX))
+            .count() as u32;
+        history.insert(
+            (ticket_id, position),
+            Versioned::new(&StatusChange {
+                from,
+                to,
+                actor,
+                at: time(),
+            }),
+        );
+    });
+}
+
+// Function to remove the history of a deleted ticket
+pub(crate) fn remove_history(ticket_id: u64) {
+    TICKET_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let keys: Vec<(u64, u32)> = history
+            .range((ticket_id, 0)..=(ticket_id, u32::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            history.remove(&key);
+        }
+    });
+}
+
+#[ic_cdk::query]
+fn get_ticket_history(id: u64) -> Result<Vec<StatusChange>, Error> {
+    // Retrieve the ticket with the given ID, or return a NotFound error if not found
+    let ticket = _get_ticket(&id)?.ok_or(Error::NotFound {
+        msg: format!("ticket id:{} does not exist", id),
+    })?;
+
+    // Only the holder of the ticket or a manager of its event may read its history
+    _ensure_ticket_owner(&ticket, &ic_cdk::caller())?;
+
+    TICKET_HISTORY.with(|history| {
+        history
+            .borrow()
+            .range((id, 0)..=(id, u32::MAX))
+            .map(|(_, change)| change.decode())
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+fn expire_tickets(event_id: u64) -> Result<Vec<u64>, Error> {
+    let caller = _authenticated_caller()?;
+
+    // Retrieve the event with the given ID, or return a NotFound error if not found
+    let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+
+    // Only the organizer who owns the event, or an admin, may expire its tickets, once it has ended
+    roles::ensure_event_manager(&event, &caller)?;
+    if time() < event.ends_at {
+        return Err(Error::Conflict {
+            msg: format!("event id:{} has not ended yet", event_id),
+        });
+    }
+
+    // Expire the next batch of tickets that were never used; the caller calls again until none are
+    // left
+    let mut expired = vec![];
+    for ticket_id in _event_ticket_ids(event_id) {
+        if expired.len() == EXPIRY_BATCH_SIZE {
+            break;
+        }
+        if let Some(mut ticket) = _get_ticket(&ticket_id)? {
+            if ticket.status.is_valid() && !refunds::is_pending(ticket.id) {
+                transition(&mut ticket, TicketStatus::Expired, caller)?;
+                TICKET_STORAGE.with(|tickets| {
+                    tickets
+                        .borrow_mut()
+                        .insert(ticket.id, Versioned::new(&ticket))
+                });
+                expired.push(ticket.id);
+            }
+        }
+    }
+    Ok(expired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TicketStatus::{self, *};
+
+    const ALL: [TicketStatus; 7] = [
+        Reserved,
+        Issued,
+        CheckedIn,
+        Transferred,
+        Cancelled,
+        Refunded,
+        Expired,
+    ];
+
+    // Function to list the statuses a ticket in the given status may move to
+    fn successors(from: TicketStatus) -> Vec<TicketStatus> {
+        ALL.into_iter().filter(|to| from.can_become(*to)).collect()
+    }
+
+    #[test]
+    fn reserved_tickets_are_issued_or_given_up() {
+        assert_eq!(successors(Reserved), vec![Issued, Cancelled, Expired]);
+    }
+
+    #[test]
+    fn valid_tickets_are_used_moved_or_ended() {
+        for from in [Issued, Transferred] {
+            assert_eq!(
+                successors(from),
+                vec![CheckedIn, Transferred, Cancelled, Refunded, Expired]
+            );
+        }
+    }
+
+    #[test]
+    fn ended_tickets_never_change() {
+        for from in [CheckedIn, Cancelled, Refunded, Expired] {
+            assert!(successors(from).is_empty());
+        }
+    }
+}
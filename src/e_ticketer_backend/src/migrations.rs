@@ -0,0 +1,959 @@
+use crate::{
+    _email_key, _link_ticket, refunds::RefundPolicy, seating::Venue, tiers::TicketTier,
+    transfers::TransferRules, Error, Event, Memory, Ticket, TicketStatus, User, EVENT_STORAGE,
+    TICKET_STORAGE, USER_EMAIL_INDEX, USER_EVENT_TICKETS, USER_STORAGE,
+};
+use candid::{CandidType, Decode, Encode, Nat, Principal};
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::de::DeserializeOwned;
+use std::thread::LocalKey;
+use std::{borrow::Cow, cell::RefCell, marker::PhantomData, time::Duration};
+
+// Versioned records start with this tag, which can never begin a Candid message ("DIDL")
+const ENVELOPE_MAGIC: &[u8; 4] = b"VREC";
+
+// Length of the tag followed by the little-endian schema version
+const ENVELOPE_HEADER_LEN: usize = 8;
+
+// Most records a single migration message rewrites
+const MIGRATION_BATCH_SIZE: usize = 500;
+
+// Define a struct for one step of a migration registry, upgrading a record from one version
+// to the next
+pub(crate) struct Migration {
+    pub from: u32,
+    pub migrate: fn(&[u8]) -> Result<Vec<u8>, Error>,
+}
+
+// Define a trait for records stored in a versioned envelope
+pub(crate) trait Record: CandidType + DeserializeOwned {
+    // Name used in error messages
+    const KIND: &'static str;
+    // Schema version written by this build
+    const VERSION: u32;
+    // Largest encoded record, including the envelope header
+    const MAX_SIZE: u32;
+
+    // Migration steps from every older version up to the current one
+    fn migrations() -> &'static [Migration] {
+        &[]
+    }
+
+    // Version of a record written before records carried an envelope
+    fn unversioned_version(_bytes: &[u8]) -> u32 {
+        1
+    }
+}
+
+// Define a struct for a record as stored, decoded only on demand so a bad record surfaces as
+// an error instead of a trap
+pub(crate) struct Versioned<T> {
+    bytes: Vec<u8>,
+    record: PhantomData<T>,
+}
+
+impl<T: Record> Versioned<T> {
+    // Function to wrap a record in an envelope carrying the current schema version
+    pub(crate) fn new(record: &T) -> Self {
+        let mut bytes = ENVELOPE_MAGIC.to_vec();
+        bytes.extend_from_slice(&T::VERSION.to_le_bytes());
+        bytes.extend_from_slice(&Encode!(record).expect("records are always encodable"));
+        Versioned {
+            bytes,
+            record: PhantomData,
+        }
+    }
+
+    // Function to wrap a record, rejecting it if it does not fit in its stable-memory slot
+    pub(crate) fn try_new(record: &T) -> Result<Self, Error> {
+        let versioned = Self::new(record);
+        if versioned.bytes.len() > T::MAX_SIZE as usize {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "{} record is {} bytes, at most {} can be stored",
+                    T::KIND,
+                    versioned.bytes.len(),
+                    T::MAX_SIZE
+                ),
+            });
+        }
+        Ok(versioned)
+    }
+
+    // Function to split the envelope into its schema version and Candid payload
+    fn split(&self) -> (u32, &[u8]) {
+        match self.bytes.strip_prefix(ENVELOPE_MAGIC) {
+            Some(rest) if rest.len() >= ENVELOPE_HEADER_LEN - ENVELOPE_MAGIC.len() => {
+                let (version, payload) = rest.split_at(ENVELOPE_HEADER_LEN - ENVELOPE_MAGIC.len());
+                (
+                    u32::from_le_bytes(version.try_into().expect("version is four bytes")),
+                    payload,
+                )
+            }
+            _ => (T::unversioned_version(&self.bytes), &self.bytes),
+        }
+    }
+
+    // Function to get the schema version the record was written with
+    pub(crate) fn version(&self) -> u32 {
+        self.split().0
+    }
+
+    // Function to decode the record, running every registered migration it is missing
+    pub(crate) fn decode(&self) -> Result<T, Error> {
+        let (mut version, payload) = self.split();
+        if version > T::VERSION {
+            return Err(Error::DecodeFailed {
+                msg: format!(
+                    "{} record has schema version {} but this build reads up to {}",
+                    T::KIND,
+                    version,
+                    T::VERSION
+                ),
+            });
+        }
+
+        let mut payload = Cow::Borrowed(payload);
+        while version < T::VERSION {
+            let migration = T::migrations()
+                .iter()
+                .find(|migration| migration.from == version)
+                .ok_or_else(|| Error::DecodeFailed {
+                    msg: format!(
+                        "no migration for {} records from version {}",
+                        T::KIND,
+                        version
+                    ),
+                })?;
+            payload = Cow::Owned((migration.migrate)(&payload)?);
+            version += 1;
+        }
+
+        Decode!(&payload, T).map_err(|e| Error::DecodeFailed {
+            msg: format!("{} record could not be decoded: {}", T::KIND, e),
+        })
+    }
+}
+
+impl<T> Storable for Versioned<T> {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.bytes)
+    }
+    // Conversion from bytes, deferring decoding to `decode`
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Versioned {
+            bytes: bytes.into_owned(),
+            record: PhantomData,
+        }
+    }
+}
+
+impl<T: Record> BoundedStorable for Versioned<T> {
+    const MAX_SIZE: u32 = T::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define a struct reporting the schema version of each kind of record
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct SchemaVersion {
+    events: u32,
+    users: u32,
+    tickets: u32,
+    tiers: u32,
+    venues: u32,
+}
+
+// Function to get the schema versions written by this build
+pub(crate) fn schema_version() -> SchemaVersion {
+    SchemaVersion {
+        events: Event::VERSION,
+        users: User::VERSION,
+        tickets: Ticket::VERSION,
+        tiers: TicketTier::VERSION,
+        venues: Venue::VERSION,
+    }
+}
+
+// Timezone assigned to events migrated from free-form date strings
+const LEGACY_TIMEZONE: &str = "UTC";
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Length assumed for events migrated from free-form date strings, which recorded no end
+const LEGACY_EVENT_DURATION_NANOS: u64 = 4 * 60 * 60 * NANOS_PER_SECOND;
+
+// Owner and principal given to records written before events and users were bound to principals;
+// the anonymous principal can never call an update, so only admins manage such events, and such
+// users are bound to their owner's principal by an admin
+pub(crate) const UNCLAIMED_PRINCIPAL: Principal = Principal::anonymous();
+
+// Define a struct for version 0 of 'Event', as stored by the first release of the canister
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BaselineEvent {
+    id: u64,
+    name: String,
+    description: String,
+    date: String,
+    start_time: String,
+    location: String,
+    attendee_ids: Vec<u64>,
+    ticket_ids: Vec<u64>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for version 1 of 'Event', stored before it carried typed start and end instants
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LegacyEvent {
+    id: u64,
+    owner: Principal,
+    name: String,
+    description: String,
+    date: String,
+    start_time: String,
+    location: String,
+    capacity: u32,
+    exchangeable: bool,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for version 3 of 'Event', stored before it carried a refund policy
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EventV3 {
+    id: u64,
+    owner: Principal,
+    name: String,
+    description: String,
+    starts_at: u64,
+    ends_at: u64,
+    timezone: String,
+    location: String,
+    capacity: u32,
+    exchangeable: bool,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for version 4 of 'Event', stored before it carried transfer rules
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EventV4 {
+    id: u64,
+    owner: Principal,
+    name: String,
+    description: String,
+    starts_at: u64,
+    ends_at: u64,
+    timezone: String,
+    location: String,
+    capacity: u32,
+    exchangeable: bool,
+    refund_policy: RefundPolicy,
+    cancelled_at: Option<u64>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl From<LegacyEvent> for EventV3 {
+    fn from(legacy: LegacyEvent) -> Self {
+        // Dates that cannot be parsed fall back to the creation time so the event stays readable;
+        // the end is unknown, so the event is assumed to last a few hours until the organizer
+        // updates it, which keeps it open for check-in and signed tickets valid meanwhile
+        let starts_at =
+            parse_legacy_instant(&legacy.date, &legacy.start_time).unwrap_or(legacy.created_at);
+        EventV3 {
+            id: legacy.id,
+            owner: legacy.owner,
+            name: legacy.name,
+            description: legacy.description,
+            starts_at,
+            ends_at: starts_at.saturating_add(LEGACY_EVENT_DURATION_NANOS),
+            timezone: LEGACY_TIMEZONE.to_string(),
+            location: legacy.location,
+            capacity: legacy.capacity,
+            exchangeable: legacy.exchangeable,
+            created_at: legacy.created_at,
+            updated_at: legacy.updated_at,
+        }
+    }
+}
+
+// Function to read a legacy "YYYY-MM-DD" date and "HH:MM[:SS]" time as UTC nanoseconds since epoch
+fn parse_legacy_instant(date: &str, start_time: &str) -> Option<u64> {
+    let mut date_parts = date.trim().splitn(3, '-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: u32 = date_parts.next()?.parse().ok()?;
+    let day: u32 = date_parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // A missing time is read as midnight
+    let mut time_parts = start_time.trim().splitn(3, ':');
+    let hour: u64 = time_parts
+        .next()
+        .filter(|s| !s.is_empty())
+        .map_or(Some(0), |s| s.parse().ok())?;
+    let minute: u64 = time_parts.next().map_or(Some(0), |s| s.parse().ok())?;
+    let second: u64 = time_parts.next().map_or(Some(0), |s| s.parse().ok())?;
+    if hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let seconds = days * SECONDS_PER_DAY + hour * 3600 + minute * 60 + second;
+    seconds.checked_mul(NANOS_PER_SECOND)
+}
+
+// Function to count the days from 1970-01-01 to a proleptic Gregorian date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Function to upgrade a first-release event to one owned by no one, with no capacity limit, whose
+// tickets cannot be exchanged
+fn migrate_event_v0(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let baseline = Decode!(bytes, BaselineEvent).map_err(|e| Error::DecodeFailed {
+        msg: format!("event record could not be decoded as version 0: {}", e),
+    })?;
+    let event = LegacyEvent {
+        id: baseline.id,
+        owner: UNCLAIMED_PRINCIPAL,
+        name: baseline.name,
+        description: baseline.description,
+        date: baseline.date,
+        start_time: baseline.start_time,
+        location: baseline.location,
+        capacity: u32::MAX,
+        exchangeable: false,
+        created_at: baseline.created_at,
+        updated_at: baseline.updated_at,
+    };
+    Ok(Encode!(&event).expect("records are always encodable"))
+}
+
+// Function to upgrade an event from free-form date strings to typed instants; its membership lists
+// are left to `rebuild_ticket_relations`
+fn migrate_event_v1(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let legacy = Decode!(bytes, LegacyEvent).map_err(|e| Error::DecodeFailed {
+        msg: format!("event record could not be decoded as version 1: {}", e),
+    })?;
+    Ok(Encode!(&EventV3::from(legacy)).expect("records are always encodable"))
+}
+
+// Function to upgrade an event to one that grants no refunds, as none were granted before, and is
+// not cancelled
+fn migrate_event_v3(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let legacy = Decode!(bytes, EventV3).map_err(|e| Error::DecodeFailed {
+        msg: format!("event record could not be decoded as version 3: {}", e),
+    })?;
+    let event = EventV4 {
+        id: legacy.id,
+        owner: legacy.owner,
+        name: legacy.name,
+        description: legacy.description,
+        starts_at: legacy.starts_at,
+        ends_at: legacy.ends_at,
+        timezone: legacy.timezone,
+        location: legacy.location,
+        capacity: legacy.capacity,
+        exchangeable: legacy.exchangeable,
+        refund_policy: RefundPolicy::NoRefund,
+        cancelled_at: None,
+        created_at: legacy.created_at,
+        updated_at: legacy.updated_at,
+    };
+    Ok(Encode!(&event).expect("records are always encodable"))
+}
+
+// Function to upgrade an event to one whose tickets cannot be transferred by their holders, as none
+// could be before
+fn migrate_event_v4(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let legacy = Decode!(bytes, EventV4).map_err(|e| Error::DecodeFailed {
+        msg: format!("event record could not be decoded as version 4: {}", e),
+    })?;
+    let event = Event {
+        id: legacy.id,
+        owner: legacy.owner,
+        name: legacy.name,
+        description: legacy.description,
+        starts_at: legacy.starts_at,
+        ends_at: legacy.ends_at,
+        timezone: legacy.timezone,
+        location: legacy.location,
+        capacity: legacy.capacity,
+        exchangeable: legacy.exchangeable,
+        refund_policy: legacy.refund_policy,
+        transfer_rules: TransferRules::default(),
+        cancelled_at: legacy.cancelled_at,
+        created_at: legacy.created_at,
+        updated_at: legacy.updated_at,
+    };
+    Ok(Encode!(&event).expect("records are always encodable"))
+}
+
+// Define a struct for version 0 of 'User', as stored by the first release of the canister
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BaselineUser {
+    id: u64,
+    name: String,
+    email: String,
+    password: String,
+    event_ids: Vec<u64>,
+    ticket_ids: Vec<u64>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for version 1 of 'User', stored while it still listed its events and tickets
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct UserV1 {
+    id: u64,
+    principal: Principal,
+    name: String,
+    email: String,
+    password: Option<String>,
+    password_hash: Option<String>,
+    event_ids: Vec<u64>,
+    ticket_ids: Vec<u64>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Function to upgrade a first-release user to one bound to no principal until it is claimed, keeping
+// its plaintext password to be re-hashed on its next login
+fn migrate_user_v0(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let baseline = Decode!(bytes, BaselineUser).map_err(|e| Error::DecodeFailed {
+        msg: format!("user record could not be decoded as version 0: {}", e),
+    })?;
+    let user = UserV1 {
+        id: baseline.id,
+        principal: UNCLAIMED_PRINCIPAL,
+        name: baseline.name,
+        email: baseline.email,
+        password: Some(baseline.password),
+        password_hash: None,
+        event_ids: baseline.event_ids,
+        ticket_ids: baseline.ticket_ids,
+        created_at: baseline.created_at,
+        updated_at: baseline.updated_at,
+    };
+    Ok(Encode!(&user).expect("records are always encodable"))
+}
+
+// Function to upgrade a record whose new version only dropped fields or added optional ones; Candid
+// skips fields a record no longer declares and reads missing optional fields as None, so the bytes
+// are read as they are
+fn decode_unchanged(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    Ok(bytes.to_vec())
+}
+
+// Migration registries for events and users
+pub(crate) const EVENT_MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        migrate: migrate_event_v0,
+    },
+    Migration {
+        from: 1,
+        migrate: migrate_event_v1,
+    },
+    // The membership lists are rebuilt from the tickets by `rebuild_ticket_relations`
+    Migration {
+        from: 2,
+        migrate: decode_unchanged,
+    },
+    Migration {
+        from: 3,
+        migrate: migrate_event_v3,
+    },
+    Migration {
+        from: 4,
+        migrate: migrate_event_v4,
+    },
+];
+
+pub(crate) const USER_MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        migrate: migrate_user_v0,
+    },
+    Migration {
+        from: 1,
+        migrate: decode_unchanged,
+    },
+];
+
+// Function to tell apart the unversioned event shapes: version 2 events carry typed instants, version
+// 1 events an owner, and first-release events neither
+pub(crate) fn unversioned_event_version(bytes: &[u8]) -> u32 {
+    if Decode!(bytes, EventV3).is_ok() {
+        2
+    } else if Decode!(bytes, LegacyEvent).is_ok() {
+        1
+    } else {
+        0
+    }
+}
+
+// Function to tell apart the unversioned user shapes: first-release users are bound to no principal
+pub(crate) fn unversioned_user_version(bytes: &[u8]) -> u32 {
+    match Decode!(bytes, UserV1) {
+        Ok(_) => 1,
+        Err(_) => 0,
+    }
+}
+
+// Define a struct for version 1 of 'Ticket', stored before tickets had tiers
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LegacyTicket {
+    id: u64,
+    event_id: u64,
+    user_id: u64,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for version 3 of 'Ticket', stored before tickets had a status
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TicketV3 {
+    id: u64,
+    event_id: u64,
+    user_id: u64,
+    tier_id: Option<u64>,
+    price_paid_e8s: u64,
+    payment_block_index: Option<Nat>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Define a struct for version 5 of 'Ticket', stored before it kept its face value apart from the price
+// its last buyer paid
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TicketV5 {
+    id: u64,
+    event_id: u64,
+    user_id: u64,
+    tier_id: Option<u64>,
+    price_paid_e8s: u64,
+    payment_block_index: Option<Nat>,
+    payment_ledger_id: Option<Principal>,
+    paid_by: Option<Principal>,
+    status: TicketStatus,
+    seat_id: Option<u64>,
+    refunded_e8s: u64,
+    refund_block_index: Option<Nat>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Function to upgrade a ticket to one without a tier, issued for free
+fn migrate_ticket_v1(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let legacy = Decode!(bytes, LegacyTicket).map_err(|e| Error::DecodeFailed {
+        msg: format!("ticket record could not be decoded as version 1: {}", e),
+    })?;
+    let ticket = TicketV3 {
+        id: legacy.id,
+        event_id: legacy.event_id,
+        user_id: legacy.user_id,
+        tier_id: None,
+        price_paid_e8s: 0,
+        payment_block_index: None,
+        created_at: legacy.created_at,
+        updated_at: legacy.updated_at,
+    };
+    Ok(Encode!(&ticket).expect("records are always encodable"))
+}
+
+// Function to upgrade a ticket to an issued one; its payment source is unknown, so refunds fall back
+// to its tier's ledger and its holder
+fn migrate_ticket_v3(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let legacy = Decode!(bytes, TicketV3).map_err(|e| Error::DecodeFailed {
+        msg: format!("ticket record could not be decoded as version 3: {}", e),
+    })?;
+    let ticket = TicketV5 {
+        id: legacy.id,
+        event_id: legacy.event_id,
+        user_id: legacy.user_id,
+        tier_id: legacy.tier_id,
+        price_paid_e8s: legacy.price_paid_e8s,
+        payment_block_index: legacy.payment_block_index,
+        payment_ledger_id: None,
+        paid_by: None,
+        status: TicketStatus::Issued,
+        seat_id: None,
+        refunded_e8s: 0,
+        refund_block_index: None,
+        created_at: legacy.created_at,
+        updated_at: legacy.updated_at,
+    };
+    Ok(Encode!(&ticket).expect("records are always encodable"))
+}
+
+// Function to upgrade a ticket to one whose face value is the price paid for it; resales before this
+// version already replaced that price with the listing price, which then stands as the face value
+fn migrate_ticket_v5(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let legacy = Decode!(bytes, TicketV5).map_err(|e| Error::DecodeFailed {
+        msg: format!("ticket record could not be decoded as version 5: {}", e),
+    })?;
+    let ticket = Ticket {
+        id: legacy.id,
+        event_id: legacy.event_id,
+        user_id: legacy.user_id,
+        tier_id: legacy.tier_id,
+        price_paid_e8s: legacy.price_paid_e8s,
+        face_value_e8s: legacy.price_paid_e8s,
+        payment_block_index: legacy.payment_block_index,
+        payment_ledger_id: legacy.payment_ledger_id,
+        paid_by: legacy.paid_by,
+        status: legacy.status,
+        seat_id: legacy.seat_id,
+        refunded_e8s: legacy.refunded_e8s,
+        refund_block_index: legacy.refund_block_index,
+        created_at: legacy.created_at,
+        updated_at: legacy.updated_at,
+    };
+    Ok(Encode!(&ticket).expect("records are always encodable"))
+}
+
+// Migration registry for tickets
+pub(crate) const TICKET_MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        migrate: migrate_ticket_v1,
+    },
+    // Version 3 added the optional payment block index
+    Migration {
+        from: 2,
+        migrate: decode_unchanged,
+    },
+    Migration {
+        from: 3,
+        migrate: migrate_ticket_v3,
+    },
+    // Version 5 added the optional seat
+    Migration {
+        from: 4,
+        migrate: decode_unchanged,
+    },
+    Migration {
+        from: 5,
+        migrate: migrate_ticket_v5,
+    },
+];
+
+// Function to check whether a map still holds records older than the given schema version
+fn has_records_before<T: Record>(
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, Versioned<T>, Memory>>>,
+    version: u32,
+) -> bool {
+    storage.with(|records| {
+        records
+            .borrow()
+            .iter()
+            .any(|(_, record)| record.version() < version)
+    })
+}
+
+// Function to check whether an index is still empty while the records it covers exist
+fn is_unindexed<K: BoundedStorable + Ord + Clone, V: BoundedStorable, T: Record>(
+    index: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, Versioned<T>, Memory>>>,
+) -> bool {
+    index.with(|index| index.borrow().is_empty())
+        && storage.with(|records| !records.borrow().is_empty())
+}
+
+// Function to relate every stored ticket to its event and holder
+fn rebuild_ticket_relations() {
+    TICKET_STORAGE.with(|tickets| {
+        for (_, ticket) in tickets.borrow().iter() {
+            // Tickets stored before the relations existed predate seats, so none is refused
+            if let Ok(ticket) = ticket.decode() {
+                let _ = _link_ticket(&ticket);
+            }
+        }
+    });
+}
+
+// Function to index every stored user by email; where users share an email, the oldest keeps it
+fn rebuild_email_index() {
+    USER_STORAGE.with(|users| {
+        for (id, user) in users.borrow().iter() {
+            if let Ok(user) = user.decode() {
+                let key = _email_key(&user.email);
+                USER_EMAIL_INDEX.with(|index| {
+                    let mut index = index.borrow_mut();
+                    if !index.contains_key(&key) {
+                        index.insert(key, id);
+                    }
+                });
+            }
+        }
+    });
+}
+
+// Function to rewrite the records of a map that are older than the current schema version, up to
+// `MIGRATION_BATCH_SIZE` of them from the given id on, returning the id to resume from
+fn migrate_storage<T: Record>(
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, Versioned<T>, Memory>>>,
+    start: u64,
+) -> Option<u64> {
+    storage.with(|records| {
+        let mut records = records.borrow_mut();
+        let mut batch: Vec<(u64, Versioned<T>)> = records
+            .range(start..)
+            .take(MIGRATION_BATCH_SIZE + 1)
+            .collect();
+        let resume =
+            (batch.len() > MIGRATION_BATCH_SIZE).then(|| batch.remove(MIGRATION_BATCH_SIZE).0);
+        for (id, record) in batch {
+            if record.version() == T::VERSION {
+                continue;
+            }
+            // Records that cannot be migrated are kept as they are and report the error on read, and
+            // records that outgrew their slot are kept as they are and migrated on every read
+            if let Ok(migrated) = record
+                .decode()
+                .and_then(|decoded| Versioned::try_new(&decoded))
+            {
+                records.insert(id, migrated);
+            }
+        }
+        resume
+    })
+}
+
+// Define an enum for the maps the migration rewrites, in the order it rewrites them
+#[derive(Clone, Copy)]
+enum MigrationStage {
+    Events,
+    Users,
+    Tickets,
+}
+
+// Function to rewrite one batch of records, returning where the next batch starts, if any is left
+fn migrate_batch(stage: MigrationStage, start: u64) -> Option<(MigrationStage, u64)> {
+    let resume = match stage {
+        MigrationStage::Events => migrate_storage(&EVENT_STORAGE, start),
+        MigrationStage::Users => migrate_storage(&USER_STORAGE, start),
+        MigrationStage::Tickets => migrate_storage(&TICKET_STORAGE, start),
+    };
+    match (resume, stage) {
+        (Some(resume), _) => Some((stage, resume)),
+        (None, MigrationStage::Events) => Some((MigrationStage::Users, 0)),
+        (None, MigrationStage::Users) => Some((MigrationStage::Tickets, 0)),
+        (None, MigrationStage::Tickets) => None,
+    }
+}
+
+// Function to rebuild the indexes that older records relied on, before any record is rewritten
+fn rebuild_missing_indexes() {
+    // Membership moved out of events in version 3 and users in version 2; the relation maps are
+    // rebuilt from the tickets before those records lose their lists, or once if the user-event
+    // index was added after them
+    if has_records_before(&EVENT_STORAGE, 3)
+        || has_records_before(&USER_STORAGE, 2)
+        || is_unindexed(&USER_EVENT_TICKETS, &TICKET_STORAGE)
+    {
+        rebuild_ticket_relations();
+    }
+
+    // The email index postdates the users, so it is built once if still empty
+    if is_unindexed(&USER_EMAIL_INDEX, &USER_STORAGE) {
+        rebuild_email_index();
+    }
+}
+
+// Function to rewrite the batch of records that starts at the given position from a timer, and
+// schedule the next batch until every map is rewritten
+fn schedule_batch(stage: MigrationStage, start: u64) {
+    ic_cdk_timers::set_timer(Duration::ZERO, move || {
+        if let Some((stage, start)) = migrate_batch(stage, start) {
+            schedule_batch(stage, start);
+        }
+    });
+}
+
+// Function to migrate every stored record to the current schema versions; the records are
+// rewritten in batches of their own messages, and until then reads run the same migrations on
+// the fly. Timers do not survive upgrades, so an upgrade during the migration starts it over
+pub(crate) fn start_migration() {
+    rebuild_missing_indexes();
+    schedule_batch(MigrationStage::Events, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{_event_ticket_ids, _get_event, _get_ticket, _get_user, _get_user_id_by_email};
+
+    // Function to store bytes as a first-release canister would have, without an envelope
+    fn store_unversioned<T: Record>(
+        storage: &'static LocalKey<RefCell<StableBTreeMap<u64, Versioned<T>, Memory>>>,
+        id: u64,
+        bytes: Vec<u8>,
+    ) {
+        storage.with(|records| {
+            records
+                .borrow_mut()
+                .insert(id, Versioned::from_bytes(Cow::Owned(bytes)))
+        });
+    }
+
+    fn baseline_event() -> BaselineEvent {
+        BaselineEvent {
+            id: 1,
+            name: "Concert".to_string(),
+            description: "Open air".to_string(),
+            date: "2024-05-17".to_string(),
+            start_time: "20:30".to_string(),
+            location: "Park".to_string(),
+            attendee_ids: vec![2],
+            ticket_ids: vec![3],
+            created_at: 10,
+            updated_at: None,
+        }
+    }
+
+    fn baseline_user() -> BaselineUser {
+        BaselineUser {
+            id: 2,
+            name: "Ada".to_string(),
+            email: "Ada@Example.com".to_string(),
+            password: "correct horse".to_string(),
+            event_ids: vec![1],
+            ticket_ids: vec![3],
+            created_at: 11,
+            updated_at: Some(12),
+        }
+    }
+
+    #[test]
+    fn baseline_records_are_told_apart_from_later_unversioned_ones() {
+        let event = Encode!(&baseline_event()).unwrap();
+        let user = Encode!(&baseline_user()).unwrap();
+        assert_eq!(unversioned_event_version(&event), 0);
+        assert_eq!(unversioned_user_version(&user), 0);
+
+        let legacy = Decode!(&migrate_event_v0(&event).unwrap(), LegacyEvent).unwrap();
+        assert_eq!(unversioned_event_version(&Encode!(&legacy).unwrap()), 1);
+        let user = migrate_user_v0(&user).unwrap();
+        assert_eq!(unversioned_user_version(&user), 1);
+    }
+
+    // Function to run every migration batch in turn, as the timers would
+    fn migrate_all() {
+        rebuild_missing_indexes();
+        let mut next = Some((MigrationStage::Events, 0));
+        while let Some((stage, start)) = next {
+            next = migrate_batch(stage, start);
+        }
+    }
+
+    #[test]
+    fn migration_upgrades_baseline_records() {
+        let ticket = LegacyTicket {
+            id: 3,
+            event_id: 1,
+            user_id: 2,
+            created_at: 13,
+            updated_at: None,
+        };
+        store_unversioned(&EVENT_STORAGE, 1, Encode!(&baseline_event()).unwrap());
+        store_unversioned(&USER_STORAGE, 2, Encode!(&baseline_user()).unwrap());
+        store_unversioned(&TICKET_STORAGE, 3, Encode!(&ticket).unwrap());
+
+        migrate_all();
+
+        let event = _get_event(&1).unwrap().unwrap();
+        assert_eq!(event.owner, UNCLAIMED_PRINCIPAL);
+        assert_eq!(event.capacity, u32::MAX);
+        assert!(!event.exchangeable);
+        assert_eq!(event.starts_at, 1_715_977_800 * NANOS_PER_SECOND);
+        assert_eq!(event.ends_at, event.starts_at + LEGACY_EVENT_DURATION_NANOS);
+        assert_eq!(event.timezone, LEGACY_TIMEZONE);
+
+        let user = _get_user(&2).unwrap().unwrap();
+        assert_eq!(user.principal, UNCLAIMED_PRINCIPAL);
+        assert_eq!(user.password.as_deref(), Some("correct horse"));
+        assert!(user.password_hash.is_none());
+        assert_eq!(_get_user_id_by_email("ada@example.com"), Some(2));
+
+        let ticket = _get_ticket(&3).unwrap().unwrap();
+        assert_eq!(ticket.status, TicketStatus::Issued);
+        assert_eq!(ticket.tier_id, None);
+        assert_eq!(_event_ticket_ids(1), vec![3]);
+
+        // Every record is rewritten in the current version
+        assert_eq!(
+            EVENT_STORAGE.with(|events| events.borrow().get(&1).unwrap().version()),
+            Event::VERSION
+        );
+        assert_eq!(
+            USER_STORAGE.with(|users| users.borrow().get(&2).unwrap().version()),
+            User::VERSION
+        );
+        assert_eq!(
+            TICKET_STORAGE.with(|tickets| tickets.borrow().get(&3).unwrap().version()),
+            Ticket::VERSION
+        );
+    }
+
+    #[test]
+    fn legacy_event_ends_after_it_starts() {
+        let legacy = |date: &str| LegacyEvent {
+            id: 1,
+            owner: UNCLAIMED_PRINCIPAL,
+            name: "Concert".to_string(),
+            description: String::new(),
+            date: date.to_string(),
+            start_time: "20:30".to_string(),
+            location: "Park".to_string(),
+            capacity: 10,
+            exchangeable: false,
+            created_at: 10,
+            updated_at: None,
+        };
+
+        // The end is assumed from the start, whether the date parses or falls back
+        for date in ["2024-05-17", "next friday"] {
+            let event = EventV3::from(legacy(date));
+            assert!(event.ends_at > event.starts_at);
+            assert_eq!(event.ends_at - event.starts_at, LEGACY_EVENT_DURATION_NANOS);
+        }
+    }
+
+    #[test]
+    fn migration_rewrites_a_bounded_batch_per_message() {
+        let ticket = |id| LegacyTicket {
+            id,
+            event_id: 1,
+            user_id: 2,
+            created_at: 13,
+            updated_at: None,
+        };
+        let last = MIGRATION_BATCH_SIZE as u64 + 1;
+        for id in 1..=last {
+            store_unversioned(&TICKET_STORAGE, id, Encode!(&ticket(id)).unwrap());
+        }
+        let version =
+            |id| TICKET_STORAGE.with(|tickets| tickets.borrow().get(&id).unwrap().version());
+
+        // The first batch stops short of the last ticket, and the next one picks it up
+        assert_eq!(migrate_storage(&TICKET_STORAGE, 0), Some(last));
+        assert_eq!(version(last - 1), Ticket::VERSION);
+        assert_ne!(version(last), Ticket::VERSION);
+        assert_eq!(migrate_storage(&TICKET_STORAGE, last), None);
+        assert_eq!(version(last), Ticket::VERSION);
+    }
+}
@@ -0,0 +1,519 @@
+use crate::{
+    _authenticated_caller, _ensure_ticket_owner, _get_event, _get_ticket, _get_user, ledger,
+    lifecycle, resale, reservations, roles, tiers, waitlist, Error, Event, Memory, Record, Ticket,
+    TicketStatus, Versioned, EVENT_STORAGE, MEMORY_MANAGER, TICKET_STORAGE,
+};
+use candid::{Nat, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// Largest number of tickets a single `cancel_event` or cascading delete call refunds; the caller
+// calls again for the rest
+const REFUND_BATCH_SIZE: usize = 50;
+
+// Define an enum for the refund an event grants attendees who cancel their own ticket
+#[derive(candid::CandidType, Clone, Copy, Default, Serialize, Deserialize)]
+pub(crate) enum RefundPolicy {
+    // The whole price is refunded until the event starts
+    Full,
+    // `percent` of the price is refunded until `deadline`, in nanoseconds since epoch
+    Partial {
+        percent: u8,
+        deadline: u64,
+    },
+    // Cancelled tickets are not refunded
+    #[default]
+    NoRefund,
+}
+
+// Define a struct reporting the progress of an event cancellation, or of the refunds of a cascading
+// delete
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct CancellationReport {
+    refunded_ticket_ids: Vec<u64>,
+    // Tickets whose refund failed in this batch; they are retried by the next call
+    failed_ticket_ids: Vec<u64>,
+    // Tickets still waiting for a refund, including the failed ones
+    remaining: u64,
+}
+
+impl CancellationReport {
+    // Function to check whether every ticket was refunded, so none is left for a next call
+    pub(crate) fn is_settled(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+// Define a struct for a refund waiting on the ledger
+struct PendingRefund {
+    event_id: u64,
+    user_id: u64,
+}
+
+// Define a struct for the ledger transfer of a refund, kept until the ledger answers it so a retry
+// sends the very same transfer
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RefundAttempt {
+    ledger_id: Principal,
+    to: Principal,
+    // Amount sent, after any fee the buyer pays
+    amount_e8s: u64,
+    fee_e8s: u64,
+    // Sent to the ledger on every attempt, so a retry of a transfer that went through is
+    // recognised as a duplicate
+    created_at: u64,
+}
+
+impl Record for RefundAttempt {
+    const KIND: &'static str = "refund attempt";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 256;
+}
+
+thread_local! {
+    // Refund transfers whose outcome is unknown, by ticket ID, such as those whose reply was lost
+    static REFUND_ATTEMPTS: RefCell<StableBTreeMap<u64, Versioned<RefundAttempt>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+
+    // Refunds waiting on the ledger, by ticket ID; the canister is stopped before upgrades, so none
+    // survive one
+    static PENDING_REFUNDS: RefCell<BTreeMap<u64, PendingRefund>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+// Define a struct marking a ticket as being refunded until the refund completes or fails, releasing
+// it when dropped
+struct RefundHold {
+    ticket_id: u64,
+}
+
+impl RefundHold {
+    fn new(ticket: &Ticket) -> Result<Self, Error> {
+        PENDING_REFUNDS.with(|refunds| {
+            let mut refunds = refunds.borrow_mut();
+            if refunds.contains_key(&ticket.id) {
+                return Err(Error::Conflict {
+                    msg: format!("ticket id:{} is already being refunded", ticket.id),
+                });
+            }
+            refunds.insert(
+                ticket.id,
+                PendingRefund {
+                    event_id: ticket.event_id,
+                    user_id: ticket.user_id,
+                },
+            );
+            Ok(RefundHold {
+                ticket_id: ticket.id,
+            })
+        })
+    }
+}
+
+impl Drop for RefundHold {
+    fn drop(&mut self) {
+        PENDING_REFUNDS.with(|refunds| refunds.borrow_mut().remove(&self.ticket_id));
+    }
+}
+
+// Function to count the refunds waiting on the ledger for an event
+pub(crate) fn pending_for_event(event_id: u64) -> usize {
+    PENDING_REFUNDS.with(|refunds| {
+        refunds
+            .borrow()
+            .values()
+            .filter(|refund| refund.event_id == event_id)
+            .count()
+    })
+}
+
+// Function to count the refunds waiting on the ledger for a user
+pub(crate) fn pending_for_user(user_id: u64) -> usize {
+    PENDING_REFUNDS.with(|refunds| {
+        refunds
+            .borrow()
+            .values()
+            .filter(|refund| refund.user_id == user_id)
+            .count()
+    })
+}
+
+// Function to check whether a ticket is being refunded
+pub(crate) fn is_pending(ticket_id: u64) -> bool {
+    PENDING_REFUNDS.with(|refunds| refunds.borrow().contains_key(&ticket_id))
+}
+
+// Function to check that a refund policy is well formed
+pub(crate) fn validate_policy(policy: &RefundPolicy) -> Result<(), Error> {
+    if let RefundPolicy::Partial { percent, .. } = policy {
+        if *percent > 100 {
+            return Err(Error::InvalidInput {
+                msg: format!("a partial refund of {}% is more than the price", percent),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Function to get the amount an attendee cancelling a ticket at the given instant gets back
+fn refundable_e8s(event: &Event, price_e8s: u64, now: u64) -> u64 {
+    match event.refund_policy {
+        RefundPolicy::Full if now < event.starts_at => price_e8s,
+        RefundPolicy::Partial { percent, deadline } if now < deadline => {
+            // Computed in u128 so large prices cannot overflow
+            (u128::from(price_e8s) * u128::from(percent) / 100) as u64
+        }
+        _ => 0,
+    }
+}
+
+// Function to send a ticket's refund back to its buyer and mark the ticket refunded. When
+// `buyer_pays_fee` is set, the ledger fee is taken out of the refund; otherwise the canister
+// pays it on top
+async fn refund(
+    ticket: Ticket,
+    amount_e8s: u64,
+    buyer_pays_fee: bool,
+    actor: Principal,
+) -> Result<Ticket, Error> {
+    // Reload the ticket, which another call may have changed since the caller read it, and mark it
+    // as being refunded
+    let ticket = _get_ticket(&ticket.id)?.ok_or(Error::NotFound {
+        msg: format!("ticket id:{} does not exist", ticket.id),
+    })?;
+    lifecycle::ensure_transition(&ticket, TicketStatus::Refunded)?;
+    let hold = RefundHold::new(&ticket)?;
+
+    // Tickets issued without payment have nothing to send back
+    let amount_e8s = match ticket.payment_block_index {
+        Some(_) => amount_e8s.min(ticket.price_paid_e8s),
+        None => 0,
+    };
+
+    // A transfer left unanswered by an earlier attempt is sent again as it was; otherwise a new one is
+    // recorded before it is sent
+    let mut attempt = REFUND_ATTEMPTS
+        .with(|attempts| attempts.borrow().get(&ticket.id))
+        .map(|attempt| attempt.decode())
+        .transpose()?;
+    if attempt.is_none() && amount_e8s > 0 {
+        let (ledger_id, to) = payment_source(&ticket)?;
+        let fee_e8s = ledger::fee(ledger_id).await?;
+        let send = if buyer_pays_fee {
+            amount_e8s.saturating_sub(fee_e8s)
+        } else {
+            amount_e8s
+        };
+        if send > 0 {
+            let new_attempt = RefundAttempt {
+                ledger_id,
+                to,
+                amount_e8s: send,
+                fee_e8s,
+                created_at: time(),
+            };
+            REFUND_ATTEMPTS.with(|attempts| {
+                attempts
+                    .borrow_mut()
+                    .insert(ticket.id, Versioned::new(&new_attempt))
+            });
+            attempt = Some(new_attempt);
+        }
+    }
+
+    let mut refunded_e8s = 0;
+    let mut refund_block_index: Option<Nat> = None;
+    if let Some(attempt) = attempt {
+        let memo = ticket.id.to_be_bytes().to_vec();
+        let sent = ledger::transfer(
+            attempt.ledger_id,
+            attempt.to,
+            attempt.amount_e8s,
+            attempt.fee_e8s,
+            memo,
+            attempt.created_at,
+        )
+        .await;
+        match sent {
+            Ok(block_index) => {
+                refund_block_index = Some(block_index);
+                refunded_e8s = attempt.amount_e8s;
+            }
+            // The call may have gone through without its reply arriving, so the attempt is kept
+            Err(error @ Error::LedgerUnavailable { .. }) => return Err(error),
+            // The ledger refused the transfer, so the next attempt starts afresh
+            Err(error) => {
+                REFUND_ATTEMPTS.with(|attempts| attempts.borrow_mut().remove(&ticket.id));
+                return Err(error);
+            }
+        }
+    }
+
+    // Reload the ticket, which may have moved to another holder while waiting on the ledger; the
+    // hold kept every other transition out, so it is released just before this one
+    let mut ticket = _get_ticket(&ticket.id)?.unwrap_or(ticket);
+    drop(hold);
+    lifecycle::transition(&mut ticket, TicketStatus::Refunded, actor)?;
+    ticket.refunded_e8s = refunded_e8s;
+    ticket.refund_block_index = refund_block_index;
+    TICKET_STORAGE.with(|tickets| {
+        tickets
+            .borrow_mut()
+            .insert(ticket.id, Versioned::new(&ticket))
+    });
+    REFUND_ATTEMPTS.with(|attempts| attempts.borrow_mut().remove(&ticket.id));
+
+    // Offer the freed seat to the next user on the waitlist
+    waitlist::promote(ticket.event_id);
+    Ok(ticket)
+}
+
+// Function to find the ledger a ticket was paid on and the principal that paid for it; tickets bought
+// before either was recorded fall back to their tier's ledger and their holder
+fn payment_source(ticket: &Ticket) -> Result<(Principal, Principal), Error> {
+    let ledger_id = match ticket.payment_ledger_id {
+        Some(ledger_id) => Some(ledger_id),
+        None => match ticket.tier_id {
+            Some(tier_id) => {
+                tiers::get_tier(ticket.event_id, tier_id)?.and_then(|tier| tier.ledger_id)
+            }
+            None => None,
+        },
+    };
+    let buyer = match ticket.paid_by {
+        Some(buyer) => Some(buyer),
+        None => _get_user(&ticket.user_id)?.map(|user| user.principal),
+    };
+    match (ledger_id, buyer) {
+        (Some(ledger_id), Some(buyer)) => Ok((ledger_id, buyer)),
+        _ => Err(Error::PaymentFailed {
+            msg: format!("ticket id:{} has no known payment to refund", ticket.id),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+async fn cancel_ticket(id: u64) -> Result<Ticket, Error> {
+    let caller = _authenticated_caller()?;
+
+    // Retrieve the ticket and its event, or return a NotFound error if either is missing
+    let ticket = _get_ticket(&id)?.ok_or(Error::NotFound {
+        msg: format!("ticket id:{} does not exist", id),
+    })?;
+    let event = _get_event(&ticket.event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", ticket.event_id),
+    })?;
+
+    // Only the holder of the ticket or a manager of its event may cancel it
+    _ensure_ticket_owner(&ticket, &caller)?;
+    lifecycle::ensure_transition(&ticket, TicketStatus::Refunded)?;
+
+    // Tickets of cancelled events, and tickets cancelled by a manager, are refunded in full
+    if event.cancelled_at.is_some() || roles::is_event_manager(&event, &caller) {
+        let amount_e8s = ticket.price_paid_e8s;
+        return refund(ticket, amount_e8s, false, caller).await;
+    }
+
+    // Holders get what the event's policy grants, minus the fee, and only until the event starts
+    if time() >= event.starts_at {
+        return Err(Error::Conflict {
+            msg: format!("event id:{} has already started", event.id),
+        });
+    }
+    release(ticket, &event, caller).await
+}
+
+// Function to refund a ticket its holder gives up: in full if its event was cancelled, otherwise as
+// much as the event's policy grants at this instant, minus the fee. A ticket owed nothing is only
+// cancelled
+async fn release(ticket: Ticket, event: &Event, actor: Principal) -> Result<Ticket, Error> {
+    if event.cancelled_at.is_some() {
+        let amount_e8s = ticket.price_paid_e8s;
+        return refund(ticket, amount_e8s, false, actor).await;
+    }
+    let amount_e8s = refundable_e8s(event, ticket.price_paid_e8s, time());
+    if amount_e8s > 0 {
+        return refund(ticket, amount_e8s, true, actor).await;
+    }
+
+    let mut ticket = ticket;
+    lifecycle::transition(&mut ticket, TicketStatus::Cancelled, actor)?;
+    TICKET_STORAGE.with(|tickets| {
+        tickets
+            .borrow_mut()
+            .insert(ticket.id, Versioned::new(&ticket))
+    });
+    waitlist::promote(ticket.event_id);
+    Ok(ticket)
+}
+
+// Function to release the next batch of the given tickets of a user being deleted, the way the
+// holder cancelling each of them would
+pub(crate) async fn release_batch(
+    tickets: Vec<Ticket>,
+    actor: Principal,
+) -> Result<CancellationReport, Error> {
+    let mut report = CancellationReport {
+        remaining: tickets.len() as u64,
+        ..Default::default()
+    };
+    for ticket in tickets.into_iter().take(REFUND_BATCH_SIZE) {
+        let ticket_id = ticket.id;
+        let event = _get_event(&ticket.event_id)?.ok_or(Error::NotFound {
+            msg: format!("event id:{} does not exist", ticket.event_id),
+        })?;
+        match release(ticket, &event, actor).await {
+            Ok(_) => {
+                report.refunded_ticket_ids.push(ticket_id);
+                report.remaining -= 1;
+            }
+            Err(_) => report.failed_ticket_ids.push(ticket_id),
+        }
+    }
+    Ok(report)
+}
+
+#[ic_cdk::update]
+async fn cancel_event(id: u64) -> Result<CancellationReport, Error> {
+    let caller = _authenticated_caller()?;
+
+    // Retrieve the event with the given ID, or return a NotFound error if not found
+    let event = _get_event(&id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", id),
+    })?;
+
+    // Only the organizer who owns the event, or an admin, may cancel it
+    roles::ensure_event_manager(&event, &caller)?;
+
+    // Refuse the cancellation while tickets of the event are being paid for, so no payment completes
+    // after the refunds were sent
+    if crate::_pending_purchases(|purchase| purchase.event_id == id) > 0
+        || waitlist::pending_for_event(id) > 0
+        || reservations::pending_for_event(id) > 0
+        || resale::pending_for_event(id) > 0
+    {
+        return Err(Error::Conflict {
+            msg: format!("event id:{} has purchases in progress", id),
+        });
+    }
+
+    cancel_and_refund(event, caller).await
+}
+
+// Function to cancel an event, closing its sales, waitlist and reservations on the first call, and
+// refund the next batch of its tickets in full, the canister paying the fees
+pub(crate) async fn cancel_and_refund(
+    mut event: Event,
+    caller: Principal,
+) -> Result<CancellationReport, Error> {
+    let id = event.id;
+
+    // Stop sales on the first call; later calls only resume the refunds
+    if event.cancelled_at.is_none() {
+        event.cancelled_at = Some(time());
+        event.updated_at = event.cancelled_at;
+        EVENT_STORAGE.with(|events| events.borrow_mut().insert(id, Versioned::new(&event)));
+    }
+
+    // Close the waitlist and release the reservations, cancelling the tickets they hold
+    waitlist::close(id, caller);
+    reservations::close(id, caller);
+
+    // Refund the next batch of tickets
+    let mut report = CancellationReport::default();
+    let batch: Vec<Ticket> = refundable_tickets(id)?
+        .into_iter()
+        .take(REFUND_BATCH_SIZE)
+        .collect();
+    for ticket in batch {
+        let ticket_id = ticket.id;
+        let amount_e8s = ticket.price_paid_e8s;
+        match refund(ticket, amount_e8s, false, caller).await {
+            Ok(_) => report.refunded_ticket_ids.push(ticket_id),
+            Err(_) => report.failed_ticket_ids.push(ticket_id),
+        }
+    }
+
+    // Report how many tickets the next call still has to refund
+    report.remaining = refundable_tickets(id)?.len() as u64;
+    Ok(report)
+}
+
+// Function to list the tickets of an event still waiting for a refund, skipping those in flight
+fn refundable_tickets(event_id: u64) -> Result<Vec<Ticket>, Error> {
+    let mut tickets = vec![];
+    for ticket_id in crate::_event_ticket_ids(event_id) {
+        if is_pending(ticket_id) {
+            continue;
+        }
+        if let Some(ticket) = _get_ticket(&ticket_id)? {
+            if ticket.status.is_valid() {
+                tickets.push(ticket);
+            }
+        }
+    }
+    Ok(tickets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event_with(refund_policy: RefundPolicy) -> Event {
+        Event {
+            id: 1,
+            owner: Principal::anonymous(),
+            name: "Concert".to_string(),
+            description: String::new(),
+            starts_at: 1_000,
+            ends_at: 2_000,
+            timezone: "UTC".to_string(),
+            location: String::new(),
+            capacity: 100,
+            exchangeable: false,
+            refund_policy,
+            transfer_rules: Default::default(),
+            cancelled_at: None,
+            created_at: 0,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn full_refund_lasts_until_the_event_starts() {
+        let event = event_with(RefundPolicy::Full);
+
+        assert_eq!(refundable_e8s(&event, 500, 999), 500);
+        assert_eq!(refundable_e8s(&event, 500, 1_000), 0);
+    }
+
+    #[test]
+    fn partial_refund_is_a_share_of_the_price_until_its_deadline() {
+        let event = event_with(RefundPolicy::Partial {
+            percent: 50,
+            deadline: 800,
+        });
+
+        assert_eq!(refundable_e8s(&event, 999, 799), 499);
+        assert_eq!(refundable_e8s(&event, 999, 800), 0);
+
+        // Large prices do not overflow
+        let event = event_with(RefundPolicy::Partial {
+            percent: 100,
+            deadline: 800,
+        });
+        assert_eq!(refundable_e8s(&event, u64::MAX, 0), u64::MAX);
+    }
+
+    #[test]
+    fn no_refund_policy_refunds_nothing() {
+        let event = event_with(RefundPolicy::NoRefund);
+
+        assert_eq!(refundable_e8s(&event, 500, 0), 0);
+    }
+}
@@ -0,0 +1,683 @@
+use crate::{
+    _authenticated_caller, _ensure_event_on_sale, _ensure_ticket_owner, _get_event, _get_ticket,
+    _get_user, _get_user_id_by_principal, ledger, migrations, roles, tiers, transfers, Error,
+    Event, Memory, Record, Ticket, Versioned, ID_COUNTER, MEMORY_MANAGER,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+// Interval between the sweeps that drop expired listings and retry failed payouts
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Define a struct for the terms an event sets on the resale of its tickets
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct ResaleTerms {
+    // Highest resale price, as a percentage of the price the ticket was first sold at
+    price_cap_percent: u16,
+    // Share of each resale price paid to the organizer of the event, in percent
+    royalty_percent: u8,
+}
+
+impl Record for ResaleTerms {
+    const KIND: &'static str = "resale terms";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 64;
+}
+
+// Define a struct for a ticket offered for resale by its holder
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ResaleListing {
+    ticket_id: u64,
+    event_id: u64,
+    seller_user_id: u64,
+    seller: Principal,
+    // Price asked, in e8s of the ledger the ticket was paid on
+    price_e8s: u64,
+    ledger_id: Principal,
+    // Royalty agreed when the ticket was listed, in percent
+    royalty_percent: u8,
+    listed_at: u64,
+    // The listing lapses when transfers of the event close
+    expires_at: u64,
+}
+
+impl Record for ResaleListing {
+    const KIND: &'static str = "resale listing";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 256;
+}
+
+// Define a struct for an amount the canister owes after a resale, until the ledger sends it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Payout {
+    id: u64,
+    ticket_id: u64,
+    ledger_id: Principal,
+    to: Principal,
+    // Amount owed; the ledger fee is taken out of it
+    amount_e8s: u64,
+    // Sent to the ledger on every attempt, so a retry of a transfer that went through is
+    // recognised as a duplicate; renewed when the ledger refuses the transfer
+    created_at: u64,
+}
+
+impl Record for Payout {
+    const KIND: &'static str = "payout";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 256;
+}
+
+// Define a struct for a resale waiting on the ledger
+struct PendingSale {
+    event_id: u64,
+    seller_user_id: u64,
+    buyer_user_id: u64,
+}
+
+thread_local! {
+    // Resale terms of the events that allow resale, by event ID
+    static RESALE_TERMS: RefCell<StableBTreeMap<u64, Versioned<ResaleTerms>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+
+    // Listings keyed by event, so the listings of one event are contiguous
+    static RESALE_LISTINGS: RefCell<StableBTreeMap<(u64, u64), Versioned<ResaleListing>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    // Payouts owed to sellers and organizers, by payout ID
+    static PAYOUTS: RefCell<StableBTreeMap<u64, Versioned<Payout>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    // Resales waiting on the ledger, by ticket ID; the canister is stopped before upgrades, so none
+    // survive one
+    static PENDING_SALES: RefCell<BTreeMap<u64, PendingSale>> =
+        const { RefCell::new(BTreeMap::new()) };
+
+    // Payouts being sent, so concurrent sweeps never send one twice
+    static PAYOUTS_IN_FLIGHT: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+// Define a struct marking a ticket as being resold until the sale completes or fails, releasing it
+// when dropped
+struct SaleHold {
+    ticket_id: u64,
+}
+
+impl SaleHold {
+    fn new(ticket: &Ticket, buyer_user_id: u64) -> Result<Self, Error> {
+        PENDING_SALES.with(|sales| {
+            let mut sales = sales.borrow_mut();
+            if sales.contains_key(&ticket.id) {
+                return Err(Error::Conflict {
+                    msg: format!("ticket id:{} is already being resold", ticket.id),
+                });
+            }
+            sales.insert(
+                ticket.id,
+                PendingSale {
+                    event_id: ticket.event_id,
+                    seller_user_id: ticket.user_id,
+                    buyer_user_id,
+                },
+            );
+            Ok(SaleHold {
+                ticket_id: ticket.id,
+            })
+        })
+    }
+}
+
+impl Drop for SaleHold {
+    fn drop(&mut self) {
+        PENDING_SALES.with(|sales| sales.borrow_mut().remove(&self.ticket_id));
+    }
+}
+
+// Function to count the resales waiting on the ledger for an event
+pub(crate) fn pending_for_event(event_id: u64) -> usize {
+    PENDING_SALES.with(|sales| {
+        sales
+            .borrow()
+            .values()
+            .filter(|sale| sale.event_id == event_id)
+            .count()
+    })
+}
+
+// Function to count the resales waiting on the ledger that a user sells or buys in
+pub(crate) fn pending_for_user(user_id: u64) -> usize {
+    PENDING_SALES.with(|sales| {
+        sales
+            .borrow()
+            .values()
+            .filter(|sale| sale.seller_user_id == user_id || sale.buyer_user_id == user_id)
+            .count()
+    })
+}
+
+// Function to check whether a ticket is being resold
+pub(crate) fn is_pending(ticket_id: u64) -> bool {
+    PENDING_SALES.with(|sales| sales.borrow().contains_key(&ticket_id))
+}
+
+// Function to withdraw the listing of a ticket, if it has one
+pub(crate) fn withdraw(ticket: &Ticket) {
+    RESALE_LISTINGS.with(|listings| listings.borrow_mut().remove(&(ticket.event_id, ticket.id)));
+}
+
+// Function to remove the resale terms and listings of a deleted event
+pub(crate) fn remove_event(event_id: u64) {
+    RESALE_TERMS.with(|terms| terms.borrow_mut().remove(&event_id));
+    RESALE_LISTINGS.with(|listings| {
+        let mut listings = listings.borrow_mut();
+        let keys: Vec<(u64, u64)> = listings
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            listings.remove(&key);
+        }
+    });
+}
+
+// Function to start the periodic sweep of listings and payouts; timers do not survive upgrades, so
+// it runs after install and after every upgrade
+pub(crate) fn schedule_upkeep() {
+    ic_cdk_timers::set_timer_interval(UPKEEP_INTERVAL, || {
+        expire_listings(time());
+        let payout_ids: Vec<u64> =
+            PAYOUTS.with(|payouts| payouts.borrow().iter().map(|(id, _)| id).collect());
+        ic_cdk::spawn(pay_out(payout_ids));
+    });
+}
+
+// Function to drop the listings that lapsed at the given instant, and any that cannot be read
+fn expire_listings(now: u64) {
+    RESALE_LISTINGS.with(|listings| {
+        let mut listings = listings.borrow_mut();
+        let expired: Vec<(u64, u64)> = listings
+            .iter()
+            .filter(|(_, listing)| listing.decode().map_or(true, |l| l.expires_at <= now))
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            listings.remove(&key);
+        }
+    });
+}
+
+// Function to get the resale terms of an event, or None if it does not allow resale
+fn terms(event_id: u64) -> Result<Option<ResaleTerms>, Error> {
+    RESALE_TERMS.with(|terms| {
+        terms
+            .borrow()
+            .get(&event_id)
+            .map(|terms| terms.decode())
+            .transpose()
+    })
+}
+
+// Function to get the highest price a ticket may be resold at under the given terms
+fn price_cap_e8s(terms: &ResaleTerms, ticket: &Ticket) -> u64 {
+    // Computed in u128 so large prices cannot overflow
+    let cap = u128::from(ticket.face_value_e8s) * u128::from(terms.price_cap_percent) / 100;
+    cap.try_into().unwrap_or(u64::MAX)
+}
+
+// Function to get the share of a resale price paid to the organizer, rounded down; royalties are at
+// most 100%, so the seller's share never underflows
+fn royalty_e8s(price_e8s: u64, royalty_percent: u8) -> u64 {
+    // Computed in u128 so large prices cannot overflow
+    (u128::from(price_e8s) * u128::from(royalty_percent) / 100) as u64
+}
+
+// Function to check that the organizer of an event can be paid the given royalty; events migrated
+// from before owners were recorded have no organizer to pay it to
+fn ensure_royalty_payable(event: &Event, royalty_percent: u8) -> Result<(), Error> {
+    if royalty_percent > 0 && event.owner == migrations::UNCLAIMED_PRINCIPAL {
+        return Err(Error::Conflict {
+            msg: format!(
+                "event id:{} has no organizer to pay a resale royalty to",
+                event.id
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to find the ledger a ticket is resold on: the one it was paid on, or its tier's
+fn resale_ledger(ticket: &Ticket) -> Result<Principal, Error> {
+    let ledger_id = match ticket.payment_ledger_id {
+        Some(ledger_id) => Some(ledger_id),
+        None => match ticket.tier_id {
+            Some(tier_id) => {
+                tiers::get_tier(ticket.event_id, tier_id)?.and_then(|tier| tier.ledger_id)
+            }
+            None => None,
+        },
+    };
+    match ledger_id {
+        Some(ledger_id) if ledger::is_configured(&ledger_id) => Ok(ledger_id),
+        _ => Err(Error::PaymentFailed {
+            msg: format!(
+                "ticket id:{} is not priced in a configured ledger",
+                ticket.id
+            ),
+        }),
+    }
+}
+
+// Function to check that a listing price is positive and within the event's cap
+fn ensure_price_allowed(
+    event: &Event,
+    ticket: &Ticket,
+    price_e8s: u64,
+) -> Result<ResaleTerms, Error> {
+    let terms = terms(event.id)?.ok_or(Error::Conflict {
+        msg: format!("tickets for event id:{} cannot be resold", event.id),
+    })?;
+    let cap_e8s = price_cap_e8s(&terms, ticket);
+    if price_e8s == 0 || price_e8s > cap_e8s {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "ticket id:{} can be resold for 1 to {} e8s, not {}",
+                ticket.id, cap_e8s, price_e8s
+            ),
+        });
+    }
+    Ok(terms)
+}
+
+// Function to record an amount owed after a resale, returning its ID, or None for nothing owed
+fn owe(ticket_id: u64, ledger_id: Principal, to: Principal, amount_e8s: u64) -> Option<u64> {
+    if amount_e8s == 0 {
+        return None;
+    }
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let payout = Payout {
+        id,
+        ticket_id,
+        ledger_id,
+        to,
+        amount_e8s,
+        created_at: time(),
+    };
+    PAYOUTS.with(|payouts| payouts.borrow_mut().insert(id, Versioned::new(&payout)));
+    Some(id)
+}
+
+// Function to send the given payouts, dropping each once the ledger has it; failed payouts stay
+// owed and are retried by the next sweep
+async fn pay_out(payout_ids: Vec<u64>) {
+    for id in payout_ids {
+        // A payout that cannot be read stays owed, for an admin to review
+        let Some(Ok(payout)) =
+            PAYOUTS.with(|payouts| payouts.borrow().get(&id).map(|payout| payout.decode()))
+        else {
+            continue;
+        };
+        if !PAYOUTS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(id)) {
+            continue;
+        }
+        let sent = match ledger::fee(payout.ledger_id).await {
+            // The fee is taken out of the payout; a payout that does not cover it is dropped
+            Ok(fee) if payout.amount_e8s <= fee => Ok(()),
+            Ok(fee) => ledger::transfer(
+                payout.ledger_id,
+                payout.to,
+                payout.amount_e8s - fee,
+                fee,
+                payout.id.to_be_bytes().to_vec(),
+                payout.created_at,
+            )
+            .await
+            .map(|_| ()),
+            Err(error) => Err(error),
+        };
+        match sent {
+            Ok(()) => {
+                PAYOUTS.with(|payouts| payouts.borrow_mut().remove(&id));
+            }
+            // The call may have gone through without its reply arriving, so the next attempt is sent
+            // as it was
+            Err(Error::LedgerUnavailable { .. }) => {}
+            // The ledger refused the transfer, so the next attempt is a new one; a payout left
+            // unanswered past the ledger's deduplication window is refused as too old, and would
+            // otherwise never be sent
+            Err(_) => {
+                let renewed = Payout {
+                    created_at: time(),
+                    ..payout
+                };
+                PAYOUTS.with(|payouts| payouts.borrow_mut().insert(id, Versioned::new(&renewed)));
+            }
+        }
+        PAYOUTS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&id));
+    }
+}
+
+#[ic_cdk::update]
+fn set_resale_terms(event_id: u64, terms: Option<ResaleTerms>) -> Result<String, Error> {
+    let caller = _authenticated_caller()?;
+
+    // Retrieve the event, or return a NotFound error if not found
+    let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+
+    // Only the organizer who owns the event, or an admin, may set its resale terms
+    roles::ensure_event_manager(&event, &caller)?;
+
+    match terms {
+        Some(terms) => {
+            if terms.royalty_percent > 100 {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "a royalty of {}% is more than the price",
+                        terms.royalty_percent
+                    ),
+                });
+            }
+            ensure_royalty_payable(&event, terms.royalty_percent)?;
+            RESALE_TERMS
+                .with(|stored| stored.borrow_mut().insert(event_id, Versioned::new(&terms)));
+            Ok(format!("resale terms of event id:{} set", event_id))
+        }
+        None => {
+            // Closing resale withdraws every listing of the event
+            remove_event(event_id);
+            Ok(format!("resale of event id:{} closed", event_id))
+        }
+    }
+}
+
+#[ic_cdk::query]
+fn get_resale_terms(event_id: u64) -> Result<Option<ResaleTerms>, Error> {
+    // Check that the event exists, or return a NotFound error if not found
+    if _get_event(&event_id)?.is_none() {
+        return Err(Error::NotFound {
+            msg: format!("event id:{} does not exist", event_id),
+        });
+    }
+    terms(event_id)
+}
+
+#[ic_cdk::update]
+fn list_for_resale(ticket_id: u64, price_e8s: u64) -> Result<ResaleListing, Error> {
+    let caller = _authenticated_caller()?;
+
+    // Retrieve the ticket and its event, or return a NotFound error if either is missing
+    let ticket = _get_ticket(&ticket_id)?.ok_or(Error::NotFound {
+        msg: format!("ticket id:{} does not exist", ticket_id),
+    })?;
+    let event = _get_event(&ticket.event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", ticket.event_id),
+    })?;
+
+    // Only the holder of the ticket may list it, within the event's resale and transfer rules
+    let is_holder = _get_user(&ticket.user_id)?.is_some_and(|user| user.principal == caller);
+    if !is_holder {
+        return Err(Error::Unauthorized {
+            msg: format!("caller does not hold ticket id:{}", ticket_id),
+        });
+    }
+    let now = time();
+    transfers::ensure_transferable(&event, &ticket, now)?;
+    let terms = ensure_price_allowed(&event, &ticket, price_e8s)?;
+    ensure_royalty_payable(&event, terms.royalty_percent)?;
+
+    // Listing a ticket again replaces its previous listing
+    let listing = ResaleListing {
+        ticket_id,
+        event_id: event.id,
+        seller_user_id: ticket.user_id,
+        seller: caller,
+        price_e8s,
+        ledger_id: resale_ledger(&ticket)?,
+        royalty_percent: terms.royalty_percent,
+        listed_at: now,
+        expires_at: transfers::closes_at(&event),
+    };
+    RESALE_LISTINGS.with(|listings| {
+        listings
+            .borrow_mut()
+            .insert((event.id, ticket_id), Versioned::new(&listing))
+    });
+    Ok(listing)
+}
+
+#[ic_cdk::update]
+fn withdraw_listing(ticket_id: u64) -> Result<String, Error> {
+    let caller = _authenticated_caller()?;
+
+    // Retrieve the ticket with the given ID, or return a NotFound error if not found
+    let ticket = _get_ticket(&ticket_id)?.ok_or(Error::NotFound {
+        msg: format!("ticket id:{} does not exist", ticket_id),
+    })?;
+
+    // Only the holder of the ticket or a manager of its event may withdraw its listing, and not
+    // while it is being sold
+    _ensure_ticket_owner(&ticket, &caller)?;
+    if is_pending(ticket_id) {
+        return Err(Error::Conflict {
+            msg: format!("ticket id:{} is being resold", ticket_id),
+        });
+    }
+    withdraw(&ticket);
+    Ok(format!("listing of ticket id:{} withdrawn", ticket_id))
+}
+
+#[ic_cdk::query]
+fn list_resale_listings(event_id: u64) -> Result<Vec<ResaleListing>, Error> {
+    // Check that the event exists, or return a NotFound error if not found
+    if _get_event(&event_id)?.is_none() {
+        return Err(Error::NotFound {
+            msg: format!("event id:{} does not exist", event_id),
+        });
+    }
+
+    // Return the listings of the event that have not lapsed
+    let now = time();
+    RESALE_LISTINGS.with(|listings| {
+        let mut live = vec![];
+        for (_, listing) in listings
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+        {
+            let listing = listing.decode()?;
+            if listing.expires_at > now {
+                live.push(listing);
+            }
+        }
+        Ok(live)
+    })
+}
+
+#[ic_cdk::update]
+async fn buy_resale_ticket(ticket_id: u64) -> Result<Ticket, Error> {
+    let caller = _authenticated_caller()?;
+
+    // The ticket goes to the user bound to the caller
+    let buyer_user_id = _get_user_id_by_principal(&caller).ok_or(Error::NotFound {
+        msg: "caller is not registered as a user".to_string(),
+    })?;
+
+    // Retrieve the ticket, its event and its listing, or return a NotFound error if one is missing
+    let ticket = _get_ticket(&ticket_id)?.ok_or(Error::NotFound {
+        msg: format!("ticket id:{} does not exist", ticket_id),
+    })?;
+    let event = _get_event(&ticket.event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", ticket.event_id),
+    })?;
+    let not_listed = || Error::NotFound {
+        msg: format!("ticket id:{} is not listed for resale", ticket_id),
+    };
+    let listing = RESALE_LISTINGS
+        .with(|listings| listings.borrow().get(&(event.id, ticket_id)))
+        .map(|listing| listing.decode())
+        .transpose()?
+        .ok_or_else(not_listed)?;
+
+    // A listing only stands while it has not lapsed and its seller still holds the ticket, at a
+    // price the event still allows
+    let now = time();
+    if listing.expires_at <= now || listing.seller_user_id != ticket.user_id {
+        withdraw(&ticket);
+        return Err(not_listed());
+    }
+    if buyer_user_id == listing.seller_user_id {
+        return Err(Error::InvalidInput {
+            msg: format!("ticket id:{} is already held by the caller", ticket_id),
+        });
+    }
+    transfers::ensure_transferable(&event, &ticket, now)?;
+    ensure_price_allowed(&event, &ticket, listing.price_e8s)?;
+    ensure_royalty_payable(&event, listing.royalty_percent)?;
+
+    // Hold the ticket while the payment is in flight, so it cannot move or be sold twice
+    let hold = SaleHold::new(&ticket, buyer_user_id)?;
+
+    // Pull the whole price into the canister, which pays the seller and the organizer from it
+    let memo = [event.id.to_be_bytes(), ticket_id.to_be_bytes()].concat();
+    let block_index =
+        ledger::transfer_from(listing.ledger_id, caller, listing.price_e8s, memo).await?;
+
+    // Reload the ticket, which the hold kept in place, unless the event was cancelled meanwhile
+    let reloaded = _ensure_event_on_sale(event.id)
+        .and_then(|()| _get_ticket(&ticket_id))
+        .map(|reloaded| reloaded.unwrap_or(ticket));
+    drop(hold);
+
+    // In this same call hand the ticket to the buyer and record what the seller and the organizer
+    // are owed, so neither happens without the other; refunds now go to the buyer, and are capped
+    // by what the buyer paid
+    let royalty_e8s = royalty_e8s(listing.price_e8s, listing.royalty_percent);
+    let resold = reloaded.and_then(|mut ticket| {
+        ticket.payment_block_index = Some(block_index);
+        ticket.payment_ledger_id = Some(listing.ledger_id);
+        ticket.paid_by = Some(caller);
+        ticket.price_paid_e8s = listing.price_e8s;
+        transfers::reassign(ticket, buyer_user_id, caller)
+    });
+    match resold {
+        Ok(ticket) => {
+            let payout_ids = [
+                owe(
+                    ticket_id,
+                    listing.ledger_id,
+                    listing.seller,
+                    listing.price_e8s - royalty_e8s,
+                ),
+                owe(ticket_id, listing.ledger_id, event.owner, royalty_e8s),
+            ];
+            ic_cdk::spawn(pay_out(payout_ids.into_iter().flatten().collect()));
+            Ok(ticket)
+        }
+        // The ticket could not be handed over, or its event was cancelled, so the buyer is paid back
+        Err(error) => {
+            let payout_id = owe(ticket_id, listing.ledger_id, caller, listing.price_e8s);
+            ic_cdk::spawn(pay_out(payout_id.into_iter().collect()));
+            Err(error)
+        }
+    }
+}
+
+#[ic_cdk::query]
+fn list_payouts() -> Result<Vec<Payout>, Error> {
+    // Only admins may review the payouts the canister still owes
+    if !roles::is_admin(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: "only admins can review payouts".to_string(),
+        });
+    }
+    PAYOUTS.with(|payouts| {
+        payouts
+            .borrow()
+            .iter()
+            .map(|(_, payout)| payout.decode())
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn price_cap_is_a_share_of_the_face_value() {
+        let terms = ResaleTerms {
+            price_cap_percent: 150,
+            royalty_percent: 10,
+        };
+        // A resold ticket's price paid does not move the cap
+        let ticket = Ticket {
+            face_value_e8s: 1_000,
+            price_paid_e8s: 5_000,
+            ..Default::default()
+        };
+        assert_eq!(price_cap_e8s(&terms, &ticket), 1_500);
+
+        let ticket = Ticket {
+            face_value_e8s: u64::MAX,
+            ..Default::default()
+        };
+        assert_eq!(price_cap_e8s(&terms, &ticket), u64::MAX);
+    }
+
+    #[test]
+    fn royalty_is_a_share_of_the_price_rounded_down() {
+        assert_eq!(royalty_e8s(1_000, 10), 100);
+        assert_eq!(royalty_e8s(999, 10), 99);
+        assert_eq!(royalty_e8s(1_000, 0), 0);
+        assert_eq!(royalty_e8s(u64::MAX, 100), u64::MAX);
+        assert_eq!(royalty_e8s(u64::MAX, 50), u64::MAX / 2);
+    }
+
+    fn event_owned_by(owner: Principal) -> Event {
+        Event {
+            id: 1,
+            owner,
+            name: "Concert".to_string(),
+            description: String::new(),
+            starts_at: 1_000,
+            ends_at: 2_000,
+            timezone: "UTC".to_string(),
+            location: String::new(),
+            capacity: 100,
+            exchangeable: false,
+            refund_policy: Default::default(),
+            transfer_rules: Default::default(),
+            cancelled_at: None,
+            created_at: 0,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn royalty_needs_an_organizer_to_pay() {
+        let event = event_owned_by(migrations::UNCLAIMED_PRINCIPAL);
+        assert!(ensure_royalty_payable(&event, 0).is_ok());
+        assert!(matches!(
+            ensure_royalty_payable(&event, 10),
+            Err(Error::Conflict { .. })
+        ));
+
+        let event = event_owned_by(Principal::management_canister());
+        assert!(ensure_royalty_payable(&event, 10).is_ok());
+    }
+}
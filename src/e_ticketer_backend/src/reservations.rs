@@ -0,0 +1,452 @@
+use crate::{
+    _authenticated_caller, _collect_payment, _ensure_event_on_sale, _ensure_on_sale, _get_event,
+    _get_ticket, _get_user_id_by_principal, _issue_reserved_ticket, _mint_ticket,
+    _remaining_capacity, _resolve_tier, _return_payment_on_failure, lifecycle, roles, seating,
+    tiers, waitlist, Error, Memory, Record, Ticket, TicketPayload, TicketStatus, Versioned,
+    ID_COUNTER, MEMORY_MANAGER, TICKET_STORAGE,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+// How long a reservation holds its tickets before they go back on sale
+const HOLD_DURATION: Duration = Duration::from_secs(10 * 60);
+
+// Most tickets a single reservation holds
+const MAX_RESERVATION_QUANTITY: u32 = 10;
+
+// Most tickets of one event a user holds across its active reservations, so no user can keep the
+// inventory off sale by reserving it over and over
+const MAX_HELD_TICKETS_PER_USER: usize = 10;
+
+// Interval between the sweeps that release expired reservations
+const RELEASE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Define a struct for tickets held for a buyer between choosing them and paying for them
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Reservation {
+    id: u64,
+    event_id: u64,
+    tier_id: Option<u64>,
+    user_id: u64,
+    // The `Reserved` tickets holding the seats, issued once the reservation is confirmed
+    ticket_ids: Vec<u64>,
+    created_at: u64,
+    // The tickets are released at this instant unless the reservation is confirmed
+    expires_at: u64,
+}
+
+impl Record for Reservation {
+    const KIND: &'static str = "reservation";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 512;
+}
+
+// Define a struct for a reservation being paid for
+struct PendingConfirmation {
+    event_id: u64,
+    user_id: u64,
+    ticket_ids: Vec<u64>,
+}
+
+thread_local! {
+    // Reservations that were neither confirmed nor released yet, by ID
+    static RESERVATIONS: RefCell<StableBTreeMap<u64, Versioned<Reservation>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    // Reservations whose payment is waiting on the ledger, by reservation ID; the canister is stopped
+    // before upgrades, so none survive one
+    static PENDING_CONFIRMATIONS: RefCell<BTreeMap<u64, PendingConfirmation>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+// Define a struct marking a reservation as being paid for until the payment completes or fails,
+// releasing it when dropped
+struct ConfirmationHold {
+    reservation_id: u64,
+}
+
+impl ConfirmationHold {
+    fn new(reservation: &Reservation) -> Result<Self, Error> {
+        PENDING_CONFIRMATIONS.with(|confirmations| {
+            let mut confirmations = confirmations.borrow_mut();
+            if confirmations.contains_key(&reservation.id) {
+                return Err(Error::Conflict {
+                    msg: format!(
+                        "reservation id:{} is already being paid for",
+                        reservation.id
+                    ),
+                });
+            }
+            confirmations.insert(
+                reservation.id,
+                PendingConfirmation {
+                    event_id: reservation.event_id,
+                    user_id: reservation.user_id,
+                    ticket_ids: reservation.ticket_ids.clone(),
+                },
+            );
+            Ok(ConfirmationHold {
+                reservation_id: reservation.id,
+            })
+        })
+    }
+}
+
+impl Drop for ConfirmationHold {
+    fn drop(&mut self) {
+        PENDING_CONFIRMATIONS
+            .with(|confirmations| confirmations.borrow_mut().remove(&self.reservation_id));
+    }
+}
+
+// Function to count the reservations being paid for in an event
+pub(crate) fn pending_for_event(event_id: u64) -> usize {
+    PENDING_CONFIRMATIONS.with(|confirmations| {
+        confirmations
+            .borrow()
+            .values()
+            .filter(|confirmation| confirmation.event_id == event_id)
+            .count()
+    })
+}
+
+// Function to count the reservations a user is paying for
+pub(crate) fn pending_for_user(user_id: u64) -> usize {
+    PENDING_CONFIRMATIONS.with(|confirmations| {
+        confirmations
+            .borrow()
+            .values()
+            .filter(|confirmation| confirmation.user_id == user_id)
+            .count()
+    })
+}
+
+// Function to check whether a reserved ticket is being paid for
+pub(crate) fn is_pending(ticket_id: u64) -> bool {
+    PENDING_CONFIRMATIONS.with(|confirmations| {
+        confirmations
+            .borrow()
+            .values()
+            .any(|confirmation| confirmation.ticket_ids.contains(&ticket_id))
+    })
+}
+
+// Function to check whether a reservation is being paid for
+fn is_reservation_pending(reservation_id: u64) -> bool {
+    PENDING_CONFIRMATIONS.with(|confirmations| confirmations.borrow().contains_key(&reservation_id))
+}
+
+// Function to list the readable reservations that match a condition
+fn find(matches: impl Fn(&Reservation) -> bool) -> Vec<Reservation> {
+    RESERVATIONS.with(|reservations| {
+        reservations
+            .borrow()
+            .iter()
+            .filter_map(|(_, reservation)| reservation.decode().ok())
+            .filter(|reservation| matches(reservation))
+            .collect()
+    })
+}
+
+// Function to remove the reservations of a deleted event; its tickets go with it
+pub(crate) fn remove_event(event_id: u64) {
+    for reservation in find(|reservation| reservation.event_id == event_id) {
+        RESERVATIONS.with(|reservations| reservations.borrow_mut().remove(&reservation.id));
+    }
+}
+
+// Function to remove the reservations of a deleted user; its tickets go with it
+pub(crate) fn remove_user(user_id: u64) {
+    for reservation in find(|reservation| reservation.user_id == user_id) {
+        RESERVATIONS.with(|reservations| reservations.borrow_mut().remove(&reservation.id));
+    }
+}
+
+// Function to end a reservation, moving the tickets it still holds to the given status
+fn end(reservation: &Reservation, to: TicketStatus, actor: Principal) {
+    for ticket_id in &reservation.ticket_ids {
+        let Ok(Some(mut ticket)) = _get_ticket(ticket_id) else {
+            continue;
+        };
+        if ticket.status != TicketStatus::Reserved {
+            continue;
+        }
+        if lifecycle::transition(&mut ticket, to, actor).is_ok() {
+            TICKET_STORAGE.with(|tickets| {
+                tickets
+                    .borrow_mut()
+                    .insert(ticket.id, Versioned::new(&ticket))
+            });
+        }
+    }
+    RESERVATIONS.with(|reservations| reservations.borrow_mut().remove(&reservation.id));
+}
+
+// Function to cancel the reservations of a cancelled event that are not being paid for; tickets
+// issued by a confirmation still being paid for are refunded along with the others
+pub(crate) fn close(event_id: u64, actor: Principal) {
+    for reservation in find(|reservation| reservation.event_id == event_id) {
+        if !is_reservation_pending(reservation.id) {
+            end(&reservation, TicketStatus::Cancelled, actor);
+        }
+    }
+}
+
+// Function to start the periodic release of expired reservations; timers do not survive upgrades, so
+// it runs after install and after every upgrade
+pub(crate) fn schedule_release() {
+    ic_cdk_timers::set_timer_interval(RELEASE_INTERVAL, || release_expired(time()));
+}
+
+// Function to release the reservations that expired at the given instant, offering their seats to
+// the waitlists of their events. Reservations being paid for are left until the payment settles
+fn release_expired(now: u64) {
+    let mut event_ids = BTreeSet::new();
+    for reservation in find(|reservation| reservation.expires_at <= now) {
+        if is_reservation_pending(reservation.id) {
+            continue;
+        }
+        end(&reservation, TicketStatus::Expired, ic_cdk::id());
+        event_ids.insert(reservation.event_id);
+    }
+    for event_id in event_ids {
+        waitlist::promote(event_id);
+    }
+}
+
+// Function to get a reservation, or return a NotFound error if it was confirmed, released or never
+// existed
+fn get(reservation_id: u64) -> Result<Reservation, Error> {
+    RESERVATIONS
+        .with(|reservations| reservations.borrow().get(&reservation_id))
+        .ok_or(Error::NotFound {
+            msg: format!("reservation id:{} does not exist", reservation_id),
+        })?
+        .decode()
+}
+
+// Function to get a reservation held by the caller
+fn own_reservation(reservation_id: u64, caller: &Principal) -> Result<Reservation, Error> {
+    let reservation = get(reservation_id)?;
+    if _get_user_id_by_principal(caller) != Some(reservation.user_id) {
+        return Err(Error::Unauthorized {
+            msg: format!("reservation id:{} belongs to another user", reservation_id),
+        });
+    }
+    Ok(reservation)
+}
+
+#[ic_cdk::update]
+fn reserve_tickets(
+    event_id: u64,
+    tier_id: Option<u64>,
+    quantity: u32,
+) -> Result<Reservation, Error> {
+    let caller = _authenticated_caller()?;
+
+    // The tickets are held for the user bound to the caller
+    let user_id = _get_user_id_by_principal(&caller).ok_or(Error::NotFound {
+        msg: "caller is not registered as a user".to_string(),
+    })?;
+    if quantity == 0 || quantity > MAX_RESERVATION_QUANTITY {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "quantity must be between 1 and {}",
+                MAX_RESERVATION_QUANTITY
+            ),
+        });
+    }
+
+    // Retrieve the event, or return a NotFound error if not found
+    let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+
+    // Refuse a reservation that would take the user's held tickets for the event over the limit
+    let held: usize =
+        find(|reservation| reservation.user_id == user_id && reservation.event_id == event_id)
+            .iter()
+            .map(|reservation| reservation.ticket_ids.len())
+            .sum();
+    if held + quantity as usize > MAX_HELD_TICKETS_PER_USER {
+        return Err(Error::Conflict {
+            msg: format!(
+                "user id:{} already holds {} of the {} tickets of event id:{} a user may reserve",
+                user_id, held, MAX_HELD_TICKETS_PER_USER, event_id
+            ),
+        });
+    }
+
+    // Refuse the reservation if the event is cancelled or has reserved seating, the event or the tier
+    // has fewer tickets left, or the tier is not on sale
+    _ensure_on_sale(&event)?;
+    seating::ensure_general_admission(event_id)?;
+    if (_remaining_capacity(&event) as usize) < quantity as usize {
+        return Err(Error::SoldOut {
+            msg: format!(
+                "event id:{} has fewer than {} tickets left",
+                event_id, quantity
+            ),
+        });
+    }
+    let now = time();
+    let tier = _resolve_tier(event_id, tier_id)?;
+    if let Some(tier) = &tier {
+        tiers::ensure_on_sale(tier, now)?;
+        if tiers::remaining(tier) < quantity as usize {
+            return Err(Error::SoldOut {
+                msg: format!(
+                    "tier id:{} has fewer than {} tickets left",
+                    tier.id, quantity
+                ),
+            });
+        }
+    }
+
+    // Hold the seats with reserved tickets, which count against the capacities until they are issued
+    // or released
+    let payload = TicketPayload {
+        event_id,
+        user_id,
+        tier_id,
+    };
+    let mut ticket_ids = vec![];
+    for _ in 0..quantity {
+        let ticket = _mint_ticket(&payload, None, 0, None, TicketStatus::Reserved, caller)?;
+        ticket_ids.push(ticket.id);
+    }
+
+    // Increment the global ID counter to get a new ID for the reservation
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let reservation = Reservation {
+        id,
+        event_id,
+        tier_id,
+        user_id,
+        ticket_ids,
+        created_at: now,
+        expires_at: now.saturating_add(HOLD_DURATION.as_nanos() as u64),
+    };
+    RESERVATIONS.with(|reservations| {
+        reservations
+            .borrow_mut()
+            .insert(id, Versioned::new(&reservation))
+    });
+    Ok(reservation)
+}
+
+#[ic_cdk::query]
+fn get_reservation(reservation_id: u64) -> Result<Reservation, Error> {
+    // Only the user holding a reservation may read it
+    own_reservation(reservation_id, &ic_cdk::caller())
+}
+
+#[ic_cdk::update]
+async fn confirm_reservation(reservation_id: u64) -> Result<Vec<Ticket>, Error> {
+    let caller = _authenticated_caller()?;
+
+    // Only the user holding the reservation may confirm it, before it expires
+    let reservation = own_reservation(reservation_id, &caller)?;
+    if reservation.expires_at <= time() {
+        return Err(Error::Conflict {
+            msg: format!("reservation id:{} has expired", reservation_id),
+        });
+    }
+    let event = _get_event(&reservation.event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", reservation.event_id),
+    })?;
+    _ensure_on_sale(&event)?;
+
+    // Only the tickets still reserved are paid for; a ticket cancelled since was given up
+    let mut tickets = vec![];
+    for ticket_id in &reservation.ticket_ids {
+        if let Some(ticket) = _get_ticket(ticket_id)? {
+            if ticket.status == TicketStatus::Reserved {
+                tickets.push(ticket);
+            }
+        }
+    }
+    if tickets.is_empty() {
+        RESERVATIONS.with(|reservations| reservations.borrow_mut().remove(&reservation_id));
+        return Err(Error::Conflict {
+            msg: format!("reservation id:{} holds no tickets", reservation_id),
+        });
+    }
+
+    // Pull the price of every ticket in one payment; the reservation cannot expire or be released
+    // while the payment is in flight
+    let tier = match reservation.tier_id {
+        Some(tier_id) => tiers::get_tier(reservation.event_id, tier_id)?,
+        None => None,
+    };
+    let hold = ConfirmationHold::new(&reservation)?;
+    let payment = match &tier {
+        Some(tier) => _collect_payment(caller, tier, tickets.len() as u64).await?,
+        None => None,
+    };
+
+    // Only now that the payment went through are the reserved tickets issued, unless the event was
+    // cancelled meanwhile. Every ticket is checked before any is issued, so a failure leaves the whole
+    // reservation to expire and the payment is sent back
+    drop(hold);
+    let price_paid_e8s = tier.map_or(0, |tier| tier.price_e8s);
+    let amount_e8s = price_paid_e8s.saturating_mul(tickets.len() as u64);
+    let issued = _ensure_event_on_sale(reservation.event_id).and_then(|()| {
+        let mut reloaded = vec![];
+        for ticket in tickets {
+            let ticket = _get_ticket(&ticket.id)?.unwrap_or(ticket);
+            lifecycle::ensure_transition(&ticket, TicketStatus::Issued)?;
+            reloaded.push(ticket);
+        }
+        let mut issued = vec![];
+        for ticket in reloaded {
+            issued.push(_issue_reserved_ticket(
+                ticket,
+                price_paid_e8s,
+                payment.as_ref(),
+                caller,
+            )?);
+        }
+        RESERVATIONS.with(|reservations| reservations.borrow_mut().remove(&reservation_id));
+        Ok(issued)
+    });
+    _return_payment_on_failure(issued, payment, amount_e8s).await
+}
+
+#[ic_cdk::update]
+fn release_reservation(reservation_id: u64) -> Result<String, Error> {
+    let caller = _authenticated_caller()?;
+
+    // Only the user holding the reservation, or a manager of its event, may release it
+    let reservation = get(reservation_id)?;
+    if _get_user_id_by_principal(&caller) != Some(reservation.user_id) {
+        let event = _get_event(&reservation.event_id)?;
+        if !event.is_some_and(|event| roles::is_event_manager(&event, &caller)) {
+            return Err(Error::Unauthorized {
+                msg: format!("caller cannot release reservation id:{}", reservation_id),
+            });
+        }
+    }
+    if is_reservation_pending(reservation_id) {
+        return Err(Error::Conflict {
+            msg: format!("reservation id:{} is being paid for", reservation_id),
+        });
+    }
+
+    // Cancel the reserved tickets and offer their seats to the waitlist
+    end(&reservation, TicketStatus::Cancelled, caller);
+    waitlist::promote(reservation.event_id);
+    Ok(format!("reservation id: {} released", reservation_id))
+}
@@ -0,0 +1,257 @@
+use crate::{
+    _authenticated_caller, _get_event, _get_user_id_by_principal, _principal_key, Error, Event,
+    Memory, PrincipalKey, Record, Versioned, MEMORY_MANAGER,
+};
+use candid::Principal;
+use ic_cdk::api::{is_controller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Roles are stored under a one-byte tag followed by the event id for per-event roles
+type RoleKey = Blob<9>;
+
+const ADMIN_TAG: u8 = 0;
+const ORGANIZER_TAG: u8 = 1;
+const DOOR_STAFF_TAG: u8 = 2;
+
+// Define an enum for the roles a principal can hold
+#[derive(candid::CandidType, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Role {
+    // Manages roles and every event
+    Admin,
+    // Creates and manages their own events
+    Organizer,
+    // Admits ticket holders at the door of a single event
+    DoorStaff { event_id: u64 },
+    // Holds tickets; implied for every principal with a registered user
+    Attendee,
+}
+
+// Define a struct for a role granted to a principal
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RoleGrant {
+    principal: Principal,
+    role: Role,
+    granted_by: Principal,
+    granted_at: u64,
+}
+
+// Define a struct for the grant details kept in stable memory
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StoredGrant {
+    granted_by: Principal,
+    granted_at: u64,
+}
+
+impl Record for StoredGrant {
+    const KIND: &'static str = "role grant";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 128;
+}
+
+thread_local! {
+    static ROLE_STORAGE: RefCell<StableBTreeMap<(PrincipalKey, RoleKey), Versioned<StoredGrant>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+}
+
+fn role_key(role: &Role) -> Option<RoleKey> {
+    // Helper function to encode a stored role; the implied attendee role is never stored
+    let bytes = match role {
+        Role::Admin => vec![ADMIN_TAG],
+        Role::Organizer => vec![ORGANIZER_TAG],
+        Role::DoorStaff { event_id } => {
+            let mut bytes = vec![DOOR_STAFF_TAG];
+            bytes.extend_from_slice(&event_id.to_be_bytes());
+            bytes
+        }
+        Role::Attendee => return None,
+    };
+    Some(RoleKey::try_from(bytes.as_slice()).expect("role key fits in 9 bytes"))
+}
+
+fn role_from_key(key: &RoleKey) -> Role {
+    // Helper function to decode a stored role
+    let bytes = key.as_slice();
+    match bytes[0] {
+        ADMIN_TAG => Role::Admin,
+        ORGANIZER_TAG => Role::Organizer,
+        _ => Role::DoorStaff {
+            event_id: u64::from_be_bytes(bytes[1..9].try_into().expect("door staff key")),
+        },
+    }
+}
+
+// Function to check whether a principal holds a role
+pub(crate) fn has_role(principal: &Principal, role: &Role) -> bool {
+    match role_key(role) {
+        Some(key) => ROLE_STORAGE.with(|roles| {
+            roles
+                .borrow()
+                .contains_key(&(_principal_key(principal), key))
+        }),
+        None => _get_user_id_by_principal(principal).is_some(),
+    }
+}
+
+// Function to revoke every door staff grant for a deleted event
+pub(crate) fn remove_event(event_id: u64) {
+    let Some(event_key) = role_key(&Role::DoorStaff { event_id }) else {
+        return;
+    };
+    ROLE_STORAGE.with(|roles| {
+        let mut roles = roles.borrow_mut();
+        let keys: Vec<(PrincipalKey, RoleKey)> = roles
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|(_, role)| *role == event_key)
+            .collect();
+        for key in keys {
+            roles.remove(&key);
+        }
+    });
+}
+
+// Function to check whether a principal is an admin; controllers always are
+pub(crate) fn is_admin(principal: &Principal) -> bool {
+    is_controller(principal) || has_role(principal, &Role::Admin)
+}
+
+// Function to check that the caller may create events
+pub(crate) fn ensure_organizer(caller: &Principal) -> Result<(), Error> {
+    if is_admin(caller) || has_role(caller, &Role::Organizer) {
+        return Ok(());
+    }
+    Err(Error::Unauthorized {
+        msg: "caller is not an organizer".to_string(),
+    })
+}
+
+// Function to check whether a principal manages an event: admins, or the organizer who owns it
+pub(crate) fn is_event_manager(event: &Event, principal: &Principal) -> bool {
+    is_admin(principal) || (event.owner == *principal && has_role(principal, &Role::Organizer))
+}
+
+// Function to check that the caller manages the given event
+pub(crate) fn ensure_event_manager(event: &Event, caller: &Principal) -> Result<(), Error> {
+    if !is_event_manager(event, caller) {
+        return Err(Error::Unauthorized {
+            msg: format!("caller does not manage event id:{}", event.id),
+        });
+    }
+    Ok(())
+}
+
+// Function to check that the caller may grant or revoke a role
+fn ensure_can_assign(role: &Role, caller: &Principal) -> Result<(), Error> {
+    match role {
+        Role::Admin | Role::Organizer => {
+            if !is_admin(caller) {
+                return Err(Error::Unauthorized {
+                    msg: "only admins can assign this role".to_string(),
+                });
+            }
+        }
+        // Door staff are assigned per event by whoever manages that event
+        Role::DoorStaff { event_id } => {
+            let event = _get_event(event_id)?.ok_or(Error::NotFound {
+                msg: format!("event id:{} does not exist", event_id),
+            })?;
+            ensure_event_manager(&event, caller)?;
+        }
+        Role::Attendee => {
+            return Err(Error::InvalidInput {
+                msg: "the attendee role is implied by registering a user".to_string(),
+            })
+        }
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn grant_role(principal: Principal, role: Role) -> Result<RoleGrant, Error> {
+    let caller = _authenticated_caller()?;
+    ensure_can_assign(&role, &caller)?;
+    if principal == Principal::anonymous() {
+        return Err(Error::InvalidInput {
+            msg: "roles cannot be granted to the anonymous principal".to_string(),
+        });
+    }
+
+    // Record the grant, replacing any earlier grant of the same role
+    let key = role_key(&role).expect("attendee role is rejected above");
+    let grant = StoredGrant {
+        granted_by: caller,
+        granted_at: time(),
+    };
+    ROLE_STORAGE.with(|roles| {
+        roles
+            .borrow_mut()
+            .insert((_principal_key(&principal), key), Versioned::new(&grant))
+    });
+
+    Ok(RoleGrant {
+        principal,
+        role,
+        granted_by: grant.granted_by,
+        granted_at: grant.granted_at,
+    })
+}
+
+#[ic_cdk::update]
+fn revoke_role(principal: Principal, role: Role) -> Result<String, Error> {
+    let caller = _authenticated_caller()?;
+    ensure_can_assign(&role, &caller)?;
+
+    // Remove the grant, or return a NotFound error if the principal does not hold the role
+    let key = role_key(&role).expect("attendee role is rejected above");
+    match ROLE_STORAGE.with(|roles| {
+        roles
+            .borrow_mut()
+            .remove(&(_principal_key(&principal), key))
+    }) {
+        Some(_) => Ok(format!("role {:?} revoked from {}", role, principal)),
+        None => Err(Error::NotFound {
+            msg: format!("{} does not hold role {:?}", principal, role),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn list_roles(principal: Option<Principal>) -> Result<Vec<RoleGrant>, Error> {
+    // Anyone may list their own roles; admins may list the roles of any or all principals
+    let caller = ic_cdk::caller();
+    if principal != Some(caller) && !is_admin(&caller) {
+        return Err(Error::Unauthorized {
+            msg: "only admins can list the roles of other principals".to_string(),
+        });
+    }
+
+    let to_grant = |((key, role), grant): ((PrincipalKey, RoleKey), Versioned<StoredGrant>)| {
+        let grant = grant.decode()?;
+        Ok(RoleGrant {
+            principal: Principal::from_slice(key.as_slice()),
+            role: role_from_key(&role),
+            granted_by: grant.granted_by,
+            granted_at: grant.granted_at,
+        })
+    };
+    ROLE_STORAGE.with(|roles| {
+        let roles = roles.borrow();
+        match principal {
+            // Grants of one principal are contiguous, so only its range is scanned
+            Some(principal) => {
+                let key = _principal_key(&principal);
+                roles
+                    .range((key, RoleKey::default())..)
+                    .take_while(|((principal_key, _), _)| *principal_key == key)
+                    .map(to_grant)
+                    .collect()
+            }
+            None => roles.iter().map(to_grant).collect(),
+        }
+    })
+}
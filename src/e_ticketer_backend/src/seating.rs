@@ -0,0 +1,881 @@
+use crate::{
+    _authenticated_caller, _collect_payment, _ensure_event_on_sale, _ensure_on_sale, _get_event,
+    _get_ticket, _get_user_id_by_principal, _mint_ticket, _pending_purchases, _remaining_capacity,
+    _resolve_tier, _return_payment_on_failure, _seats_taken, _validate_length, roles, tiers, Error,
+    Memory, PurchaseHold, Record, Ticket, TicketPayload, TicketStatus, Versioned, ID_COUNTER,
+    MAX_NAME_LENGTH, MEMORY_MANAGER,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+// Longest accepted section, row, seat number or price tier label, in bytes
+const MAX_LABEL_LENGTH: usize = 32;
+
+// Most seats a venue layout holds
+const MAX_VENUE_SEATS: usize = 10_000;
+
+// Most seats a single `create_venue` or `add_venue_seats` call lays out; larger venues are laid out
+// over several calls
+const MAX_SEATS_PER_CALL: usize = 1_000;
+
+// Default and largest number of seats a page of a seat listing holds
+const DEFAULT_SEAT_PAGE_SIZE: u32 = 100;
+const MAX_SEAT_PAGE_SIZE: u32 = 500;
+
+// Maximum number of seats a single listing call inspects, so sparse section filters stay within
+// limits
+const MAX_SCANNED_SEATS: usize = 2_000;
+
+// Most distinct price tiers a venue layout uses
+const MAX_PRICE_TIERS: usize = 16;
+
+// Most accessibility flags a seat carries
+const MAX_ACCESSIBILITY_FLAGS: usize = 5;
+
+// Define a struct for a 'Venue', a seating layout events can be sold by
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Venue {
+    id: u64,
+    owner: Principal,
+    name: String,
+    // Distinct price tier labels of the seats, which an event maps to its own tiers
+    price_tiers: Vec<String>,
+    seat_count: u32,
+    created_at: u64,
+}
+
+impl Record for Venue {
+    const KIND: &'static str = "venue";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 1024;
+}
+
+// Define an enum for the accessibility features of a seat
+#[derive(candid::CandidType, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Accessibility {
+    Wheelchair,
+    Companion,
+    StepFree,
+    HearingLoop,
+    LimitedView,
+}
+
+// Define a struct for one seat of a venue layout
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Seat {
+    // Seats are numbered from 1 within their venue, in the order of the layout
+    id: u64,
+    section: String,
+    row: String,
+    number: String,
+    accessibility: Vec<Accessibility>,
+    // Label of the price tier the seat is sold in, e.g. "Orchestra"
+    price_tier: String,
+}
+
+impl Record for Seat {
+    const KIND: &'static str = "seat";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 256;
+}
+
+// Define a struct for the payload of a seat (used in update calls)
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct SeatPayload {
+    number: String,
+    accessibility: Vec<Accessibility>,
+    price_tier: String,
+}
+
+// Define a struct for the payload of a row of seats (used in update calls)
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct RowPayload {
+    name: String,
+    seats: Vec<SeatPayload>,
+}
+
+// Define a struct for the payload of a section of rows (used in update calls)
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct SectionPayload {
+    name: String,
+    rows: Vec<RowPayload>,
+}
+
+// Define a struct for the payload of a venue layout (used in update calls)
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct VenuePayload {
+    name: String,
+    // The first seats of the layout; the rest are added with `add_venue_seats`
+    sections: Vec<SectionPayload>,
+}
+
+// Define a struct for the tier of an event a price tier of its venue is sold in
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct PriceTierMapping {
+    price_tier: String,
+    tier_id: u64,
+}
+
+// Define a struct for the venue layout an event sells its seats by
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct EventSeating {
+    venue_id: u64,
+    // Every price tier of the venue, mapped to one of the event's tiers
+    price_tiers: Vec<PriceTierMapping>,
+}
+
+impl Record for EventSeating {
+    const KIND: &'static str = "event seating";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 1024;
+}
+
+// Define an enum for whether a seat of an event can be bought
+#[derive(candid::CandidType, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum SeatStatus {
+    Available,
+    // Reserved, or being paid for
+    Held,
+    Sold,
+    // The seat's tier is retired or not on sale
+    Unavailable,
+}
+
+// Define a struct for one seat of an event's seat map
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct SeatAvailability {
+    seat: Seat,
+    tier_id: u64,
+    status: SeatStatus,
+}
+
+// Define a struct for one page of the seats of a venue
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct SeatPage {
+    seats: Vec<Seat>,
+    // Cursor to pass to the next call, or None once every seat has been listed
+    next_cursor: Option<u64>,
+}
+
+// Define a struct for one page of an event's seat map
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct SeatMapPage {
+    seats: Vec<SeatAvailability>,
+    // Cursor to pass to the next call, or None once every seat has been listed
+    next_cursor: Option<u64>,
+}
+
+thread_local! {
+    static VENUE_STORAGE: RefCell<StableBTreeMap<u64, Versioned<Venue>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    // Seats are keyed by venue so the layout of one venue is contiguous
+    static VENUE_SEATS: RefCell<StableBTreeMap<(u64, u64), Versioned<Seat>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    // Seat of each position of a venue, keyed by venue and the digest of the seat's section, row
+    // and number, so seats added to a venue are checked against it without reading its layout
+    static SEAT_POSITIONS: RefCell<StableBTreeMap<(u64, [u8; 32]), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+    ));
+
+    static EVENT_SEATING: RefCell<StableBTreeMap<u64, Versioned<EventSeating>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    // Latest ticket of each seat of an event, keyed by `(event_id, seat_id)`; the seat is taken
+    // while that ticket holds it
+    static SEAT_TICKETS: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+}
+
+// Function to check whether an event sells reserved seats
+pub(crate) fn is_seated(event_id: u64) -> bool {
+    EVENT_SEATING.with(|seating| seating.borrow().contains_key(&event_id))
+}
+
+// Function to refuse the general admission sales of an event that sells reserved seats
+pub(crate) fn ensure_general_admission(event_id: u64) -> Result<(), Error> {
+    if is_seated(event_id) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "event id:{} has reserved seating, use purchase_seat",
+                event_id
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to relate a seated ticket to its seat, or return a Conflict error if another ticket holds
+// the seat
+pub(crate) fn link_seat(ticket: &Ticket) -> Result<(), Error> {
+    let Some(seat_id) = ticket.seat_id else {
+        return Ok(());
+    };
+    let key = (ticket.event_id, seat_id);
+    let holder = SEAT_TICKETS.with(|relation| relation.borrow().get(&key));
+    if let Some(holder) = holder.filter(|holder| *holder != ticket.id && seat_holder(key).is_some())
+    {
+        return Err(Error::Conflict {
+            msg: format!(
+                "seat id:{} of event id:{} is held by ticket id:{}",
+                seat_id, ticket.event_id, holder
+            ),
+        });
+    }
+    SEAT_TICKETS.with(|relation| relation.borrow_mut().insert(key, ticket.id));
+    Ok(())
+}
+
+// Function to remove the relation of a seated ticket to its seat, if the seat still points at it
+pub(crate) fn unlink_seat(ticket: &Ticket) {
+    let Some(seat_id) = ticket.seat_id else {
+        return;
+    };
+    let key = (ticket.event_id, seat_id);
+    SEAT_TICKETS.with(|relation| {
+        let mut relation = relation.borrow_mut();
+        if relation.get(&key) == Some(ticket.id) {
+            relation.remove(&key);
+        }
+    });
+}
+
+// Function to remove the seating of a deleted event and the relations of its seats
+pub(crate) fn remove_event(event_id: u64) {
+    EVENT_SEATING.with(|seating| seating.borrow_mut().remove(&event_id));
+    SEAT_TICKETS.with(|relation| {
+        let mut relation = relation.borrow_mut();
+        let keys: Vec<(u64, u64)> = relation
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            relation.remove(&key);
+        }
+    });
+}
+
+// Function to find the ticket that holds a seat of an event; a ticket that fails to decode keeps the
+// seat, so a bad record never frees it
+fn seat_holder(key: (u64, u64)) -> Option<TicketStatus> {
+    let ticket_id = SEAT_TICKETS.with(|relation| relation.borrow().get(&key))?;
+    match _get_ticket(&ticket_id) {
+        Ok(ticket) => ticket
+            .filter(|ticket| ticket.event_id == key.0 && ticket.seat_id == Some(key.1))
+            .map(|ticket| ticket.status)
+            .filter(|status| status.holds_seat()),
+        Err(_) => Some(TicketStatus::Issued),
+    }
+}
+
+// Function to tell whether a seat of an event is sold, held by a reservation or a payment in flight,
+// or free
+fn seat_status(event_id: u64, seat_id: u64) -> SeatStatus {
+    match seat_holder((event_id, seat_id)) {
+        Some(TicketStatus::Reserved) => SeatStatus::Held,
+        Some(_) => SeatStatus::Sold,
+        None if _pending_purchases(|purchase| {
+            purchase.event_id == event_id && purchase.seat_id == Some(seat_id)
+        }) > 0 =>
+        {
+            SeatStatus::Held
+        }
+        None => SeatStatus::Available,
+    }
+}
+
+// Function to get a venue, or return a NotFound error if not found
+fn venue(venue_id: u64) -> Result<Venue, Error> {
+    VENUE_STORAGE
+        .with(|venues| {
+            venues
+                .borrow()
+                .get(&venue_id)
+                .map(|venue| venue.decode())
+                .transpose()
+        })?
+        .ok_or(Error::NotFound {
+            msg: format!("venue id:{} does not exist", venue_id),
+        })
+}
+
+// Function to list one page of the seats of a venue in layout order, starting after the cursor and
+// optionally only those of one section, with the cursor the next page starts after
+fn seat_page(
+    venue_id: u64,
+    section: Option<&str>,
+    cursor: Option<u64>,
+    page_size: u32,
+) -> Result<(Vec<Seat>, Option<u64>), Error> {
+    // Clamp the page size, using the default when none is given
+    let page_size = match page_size {
+        0 => DEFAULT_SEAT_PAGE_SIZE,
+        size => size.min(MAX_SEAT_PAGE_SIZE),
+    } as usize;
+
+    // Scan seats in layout order, starting after the cursor
+    let start = cursor.map_or(Bound::Included((venue_id, 0)), |seat_id| {
+        Bound::Excluded((venue_id, seat_id))
+    });
+    VENUE_SEATS.with(|seats| {
+        let seats = seats.borrow();
+        let mut page = vec![];
+        let mut last_scanned = None;
+        let range = seats.range((start, Bound::Included((venue_id, u64::MAX))));
+        for (scanned, ((_, seat_id), seat)) in range.enumerate() {
+            // Stop when the page is full or the scan budget is spent; the caller resumes here
+            if page.len() == page_size || scanned == MAX_SCANNED_SEATS {
+                return Ok((page, last_scanned));
+            }
+            last_scanned = Some(seat_id);
+            let seat = seat.decode()?;
+            if section.is_none_or(|section| seat.section == section) {
+                page.push(seat);
+            }
+        }
+
+        // Every remaining seat was scanned
+        Ok((page, None))
+    })
+}
+
+// Function to get the key a position of a venue is indexed under
+fn position_key(venue_id: u64, section: &str, row: &str, number: &str) -> (u64, [u8; 32]) {
+    let mut hasher = Sha256::new();
+    for part in [section, row, number] {
+        hasher.update((part.len() as u32).to_be_bytes());
+        hasher.update(part.as_bytes());
+    }
+    (venue_id, hasher.finalize().into())
+}
+
+// Function to index the positions of every seat once, for canisters whose venues were laid out
+// before positions were indexed
+pub(crate) fn index_seat_positions() {
+    let indexed = SEAT_POSITIONS.with(|positions| positions.borrow().len());
+    if VENUE_SEATS.with(|seats| seats.borrow().len()) == indexed {
+        return;
+    }
+    VENUE_SEATS.with(|seats| {
+        SEAT_POSITIONS.with(|positions| {
+            let mut positions = positions.borrow_mut();
+            for ((venue_id, seat_id), seat) in seats.borrow().iter() {
+                let Ok(seat) = seat.decode() else {
+                    continue;
+                };
+                positions.insert(
+                    position_key(venue_id, &seat.section, &seat.row, &seat.number),
+                    seat_id,
+                );
+            }
+        })
+    });
+}
+
+// Function to find an event that sells seats by a venue
+fn seated_event(venue_id: u64) -> Option<u64> {
+    EVENT_SEATING.with(|seating| {
+        seating
+            .borrow()
+            .iter()
+            .find(|(_, seating)| seating.decode().map_or(true, |s| s.venue_id == venue_id))
+            .map(|(event_id, _)| event_id)
+    })
+}
+
+// Function to check that the caller owns a venue or is an admin
+fn ensure_venue_owner(venue: &Venue, caller: &Principal) -> Result<(), Error> {
+    if venue.owner != *caller && !roles::is_admin(caller) {
+        return Err(Error::Unauthorized {
+            msg: format!("caller does not own venue id:{}", venue.id),
+        });
+    }
+    Ok(())
+}
+
+// Function to get the seating of an event, or refuse events sold as general admission
+fn event_seating(event_id: u64) -> Result<EventSeating, Error> {
+    EVENT_SEATING
+        .with(|seating| seating.borrow().get(&event_id))
+        .ok_or(Error::InvalidInput {
+            msg: format!(
+                "event id:{} has no reserved seating, use purchase_ticket",
+                event_id
+            ),
+        })?
+        .decode()
+}
+
+// Function to find the tier of an event a seat is sold in
+fn seat_tier_id(seating: &EventSeating, seat: &Seat) -> Result<u64, Error> {
+    seating
+        .price_tiers
+        .iter()
+        .find(|mapping| mapping.price_tier == seat.price_tier)
+        .map(|mapping| mapping.tier_id)
+        .ok_or(Error::InvalidInput {
+            msg: format!("price tier {} is not mapped to a tier", seat.price_tier),
+        })
+}
+
+// Function to check sections of a venue layout and flatten them into seats numbered on from the
+// given seat id, refusing positions given twice or already taken in the venue
+fn layout_seats(
+    venue_id: Option<u64>,
+    sections: &[SectionPayload],
+    first_seat_id: u64,
+) -> Result<Vec<Seat>, Error> {
+    let mut seats = vec![];
+    let mut positions = BTreeSet::new();
+    for section in sections {
+        _validate_length("section", &section.name, MAX_LABEL_LENGTH)?;
+        for row in &section.rows {
+            _validate_length("row", &row.name, MAX_LABEL_LENGTH)?;
+            for seat in &row.seats {
+                _validate_length("seat number", &seat.number, MAX_LABEL_LENGTH)?;
+                _validate_length("price tier", &seat.price_tier, MAX_LABEL_LENGTH)?;
+                if seat.accessibility.len() > MAX_ACCESSIBILITY_FLAGS {
+                    return Err(Error::InvalidInput {
+                        msg: format!(
+                            "a seat has at most {} accessibility flags",
+                            MAX_ACCESSIBILITY_FLAGS
+                        ),
+                    });
+                }
+
+                // A seat is identified by its section, row and number
+                let taken = venue_id.is_some_and(|venue_id| {
+                    let key = position_key(venue_id, &section.name, &row.name, &seat.number);
+                    SEAT_POSITIONS.with(|positions| positions.borrow().contains_key(&key))
+                });
+                if taken {
+                    return Err(Error::InvalidInput {
+                        msg: format!(
+                            "seat {} of row {} in section {} is already laid out",
+                            seat.number, row.name, section.name
+                        ),
+                    });
+                }
+                if !positions.insert((&section.name, &row.name, &seat.number)) {
+                    return Err(Error::InvalidInput {
+                        msg: format!(
+                            "seat {} of row {} in section {} appears twice",
+                            seat.number, row.name, section.name
+                        ),
+                    });
+                }
+                if seats.len() == MAX_SEATS_PER_CALL {
+                    return Err(Error::InvalidInput {
+                        msg: format!(
+                            "at most {} seats are laid out per call, add the rest with add_venue_seats",
+                            MAX_SEATS_PER_CALL
+                        ),
+                    });
+                }
+                let id = first_seat_id + seats.len() as u64;
+                if id > MAX_VENUE_SEATS as u64 {
+                    return Err(Error::InvalidInput {
+                        msg: format!("a venue has at most {} seats", MAX_VENUE_SEATS),
+                    });
+                }
+                seats.push(Seat {
+                    id,
+                    section: section.name.clone(),
+                    row: row.name.clone(),
+                    number: seat.number.clone(),
+                    accessibility: seat.accessibility.clone(),
+                    price_tier: seat.price_tier.clone(),
+                });
+            }
+        }
+    }
+    if seats.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "a venue layout needs at least one seat".to_string(),
+        });
+    }
+    Ok(seats)
+}
+
+// Function to add the price tiers of new seats to those a venue already uses
+fn merge_price_tiers(price_tiers: &[String], seats: &[Seat]) -> Result<Vec<String>, Error> {
+    let price_tiers: Vec<String> = price_tiers
+        .iter()
+        .chain(seats.iter().map(|seat| &seat.price_tier))
+        .cloned()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    if price_tiers.len() > MAX_PRICE_TIERS {
+        return Err(Error::InvalidInput {
+            msg: format!("a venue has at most {} price tiers", MAX_PRICE_TIERS),
+        });
+    }
+    Ok(price_tiers)
+}
+
+// Function to store the seats of a venue and index their positions
+fn store_seats(venue_id: u64, seats: Vec<Seat>) {
+    VENUE_SEATS.with(|storage| {
+        SEAT_POSITIONS.with(|positions| {
+            let mut storage = storage.borrow_mut();
+            let mut positions = positions.borrow_mut();
+            for seat in seats {
+                positions.insert(
+                    position_key(venue_id, &seat.section, &seat.row, &seat.number),
+                    seat.id,
+                );
+                storage.insert((venue_id, seat.id), Versioned::new(&seat));
+            }
+        })
+    });
+}
+
+#[ic_cdk::update]
+fn create_venue(payload: VenuePayload) -> Result<Venue, Error> {
+    // Only organizers may create venues, and the caller becomes the owner of the venue
+    let owner = _authenticated_caller()?;
+    roles::ensure_organizer(&owner)?;
+    _validate_length("name", &payload.name, MAX_NAME_LENGTH)?;
+    let seats = layout_seats(None, &payload.sections, 1)?;
+    let price_tiers = merge_price_tiers(&[], &seats)?;
+
+    // Increment the global ID counter to get a new ID for the venue
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let venue = Venue {
+        id,
+        owner,
+        name: payload.name,
+        price_tiers,
+        seat_count: seats.len() as u32,
+        created_at: time(),
+    };
+
+    // Store the venue and its seats
+    VENUE_STORAGE.with(|venues| venues.borrow_mut().insert(id, Versioned::new(&venue)));
+    store_seats(id, seats);
+    Ok(venue)
+}
+
+#[ic_cdk::update]
+fn add_venue_seats(venue_id: u64, sections: Vec<SectionPayload>) -> Result<Venue, Error> {
+    let caller = _authenticated_caller()?;
+    let mut venue = venue(venue_id)?;
+
+    // Only the organizer who owns the venue, or an admin, may extend it
+    ensure_venue_owner(&venue, &caller)?;
+
+    // The layout is fixed once an event sells seats by it, since the event maps every price tier
+    if let Some(event_id) = seated_event(venue_id) {
+        return Err(Error::Conflict {
+            msg: format!(
+                "venue id:{} is the seating of event id:{}",
+                venue_id, event_id
+            ),
+        });
+    }
+
+    // The seats are numbered on from the last seat of the layout
+    let seats = layout_seats(Some(venue_id), &sections, u64::from(venue.seat_count) + 1)?;
+    venue.price_tiers = merge_price_tiers(&venue.price_tiers, &seats)?;
+    venue.seat_count += seats.len() as u32;
+
+    // Store the venue and the new seats
+    VENUE_STORAGE.with(|venues| venues.borrow_mut().insert(venue_id, Versioned::new(&venue)));
+    store_seats(venue_id, seats);
+    Ok(venue)
+}
+
+#[ic_cdk::query]
+fn get_venue(venue_id: u64) -> Result<Venue, Error> {
+    venue(venue_id)
+}
+
+#[ic_cdk::query]
+fn list_venue_seats(
+    venue_id: u64,
+    section: Option<String>,
+    cursor: Option<u64>,
+    page_size: u32,
+) -> Result<SeatPage, Error> {
+    // Retrieve a page of the seats of a venue, optionally only those of one section
+    venue(venue_id)?;
+    let (seats, next_cursor) = seat_page(venue_id, section.as_deref(), cursor, page_size)?;
+    Ok(SeatPage { seats, next_cursor })
+}
+
+#[ic_cdk::update]
+fn delete_venue(venue_id: u64) -> Result<Venue, Error> {
+    let caller = _authenticated_caller()?;
+    let venue = venue(venue_id)?;
+
+    // Only the organizer who owns the venue, or an admin, may delete it
+    ensure_venue_owner(&venue, &caller)?;
+
+    // Refuse the deletion while an event sells seats by the venue
+    if let Some(event_id) = seated_event(venue_id) {
+        return Err(Error::Conflict {
+            msg: format!(
+                "venue id:{} is the seating of event id:{}",
+                venue_id, event_id
+            ),
+        });
+    }
+
+    // Remove the venue, its seats and their positions
+    VENUE_SEATS.with(|seats| {
+        SEAT_POSITIONS.with(|positions| {
+            let mut seats = seats.borrow_mut();
+            let mut positions = positions.borrow_mut();
+            let removed: Vec<((u64, u64), Versioned<Seat>)> =
+                seats.range((venue_id, 0)..=(venue_id, u64::MAX)).collect();
+            for (key, seat) in removed {
+                seats.remove(&key);
+                if let Ok(seat) = seat.decode() {
+                    positions.remove(&position_key(
+                        venue_id,
+                        &seat.section,
+                        &seat.row,
+                        &seat.number,
+                    ));
+                }
+            }
+        })
+    });
+    VENUE_STORAGE.with(|venues| venues.borrow_mut().remove(&venue_id));
+    Ok(venue)
+}
+
+#[ic_cdk::update]
+fn set_event_seating(event_id: u64, seating: Option<EventSeating>) -> Result<(), Error> {
+    let caller = _authenticated_caller()?;
+
+    // Retrieve the event, or return a NotFound error if not found
+    let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+
+    // Only the organizer who owns the event, or an admin, may change how it is seated
+    roles::ensure_event_manager(&event, &caller)?;
+
+    // The seating is fixed once the first ticket is sold, reserved or being paid for
+    if _seats_taken(event_id) > 0 {
+        return Err(Error::Conflict {
+            msg: format!("event id:{} already has tickets", event_id),
+        });
+    }
+
+    let Some(seating) = seating else {
+        EVENT_SEATING.with(|storage| storage.borrow_mut().remove(&event_id));
+        return Ok(());
+    };
+
+    // Every price tier of the venue must be mapped once, to a tier of the event
+    let venue = venue(seating.venue_id)?;
+    let mut mapped = BTreeMap::new();
+    for mapping in &seating.price_tiers {
+        if !venue.price_tiers.contains(&mapping.price_tier) {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "venue id:{} has no price tier {}",
+                    venue.id, mapping.price_tier
+                ),
+            });
+        }
+        tiers::get_tier(event_id, mapping.tier_id)?.ok_or(Error::NotFound {
+            msg: format!(
+                "tier id:{} does not exist for event id:{}",
+                mapping.tier_id, event_id
+            ),
+        })?;
+        if mapped
+            .insert(&mapping.price_tier, mapping.tier_id)
+            .is_some()
+        {
+            return Err(Error::InvalidInput {
+                msg: format!("price tier {} is mapped twice", mapping.price_tier),
+            });
+        }
+    }
+    if let Some(price_tier) = venue
+        .price_tiers
+        .iter()
+        .find(|price_tier| !mapped.contains_key(price_tier))
+    {
+        return Err(Error::InvalidInput {
+            msg: format!("price tier {} is not mapped to a tier", price_tier),
+        });
+    }
+
+    EVENT_SEATING.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(event_id, Versioned::new(&seating))
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_event_seating(event_id: u64) -> Result<Option<EventSeating>, Error> {
+    _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+    EVENT_SEATING.with(|seating| {
+        seating
+            .borrow()
+            .get(&event_id)
+            .map(|seating| seating.decode())
+            .transpose()
+    })
+}
+
+#[ic_cdk::query]
+fn get_seat_map(
+    event_id: u64,
+    section: Option<String>,
+    cursor: Option<u64>,
+    page_size: u32,
+) -> Result<SeatMapPage, Error> {
+    let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+    let seating = event_seating(event_id)?;
+
+    // Load the tiers once, so each seat only looks up whether its tier is on sale
+    let now = time();
+    let mut on_sale = BTreeMap::new();
+    for mapping in &seating.price_tiers {
+        let available = event.cancelled_at.is_none()
+            && tiers::get_tier(event_id, mapping.tier_id)?
+                .is_some_and(|tier| !tier.retired && tiers::ensure_on_sale(&tier, now).is_ok());
+        on_sale.insert(mapping.tier_id, available);
+    }
+
+    // Only the seats of the requested page are looked up
+    let (seats, next_cursor) = seat_page(seating.venue_id, section.as_deref(), cursor, page_size)?;
+    let mut map = vec![];
+    for seat in seats {
+        let tier_id = seat_tier_id(&seating, &seat)?;
+        let status = match seat_status(event_id, seat.id) {
+            SeatStatus::Available if !on_sale[&tier_id] => SeatStatus::Unavailable,
+            status => status,
+        };
+        map.push(SeatAvailability {
+            seat,
+            tier_id,
+            status,
+        });
+    }
+    Ok(SeatMapPage {
+        seats: map,
+        next_cursor,
+    })
+}
+
+#[ic_cdk::update]
+async fn purchase_seat(event_id: u64, seat_id: u64) -> Result<Ticket, Error> {
+    let caller = _authenticated_caller()?;
+
+    // The ticket is issued to the user bound to the caller
+    let user_id = _get_user_id_by_principal(&caller).ok_or(Error::NotFound {
+        msg: "caller is not registered as a user".to_string(),
+    })?;
+
+    // Retrieve the event and the seat, or return a NotFound error if either is missing
+    let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+    let seating = event_seating(event_id)?;
+    let seat = VENUE_SEATS
+        .with(|seats| seats.borrow().get(&(seating.venue_id, seat_id)))
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "seat id:{} does not exist in venue id:{}",
+                seat_id, seating.venue_id
+            ),
+        })?
+        .decode()?;
+
+    // Refuse the sale if the event is cancelled, the seat is taken, the event or the seat's tier has
+    // no tickets left, or the tier is not on sale
+    _ensure_on_sale(&event)?;
+    if seat_status(event_id, seat_id) != SeatStatus::Available {
+        return Err(Error::Conflict {
+            msg: format!("seat id:{} is taken for event id:{}", seat_id, event_id),
+        });
+    }
+    if _remaining_capacity(&event) == 0 {
+        return Err(Error::SoldOut {
+            msg: format!("event id:{} is sold out", event_id),
+        });
+    }
+    let tier_id = seat_tier_id(&seating, &seat)?;
+    let tier = _resolve_tier(event_id, Some(tier_id))?.expect("a tier id is given");
+    tiers::ensure_on_sale(&tier, time())?;
+
+    // Hold the seat while the payment is in flight, so no other purchase can take it
+    let hold = PurchaseHold::new(event_id, tier_id, user_id, Some(seat_id));
+
+    // Pull the price from the buyer's account
+    let payment = _collect_payment(caller, &tier, 1).await?;
+
+    // Only now that the payment went through is the held seat turned into a ticket, unless the event
+    // was cancelled meanwhile
+    let payload = TicketPayload {
+        event_id,
+        user_id,
+        tier_id: Some(tier_id),
+    };
+    let ticket = _ensure_event_on_sale(event_id).and_then(|()| {
+        _mint_ticket(
+            &payload,
+            Some(seat_id),
+            tier.price_e8s,
+            payment.clone(),
+            TicketStatus::Issued,
+            caller,
+        )
+    });
+    drop(hold);
+    _return_payment_on_failure(ticket, payment, tier.price_e8s).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn largest_seat_fits_its_slot() {
+        let label = "x".repeat(MAX_LABEL_LENGTH);
+        let seat = Seat {
+            id: u64::MAX,
+            section: label.clone(),
+            row: label.clone(),
+            number: label.clone(),
+            accessibility: vec![Accessibility::LimitedView; MAX_ACCESSIBILITY_FLAGS],
+            price_tier: label,
+        };
+        assert!(Versioned::try_new(&seat).is_ok());
+    }
+}
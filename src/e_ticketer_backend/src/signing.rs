@@ -0,0 +1,431 @@
+use crate::{
+    _authenticated_caller, _get_event, _get_ticket, _get_user, _validate_length, checkin, roles,
+    Error, Memory, Record, Ticket, Versioned, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::ops::Bound;
+use std::time::Duration;
+use ticket_verifier::TicketClaims;
+
+// Delay before fetching the verification key again when it is unavailable
+const KEY_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+// Name of the threshold ECDSA key tickets are signed with until an admin picks another; `key_1` is
+// the production key
+const DEFAULT_KEY_NAME: &str = "key_1";
+
+// Longest name of a threshold ECDSA key
+const MAX_KEY_NAME_LENGTH: usize = 64;
+
+// Derivation path of the ticket signing key, so it is distinct from any other key of the canister
+const DERIVATION_PATH: &[u8] = b"tickets";
+
+// Most revoked signatures a single `get_revoked_signatures` call lists
+const REVOCATION_PAGE_SIZE: usize = 1000;
+
+// Define a struct for a signature kept so a ticket is signed once per revision and holder
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CachedSignature {
+    claims: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl Record for CachedSignature {
+    const KIND: &'static str = "signature";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 256;
+}
+
+// Define a struct for a ticket whose signatures below a revision no longer admit
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RevokedSignature {
+    ticket_id: u64,
+    revoked_below: u32,
+}
+
+// Define a struct for one page of the revoked signatures of an event
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct RevocationPage {
+    revoked: Vec<RevokedSignature>,
+    // Cursor to pass to the next call, or None once every revocation has been listed
+    next_cursor: Option<u64>,
+}
+
+// Define a struct marking a ticket as being signed until the signature arrives or fails, so
+// concurrent calls do not pay for the same signature twice
+struct SigningHold {
+    ticket_id: u64,
+}
+
+impl SigningHold {
+    fn new(ticket_id: u64) -> Result<Self, Error> {
+        if !SIGNING.with(|signing| signing.borrow_mut().insert(ticket_id)) {
+            return Err(Error::Conflict {
+                msg: format!(
+                    "ticket id:{} is already being signed, retry shortly",
+                    ticket_id
+                ),
+            });
+        }
+        Ok(SigningHold { ticket_id })
+    }
+}
+
+impl Drop for SigningHold {
+    fn drop(&mut self) {
+        SIGNING.with(|signing| signing.borrow_mut().remove(&self.ticket_id));
+    }
+}
+
+thread_local! {
+    // SEC1-encoded public key that verifies signed tickets, fetched once and kept across upgrades;
+    // empty until then
+    static VERIFICATION_KEY: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))), Vec::new())
+            .expect("Cannot create the verification key")
+    );
+
+    // Name of the threshold ECDSA key tickets are signed with
+    static KEY_NAME: RefCell<Cell<String, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))),
+            DEFAULT_KEY_NAME.to_string(),
+        )
+        .expect("Cannot create the signing key name")
+    );
+
+    // Current revision of each ticket that was ever revised; tickets without one are at revision 0
+    static TICKET_REVISIONS: RefCell<StableBTreeMap<u64, u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+
+    // Revision below which the signatures of each ticket no longer admit, keyed by the event the
+    // ticket was for when it was revised
+    static REVOCATIONS: RefCell<StableBTreeMap<(u64, u64), u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+    ));
+
+    // Latest signature of each ticket, handed out again while its claims are unchanged
+    static SIGNATURES: RefCell<StableBTreeMap<u64, Versioned<CachedSignature>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+
+    // Tickets whose signature is being requested from the management canister
+    static SIGNING: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+// Function to get the name of the threshold ECDSA key tickets are signed with
+fn key_name() -> String {
+    KEY_NAME.with(|name| name.borrow().get().clone())
+}
+
+// Function to get the current revision of a ticket
+fn revision(ticket_id: u64) -> u32 {
+    TICKET_REVISIONS.with(|revisions| revisions.borrow().get(&ticket_id).unwrap_or_default())
+}
+
+// Function to revise a ticket that changed, so the signatures handed out for it before stop
+// admitting; called before the changed ticket is stored
+pub(crate) fn revoke(ticket: &Ticket) {
+    let revision = revision(ticket.id) + 1;
+    TICKET_REVISIONS.with(|revisions| revisions.borrow_mut().insert(ticket.id, revision));
+    REVOCATIONS.with(|revocations| {
+        revocations
+            .borrow_mut()
+            .insert((ticket.event_id, ticket.id), revision)
+    });
+    SIGNATURES.with(|signatures| signatures.borrow_mut().remove(&ticket.id));
+}
+
+// Function to remove what is kept for the signatures of a deleted event and its tickets
+pub(crate) fn remove_event(event_id: u64, ticket_ids: &[u64]) {
+    for ticket_id in ticket_ids {
+        TICKET_REVISIONS.with(|revisions| revisions.borrow_mut().remove(ticket_id));
+        SIGNATURES.with(|signatures| signatures.borrow_mut().remove(ticket_id));
+    }
+    REVOCATIONS.with(|revocations| {
+        let mut revocations = revocations.borrow_mut();
+        let keys: Vec<(u64, u64)> = revocations
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            revocations.remove(&key);
+        }
+    });
+}
+
+// Function to get the verification key, or None until it has been fetched
+fn verification_key() -> Option<Vec<u8>> {
+    VERIFICATION_KEY.with(|key| Some(key.borrow().get().clone()).filter(|key| !key.is_empty()))
+}
+
+// Function to fetch the verification key right after install or upgrade, unless the canister already
+// has one
+pub(crate) fn schedule_key_fetch(delay: Duration) {
+    if verification_key().is_some() {
+        return;
+    }
+    ic_cdk_timers::set_timer(delay, || {
+        ic_cdk::spawn(async {
+            // A key fetched under a name that was replaced meanwhile is dropped
+            let name = key_name();
+            match public_key().await {
+                Ok(public_key) if verification_key().is_none() && key_name() == name => {
+                    VERIFICATION_KEY.with(|key| {
+                        key.borrow_mut()
+                            .set(public_key)
+                            .expect("Cannot set the verification key")
+                    });
+                }
+                Ok(_) => schedule_key_fetch(Duration::ZERO),
+                Err(_) => schedule_key_fetch(KEY_RETRY_DELAY),
+            }
+        })
+    });
+}
+
+// Function to get the threshold ECDSA key tickets are signed with
+#[cfg(not(feature = "local-signing"))]
+fn key_id() -> ic_cdk::api::management_canister::ecdsa::EcdsaKeyId {
+    use ic_cdk::api::management_canister::ecdsa::{EcdsaCurve, EcdsaKeyId};
+    EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name: key_name(),
+    }
+}
+
+// Function to ask the management canister for the public key of the ticket signing key
+#[cfg(not(feature = "local-signing"))]
+async fn public_key() -> Result<Vec<u8>, Error> {
+    use ic_cdk::api::management_canister::ecdsa::{ecdsa_public_key, EcdsaPublicKeyArgument};
+    let (response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: vec![DERIVATION_PATH.to_vec()],
+        key_id: key_id(),
+    })
+    .await
+    .map_err(|(code, msg)| Error::NotCreated {
+        msg: format!("verification key unavailable: {:?} {}", code, msg),
+    })?;
+    Ok(response.public_key)
+}
+
+// Function to sign a message hash with the threshold ECDSA key, returning the 64-byte signature
+#[cfg(not(feature = "local-signing"))]
+async fn sign(message_hash: [u8; 32]) -> Result<Vec<u8>, Error> {
+    use ic_cdk::api::management_canister::ecdsa::{sign_with_ecdsa, SignWithEcdsaArgument};
+    let (response,) = sign_with_ecdsa(SignWithEcdsaArgument {
+        message_hash: message_hash.to_vec(),
+        derivation_path: vec![DERIVATION_PATH.to_vec()],
+        key_id: key_id(),
+    })
+    .await
+    .map_err(|(code, msg)| Error::NotCreated {
+        msg: format!("ticket could not be signed: {:?} {}", code, msg),
+    })?;
+    Ok(response.signature)
+}
+
+// Function to get the local signing key, derived from the secret behind ticket codes
+#[cfg(feature = "local-signing")]
+fn local_key() -> Result<k256::ecdsa::SigningKey, Error> {
+    let unavailable = || Error::NotCreated {
+        msg: "ticket signing is not available yet, retry shortly".to_string(),
+    };
+    let secret = crate::codes::derived_key(DERIVATION_PATH).ok_or_else(unavailable)?;
+    k256::ecdsa::SigningKey::from_slice(&secret).map_err(|_| unavailable())
+}
+
+// Function to get the public key of the local signing key
+#[cfg(feature = "local-signing")]
+async fn public_key() -> Result<Vec<u8>, Error> {
+    let key = local_key()?;
+    Ok(key
+        .verifying_key()
+        .to_encoded_point(true)
+        .as_bytes()
+        .to_vec())
+}
+
+// Function to sign a message hash with the local signing key, returning the 64-byte signature
+#[cfg(feature = "local-signing")]
+async fn sign(message_hash: [u8; 32]) -> Result<Vec<u8>, Error> {
+    use k256::ecdsa::signature::hazmat::PrehashSigner;
+    let signature: k256::ecdsa::Signature =
+        local_key()?
+            .sign_prehash(&message_hash)
+            .map_err(|_| Error::NotCreated {
+                msg: "ticket could not be signed".to_string(),
+            })?;
+    Ok(signature.to_bytes().to_vec())
+}
+
+// Function to get the claims a ticket is signed with for the caller, checking that the caller holds
+// it and that it still admits
+fn claims_for(id: u64, caller: &candid::Principal) -> Result<TicketClaims, Error> {
+    // Retrieve the ticket and its event, or return a NotFound error if either is missing
+    let ticket = _get_ticket(&id)?.ok_or(Error::NotFound {
+        msg: format!("ticket id:{} does not exist", id),
+    })?;
+    let event = _get_event(&ticket.event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", ticket.event_id),
+    })?;
+
+    // Only the holder of a ticket that still admits may get it signed
+    let is_holder = _get_user(&ticket.user_id)?.is_some_and(|user| user.principal == *caller);
+    if !is_holder {
+        return Err(Error::Unauthorized {
+            msg: format!("caller does not hold ticket id:{}", id),
+        });
+    }
+    if !ticket.status.is_valid() || event.cancelled_at.is_some() {
+        return Err(Error::Conflict {
+            msg: format!("ticket id:{} does not admit", id),
+        });
+    }
+
+    // The signed ticket admits while doors are open, the same window as online check-in, until the
+    // ticket is revised
+    Ok(TicketClaims {
+        event_id: ticket.event_id,
+        ticket_id: ticket.id,
+        revision: revision(ticket.id),
+        holder: caller.as_slice().to_vec(),
+        tier_id: ticket.tier_id,
+        valid_from: event
+            .starts_at
+            .saturating_sub(checkin::DOORS_OPEN_BEFORE_NANOS),
+        valid_until: event.ends_at,
+    })
+}
+
+#[ic_cdk::update]
+async fn get_signed_ticket(id: u64) -> Result<Vec<u8>, Error> {
+    let caller = ic_cdk::caller();
+    let claims = claims_for(id, &caller)?.encode();
+
+    // A ticket whose claims did not change since it was last signed gets the same signature
+    let cached = SIGNATURES
+        .with(|signatures| signatures.borrow().get(&id))
+        .and_then(|cached| cached.decode().ok());
+    if let Some(cached) = cached.filter(|cached| cached.claims == claims) {
+        return Ok(ticket_verifier::signed_ticket(
+            &cached.claims,
+            &cached.signature,
+        ));
+    }
+
+    // Sign the claims; a ticket that changed meanwhile, or a key that was replaced, makes the
+    // signature stale, so it is neither kept nor handed out
+    let hold = SigningHold::new(id)?;
+    let name = key_name();
+    let signature = sign(ticket_verifier::message_hash(&claims)).await?;
+    drop(hold);
+    if claims_for(id, &caller)?.encode() != claims || key_name() != name {
+        return Err(Error::Conflict {
+            msg: format!("ticket id:{} changed while it was signed, retry", id),
+        });
+    }
+    SIGNATURES.with(|signatures| {
+        signatures.borrow_mut().insert(
+            id,
+            Versioned::new(&CachedSignature {
+                claims: claims.clone(),
+                signature: signature.clone(),
+            }),
+        )
+    });
+    Ok(ticket_verifier::signed_ticket(&claims, &signature))
+}
+
+#[ic_cdk::query]
+fn get_revoked_signatures(event_id: u64, cursor: Option<u64>) -> Result<RevocationPage, Error> {
+    // Retrieve the event, or return a NotFound error if not found
+    let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+
+    // Only door staff of the event, or its managers, sync the revocations their scanners check
+    checkin::ensure_door_staff(&event, &ic_cdk::caller())?;
+
+    // List revocations in ticket ID order, starting after the cursor
+    let start = cursor.map_or(Bound::Included((event_id, 0)), |ticket_id| {
+        Bound::Excluded((event_id, ticket_id))
+    });
+    REVOCATIONS.with(|revocations| {
+        let revocations = revocations.borrow();
+        let mut revoked: Vec<RevokedSignature> = revocations
+            .range((start, Bound::Included((event_id, u64::MAX))))
+            .take(REVOCATION_PAGE_SIZE + 1)
+            .map(|((_, ticket_id), revoked_below)| RevokedSignature {
+                ticket_id,
+                revoked_below,
+            })
+            .collect();
+        let next_cursor = if revoked.len() > REVOCATION_PAGE_SIZE {
+            revoked.truncate(REVOCATION_PAGE_SIZE);
+            revoked.last().map(|revocation| revocation.ticket_id)
+        } else {
+            None
+        };
+        Ok(RevocationPage {
+            revoked,
+            next_cursor,
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn set_ticket_signing_key(name: String) -> Result<String, Error> {
+    // Only admins may choose the key tickets are signed with
+    let caller = _authenticated_caller()?;
+    if !roles::is_admin(&caller) {
+        return Err(Error::Unauthorized {
+            msg: "only admins can configure the ticket signing key".to_string(),
+        });
+    }
+    _validate_length("key name", &name, MAX_KEY_NAME_LENGTH)?;
+    if name.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "key name cannot be empty".to_string(),
+        });
+    }
+
+    // Signatures and the verification key of the previous key are dropped, and the new
+    // verification key is fetched; scanners fetch it again before verifying new signatures
+    KEY_NAME.with(|key_name| {
+        key_name
+            .borrow_mut()
+            .set(name.clone())
+            .expect("Cannot set the signing key name")
+    });
+    VERIFICATION_KEY.with(|key| {
+        key.borrow_mut()
+            .set(Vec::new())
+            .expect("Cannot set the verification key")
+    });
+    SIGNATURES.with(|signatures| {
+        let mut signatures = signatures.borrow_mut();
+        let ticket_ids: Vec<u64> = signatures.iter().map(|(ticket_id, _)| ticket_id).collect();
+        for ticket_id in ticket_ids {
+            signatures.remove(&ticket_id);
+        }
+    });
+    schedule_key_fetch(Duration::ZERO);
+    Ok(format!("tickets are now signed with key {}", name))
+}
+
+#[ic_cdk::query]
+fn ticket_verification_key() -> Result<Vec<u8>, Error> {
+    // Return the compressed SEC1 public key scanners verify signed tickets with
+    verification_key().ok_or(Error::NotCreated {
+        msg: "verification key is not available yet, retry shortly".to_string(),
+    })
+}
@@ -0,0 +1,284 @@
+use crate::{
+    _authenticated_caller, _get_event, _validate_length, ledger, Error, Memory, Record, Versioned,
+    ID_COUNTER, MAX_NAME_LENGTH, MEMORY_MANAGER, TIER_TICKETS,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Longest accepted currency symbol, in bytes
+const MAX_CURRENCY_LENGTH: usize = 16;
+
+// Define a struct for a 'TicketTier', one type of ticket an event sells
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TicketTier {
+    pub id: u64,
+    pub event_id: u64,
+    pub name: String,
+    // Price of one ticket in e8s of the tier's currency
+    pub price_e8s: u64,
+    // Currency symbol, e.g. "ICP", and the ledger canister it is paid on; free tiers need no ledger
+    pub currency: String,
+    pub ledger_id: Option<Principal>,
+    pub capacity: u32,
+    // Tickets of the tier are sold from `sales_start` until `sales_end`, in nanoseconds since epoch
+    pub sales_start: u64,
+    pub sales_end: u64,
+    // Retired tiers are no longer sold, but the tickets already sold keep them
+    pub retired: bool,
+    pub created_at: u64,
+    pub updated_at: Option<u64>,
+}
+
+impl Record for TicketTier {
+    const KIND: &'static str = "tier";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 512;
+}
+
+// Define a struct for the payload of a tier (used in update calls)
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct TierPayload {
+    name: String,
+    price_e8s: u64,
+    currency: String,
+    ledger_id: Option<Principal>,
+    capacity: u32,
+    sales_start: u64,
+    sales_end: u64,
+}
+
+thread_local! {
+    // Tiers are keyed by event so the tiers of one event are contiguous
+    static TIER_STORAGE: RefCell<StableBTreeMap<(u64, u64), Versioned<TicketTier>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+}
+
+// Function to get a tier of an event
+pub(crate) fn get_tier(event_id: u64, tier_id: u64) -> Result<Option<TicketTier>, Error> {
+    TIER_STORAGE.with(|tiers| {
+        tiers
+            .borrow()
+            .get(&(event_id, tier_id))
+            .map(|tier| tier.decode())
+            .transpose()
+    })
+}
+
+// Function to list the tiers of an event, in ID order
+pub(crate) fn event_tiers(event_id: u64) -> Result<Vec<TicketTier>, Error> {
+    TIER_STORAGE.with(|tiers| {
+        tiers
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, tier)| tier.decode())
+            .collect()
+    })
+}
+
+// Function to remove every tier of a deleted event
+pub(crate) fn remove_event_tiers(event_id: u64) {
+    TIER_STORAGE.with(|tiers| {
+        let mut tiers = tiers.borrow_mut();
+        let keys: Vec<(u64, u64)> = tiers
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            tiers.remove(&key);
+        }
+    });
+}
+
+// Function to count the tickets sold in a tier that still take a seat
+pub(crate) fn sold(tier_id: u64) -> usize {
+    let ticket_ids = TIER_TICKETS.with(|relation| {
+        relation
+            .borrow()
+            .range((tier_id, 0)..=(tier_id, u64::MAX))
+            .map(|((_, ticket_id), _)| ticket_id)
+            .collect()
+    });
+    crate::_count_seats(ticket_ids)
+}
+
+// Function to count the tickets a tier can still take, besides those sold, reserved or being paid
+// for
+pub(crate) fn remaining(tier: &TicketTier) -> usize {
+    let pending = crate::_pending_purchases(|purchase| purchase.tier_id == tier.id);
+    (tier.capacity as usize).saturating_sub(sold(tier.id) + pending)
+}
+
+// Function to check that a tier can take one more ticket
+pub(crate) fn ensure_available(tier: &TicketTier) -> Result<(), Error> {
+    if tier.retired {
+        return Err(Error::InvalidInput {
+            msg: format!("tier id:{} is retired", tier.id),
+        });
+    }
+    if remaining(tier) == 0 {
+        return Err(Error::SoldOut {
+            msg: format!("tier id:{} is sold out", tier.id),
+        });
+    }
+    Ok(())
+}
+
+// Function to check that a tier is on sale at the given instant
+pub(crate) fn ensure_on_sale(tier: &TicketTier, now: u64) -> Result<(), Error> {
+    if now < tier.sales_start || now >= tier.sales_end {
+        return Err(Error::InvalidInput {
+            msg: format!("tier id:{} is not on sale", tier.id),
+        });
+    }
+    Ok(())
+}
+
+// Function to check a tier payload
+fn validate_payload(payload: &TierPayload) -> Result<(), Error> {
+    _validate_length("name", &payload.name, MAX_NAME_LENGTH)?;
+    _validate_length("currency", &payload.currency, MAX_CURRENCY_LENGTH)?;
+    if payload.sales_end <= payload.sales_start {
+        return Err(Error::InvalidInput {
+            msg: "tier sales must end after they start".to_string(),
+        });
+    }
+    let ledger_configured = payload
+        .ledger_id
+        .is_some_and(|id| ledger::is_configured(&id));
+    if payload.price_e8s > 0 && !ledger_configured {
+        return Err(Error::InvalidInput {
+            msg: "a priced tier needs a configured ledger id".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Function to load the tier of an event and check that the caller manages the event
+fn managed_tier(event_id: u64, tier_id: u64, caller: &Principal) -> Result<TicketTier, Error> {
+    let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+    crate::roles::ensure_event_manager(&event, caller)?;
+    get_tier(event_id, tier_id)?.ok_or(Error::NotFound {
+        msg: format!(
+            "tier id:{} does not exist for event id:{}",
+            tier_id, event_id
+        ),
+    })
+}
+
+#[ic_cdk::query]
+fn list_tiers(event_id: u64) -> Result<Vec<TicketTier>, Error> {
+    // Check that the event with the given ID exists, or return a NotFound error if not found
+    if _get_event(&event_id)?.is_none() {
+        return Err(Error::NotFound {
+            msg: format!("event id:{} does not exist", event_id),
+        });
+    }
+    event_tiers(event_id)
+}
+
+#[ic_cdk::update]
+fn add_tier(event_id: u64, payload: TierPayload) -> Result<TicketTier, Error> {
+    let caller = _authenticated_caller()?;
+
+    // Only the organizer who owns the event, or an admin, may add tiers to it
+    let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+    crate::roles::ensure_event_manager(&event, &caller)?;
+    validate_payload(&payload)?;
+
+    // Increment the global ID counter to get a new ID for the tier
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+
+    let tier = TicketTier {
+        id,
+        event_id,
+        name: payload.name,
+        price_e8s: payload.price_e8s,
+        currency: payload.currency,
+        ledger_id: payload.ledger_id,
+        capacity: payload.capacity,
+        sales_start: payload.sales_start,
+        sales_end: payload.sales_end,
+        retired: false,
+        created_at: time(),
+        updated_at: None,
+    };
+
+    // Insert the new tier into the storage
+    let record = Versioned::try_new(&tier)?;
+    TIER_STORAGE.with(|tiers| tiers.borrow_mut().insert((event_id, id), record));
+    Ok(tier)
+}
+
+#[ic_cdk::update]
+fn update_tier(event_id: u64, tier_id: u64, payload: TierPayload) -> Result<TicketTier, Error> {
+    let caller = _authenticated_caller()?;
+    let tier = managed_tier(event_id, tier_id, &caller)?;
+    validate_payload(&payload)?;
+
+    // The capacity cannot drop below the number of tickets already sold
+    let sold = sold(tier_id);
+    if (payload.capacity as usize) < sold {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "tier id:{} already sold {} tickets, capacity cannot be lowered to {}",
+                tier_id, sold, payload.capacity
+            ),
+        });
+    }
+
+    // Tickets already sold keep the price they were bought at
+    let updated_tier = TicketTier {
+        id: tier_id,
+        event_id,
+        name: payload.name,
+        price_e8s: payload.price_e8s,
+        currency: payload.currency,
+        ledger_id: payload.ledger_id,
+        capacity: payload.capacity,
+        sales_start: payload.sales_start,
+        sales_end: payload.sales_end,
+        retired: tier.retired,
+        created_at: tier.created_at,
+        updated_at: Some(time()),
+    };
+
+    // Insert the updated tier into the storage
+    let record = Versioned::try_new(&updated_tier)?;
+    TIER_STORAGE.with(|tiers| tiers.borrow_mut().insert((event_id, tier_id), record));
+
+    // Offer the tickets a larger capacity adds to the users waiting for the tier
+    if updated_tier.capacity > tier.capacity {
+        crate::waitlist::promote(event_id);
+    }
+    Ok(updated_tier)
+}
+
+#[ic_cdk::update]
+fn retire_tier(event_id: u64, tier_id: u64) -> Result<TicketTier, Error> {
+    let caller = _authenticated_caller()?;
+    let mut tier = managed_tier(event_id, tier_id, &caller)?;
+
+    // Stop selling the tier; tickets already sold stay valid
+    tier.retired = true;
+    tier.updated_at = Some(time());
+    TIER_STORAGE.with(|tiers| {
+        tiers
+            .borrow_mut()
+            .insert((event_id, tier_id), Versioned::new(&tier))
+    });
+    Ok(tier)
+}
@@ -0,0 +1,198 @@
+use crate::{
+    _authenticated_caller, _ensure_ticket_owner, _get_event, _get_ticket, _get_user,
+    _get_user_id_by_principal, _link_ticket, _unlink_ticket, codes, icrc37, lifecycle, Error,
+    Event, Memory, Record, Ticket, TicketStatus, Versioned, MEMORY_MANAGER, TICKET_STORAGE,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Define a struct for the rules an event sets on holders passing their tickets on
+#[derive(candid::CandidType, Clone, Copy, Default, Serialize, Deserialize)]
+pub(crate) struct TransferRules {
+    // Whether holders may transfer their tickets at all
+    allowed: bool,
+    // Transfers close this long before the event starts, in nanoseconds
+    cutoff_nanos: u64,
+    // Most times a ticket may change holders, or None for no limit
+    max_transfers: Option<u32>,
+}
+
+// Define a struct for one move of a ticket from a holder to another
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TicketTransfer {
+    from_user_id: u64,
+    to_user_id: u64,
+    actor: Principal,
+    at: u64,
+}
+
+impl Record for TicketTransfer {
+    const KIND: &'static str = "ticket transfer";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 128;
+}
+
+thread_local! {
+    // Holder changes of each ticket, keyed by ticket and by position in its history
+    static TRANSFER_HISTORY: RefCell<StableBTreeMap<(u64, u32), Versioned<TicketTransfer>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+}
+
+// Function to count the times a ticket changed holders
+fn transfer_count(ticket_id: u64) -> u32 {
+    TRANSFER_HISTORY.with(|history| {
+        history
+            .borrow()
+            .range((ticket_id, 0)..=(ticket_id, u32::MAX))
+            .count() as u32
+    })
+}
+
+// Function to append a holder change to the history of a ticket
+pub(crate) fn record(ticket_id: u64, from_user_id: u64, to_user_id: u64, actor: Principal) {
+    let position = transfer_count(ticket_id);
+    TRANSFER_HISTORY.with(|history| {
+        history.borrow_mut().insert(
+            (ticket_id, position),
+            Versioned::new(&TicketTransfer {
+                from_user_id,
+                to_user_id,
+                actor,
+                at: time(),
+            }),
+        )
+    });
+}
+
+// Function to remove the transfer history of a deleted ticket
+pub(crate) fn remove_history(ticket_id: u64) {
+    TRANSFER_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let keys: Vec<(u64, u32)> = history
+            .range((ticket_id, 0)..=(ticket_id, u32::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            history.remove(&key);
+        }
+    });
+}
+
+// Function to get the instant transfers of an event's tickets close
+pub(crate) fn closes_at(event: &Event) -> u64 {
+    event
+        .starts_at
+        .saturating_sub(event.transfer_rules.cutoff_nanos)
+}
+
+// Function to check that the rules of its event let a ticket change holders at the given instant
+pub(crate) fn ensure_transferable(event: &Event, ticket: &Ticket, now: u64) -> Result<(), Error> {
+    let rules = &event.transfer_rules;
+    if !rules.allowed || event.cancelled_at.is_some() {
+        return Err(Error::Conflict {
+            msg: format!("tickets for event id:{} cannot be transferred", event.id),
+        });
+    }
+    if now >= closes_at(event) {
+        return Err(Error::Conflict {
+            msg: format!("transfers for event id:{} are closed", event.id),
+        });
+    }
+    if rules
+        .max_transfers
+        .is_some_and(|max| transfer_count(ticket.id) >= max)
+    {
+        return Err(Error::Conflict {
+            msg: format!(
+                "ticket id:{} was already transferred the most times allowed",
+                ticket.id
+            ),
+        });
+    }
+    lifecycle::ensure_transition(ticket, TicketStatus::Transferred)
+}
+
+// Function to find the user bound to the principal a ticket is sent to
+pub(crate) fn recipient_user_id(to: &Principal) -> Result<u64, Error> {
+    _get_user_id_by_principal(to).ok_or(Error::NotFound {
+        msg: format!("principal {} has no user", to),
+    })
+}
+
+// Function to move a ticket to another holder, keeping its relations and histories in step, and
+// store it
+pub(crate) fn reassign(
+    mut ticket: Ticket,
+    to_user_id: u64,
+    actor: Principal,
+) -> Result<Ticket, Error> {
+    let previous = ticket.clone();
+    lifecycle::transition(&mut ticket, TicketStatus::Transferred, actor)?;
+    ticket.user_id = to_user_id;
+    TICKET_STORAGE.with(|tickets| {
+        tickets
+            .borrow_mut()
+            .insert(ticket.id, Versioned::new(&ticket))
+    });
+    _unlink_ticket(&previous);
+    _link_ticket(&ticket)?;
+    record(ticket.id, previous.user_id, to_user_id, actor);
+    icrc37::clear_token_approvals(ticket.id);
+    codes::renew_nonce(ticket.id);
+    Ok(ticket)
+}
+
+#[ic_cdk::update]
+fn transfer_ticket(ticket_id: u64, to: Principal) -> Result<Ticket, Error> {
+    let caller = _authenticated_caller()?;
+
+    // Retrieve the ticket and its event, or return a NotFound error if either is missing
+    let ticket = _get_ticket(&ticket_id)?.ok_or(Error::NotFound {
+        msg: format!("ticket id:{} does not exist", ticket_id),
+    })?;
+    let event = _get_event(&ticket.event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", ticket.event_id),
+    })?;
+
+    // Only the holder of the ticket may pass it on, to another registered user
+    let is_holder = _get_user(&ticket.user_id)?.is_some_and(|user| user.principal == caller);
+    if !is_holder {
+        return Err(Error::Unauthorized {
+            msg: format!("caller does not hold ticket id:{}", ticket_id),
+        });
+    }
+    let to_user_id = recipient_user_id(&to)?;
+    if to_user_id == ticket.user_id {
+        return Err(Error::InvalidInput {
+            msg: format!("ticket id:{} is already held by {}", ticket_id, to),
+        });
+    }
+
+    // The event decides whether, until when and how often its tickets change holders
+    ensure_transferable(&event, &ticket, time())?;
+    reassign(ticket, to_user_id, caller)
+}
+
+#[ic_cdk::query]
+fn get_transfer_history(id: u64) -> Result<Vec<TicketTransfer>, Error> {
+    // Retrieve the ticket with the given ID, or return a NotFound error if not found
+    let ticket = _get_ticket(&id)?.ok_or(Error::NotFound {
+        msg: format!("ticket id:{} does not exist", id),
+    })?;
+
+    // Only the holder of the ticket or a manager of its event may read its transfers
+    _ensure_ticket_owner(&ticket, &ic_cdk::caller())?;
+
+    TRANSFER_HISTORY.with(|history| {
+        history
+            .borrow()
+            .range((id, 0)..=(id, u32::MAX))
+            .map(|(_, transfer)| transfer.decode())
+            .collect()
+    })
+}
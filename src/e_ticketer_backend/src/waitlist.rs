@@ -0,0 +1,564 @@
+use crate::{
+    _authenticated_caller, _collect_payment, _ensure_event_on_sale, _ensure_on_sale, _get_event,
+    _get_ticket, _get_user, _get_user_id_by_principal, _issue_reserved_ticket, _mint_ticket,
+    _remaining_capacity, _resolve_tier, _return_payment_on_failure, lifecycle, roles, seating,
+    tiers, Error, Memory, Record, Ticket, TicketPayload, TicketStatus, Versioned, MEMORY_MANAGER,
+    TICKET_STORAGE,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// How long a user offered a ticket from the waitlist has to accept it
+const OFFER_DURATION: Duration = Duration::from_secs(30 * 60);
+
+// Delay before an expired offer whose payment is still in flight is looked at again
+const EXPIRY_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+// Define a struct for a user waiting for a ticket of a sold-out event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct WaitlistEntry {
+    user_id: u64,
+    // Tier the user waits for, or None for events that sell no tiers
+    tier_id: Option<u64>,
+    joined_at: u64,
+}
+
+impl Record for WaitlistEntry {
+    const KIND: &'static str = "waitlist entry";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 128;
+}
+
+// Define a struct for a ticket reserved for the user at the head of a waitlist, until it is accepted
+// or the offer expires
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct WaitlistOffer {
+    user_id: u64,
+    offered_at: u64,
+    expires_at: u64,
+}
+
+impl Record for WaitlistOffer {
+    const KIND: &'static str = "waitlist offer";
+    const VERSION: u32 = 1;
+    const MAX_SIZE: u32 = 128;
+}
+
+// Define an enum for where a user stands on the waitlist of an event
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) enum WaitlistStatus {
+    // Waiting in line; the user at position 1 is offered the next ticket that frees up
+    Waiting { position: u64, tier_id: Option<u64> },
+    // Offered a reserved ticket, which the user accepts with `accept_waitlist_offer` until
+    // `expires_at`
+    Offered { ticket_id: u64, expires_at: u64 },
+    NotWaiting,
+}
+
+// Define a struct for an offer being paid for
+struct PendingAcceptance {
+    event_id: u64,
+    user_id: u64,
+}
+
+thread_local! {
+    // Waiting users in the order they joined, keyed by `(event_id, position)`
+    static WAITLIST: RefCell<StableBTreeMap<(u64, u64), Versioned<WaitlistEntry>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+
+    // Outstanding offers, keyed by `(event_id, ticket_id)` of the reserved ticket
+    static WAITLIST_OFFERS: RefCell<StableBTreeMap<(u64, u64), Versioned<WaitlistOffer>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    // Offers whose payment is waiting on the ledger, by ticket ID; the canister is stopped before
+    // upgrades, so none survive one
+    static PENDING_ACCEPTANCES: RefCell<BTreeMap<u64, PendingAcceptance>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+// Define a struct marking an offer as being paid for until the payment completes or fails, releasing
+// it when dropped
+struct AcceptanceHold {
+    ticket_id: u64,
+}
+
+impl AcceptanceHold {
+    fn new(ticket: &Ticket) -> Result<Self, Error> {
+        PENDING_ACCEPTANCES.with(|acceptances| {
+            let mut acceptances = acceptances.borrow_mut();
+            if acceptances.contains_key(&ticket.id) {
+                return Err(Error::Conflict {
+                    msg: format!("ticket id:{} is already being paid for", ticket.id),
+                });
+            }
+            acceptances.insert(
+                ticket.id,
+                PendingAcceptance {
+                    event_id: ticket.event_id,
+                    user_id: ticket.user_id,
+                },
+            );
+            Ok(AcceptanceHold {
+                ticket_id: ticket.id,
+            })
+        })
+    }
+}
+
+impl Drop for AcceptanceHold {
+    fn drop(&mut self) {
+        PENDING_ACCEPTANCES.with(|acceptances| acceptances.borrow_mut().remove(&self.ticket_id));
+    }
+}
+
+// Function to count the offers being paid for in an event
+pub(crate) fn pending_for_event(event_id: u64) -> usize {
+    PENDING_ACCEPTANCES.with(|acceptances| {
+        acceptances
+            .borrow()
+            .values()
+            .filter(|acceptance| acceptance.event_id == event_id)
+            .count()
+    })
+}
+
+// Function to count the offers a user is paying for
+pub(crate) fn pending_for_user(user_id: u64) -> usize {
+    PENDING_ACCEPTANCES.with(|acceptances| {
+        acceptances
+            .borrow()
+            .values()
+            .filter(|acceptance| acceptance.user_id == user_id)
+            .count()
+    })
+}
+
+// Function to check whether an offered ticket is being paid for
+pub(crate) fn is_pending(ticket_id: u64) -> bool {
+    PENDING_ACCEPTANCES.with(|acceptances| acceptances.borrow().contains_key(&ticket_id))
+}
+
+// Function to withdraw the offer made with a ticket, if it has one, once the ticket changes status
+pub(crate) fn withdraw_offer(ticket: &Ticket) {
+    WAITLIST_OFFERS.with(|offers| offers.borrow_mut().remove(&(ticket.event_id, ticket.id)));
+}
+
+// Function to remove the waitlist and offers of a deleted event
+pub(crate) fn remove_event(event_id: u64) {
+    remove_entries(|(entry_event_id, _), _| *entry_event_id == event_id);
+    WAITLIST_OFFERS.with(|offers| {
+        let mut offers = offers.borrow_mut();
+        let keys: Vec<(u64, u64)> = offers
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            offers.remove(&key);
+        }
+    });
+}
+
+// Function to take a deleted user off every waitlist, dropping the offers made to it along with its
+// reserved tickets
+pub(crate) fn remove_user(user_id: u64) {
+    remove_entries(|_, entry| entry.user_id == user_id);
+    WAITLIST_OFFERS.with(|offers| {
+        let mut offers = offers.borrow_mut();
+        let keys: Vec<(u64, u64)> = offers
+            .iter()
+            .filter(|(_, offer)| offer.decode().is_ok_and(|offer| offer.user_id == user_id))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            offers.remove(&key);
+        }
+    });
+}
+
+// Function to remove the waitlist entries that match a condition
+fn remove_entries(matches: impl Fn(&(u64, u64), &WaitlistEntry) -> bool) {
+    WAITLIST.with(|waitlist| {
+        let mut waitlist = waitlist.borrow_mut();
+        let keys: Vec<(u64, u64)> = waitlist
+            .iter()
+            .filter(|(key, entry)| entry.decode().is_ok_and(|entry| matches(key, &entry)))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            waitlist.remove(&key);
+        }
+    });
+}
+
+// Function to close the waitlist of a cancelled event, cancelling the tickets reserved for offers
+// that are not being paid for; a ticket issued from an offer still being paid for is refunded along
+// with the others
+pub(crate) fn close(event_id: u64, actor: Principal) {
+    remove_entries(|(entry_event_id, _), _| *entry_event_id == event_id);
+    for ticket_id in offered_ticket_ids(event_id, |_| true) {
+        let Ok(Some(mut ticket)) = _get_ticket(&ticket_id) else {
+            continue;
+        };
+        if lifecycle::transition(&mut ticket, TicketStatus::Cancelled, actor).is_ok() {
+            TICKET_STORAGE.with(|tickets| {
+                tickets
+                    .borrow_mut()
+                    .insert(ticket.id, Versioned::new(&ticket))
+            });
+        }
+    }
+}
+
+// Function to list the tickets reserved for the offers of an event that match a condition
+fn offered_ticket_ids(event_id: u64, matches: impl Fn(&WaitlistOffer) -> bool) -> Vec<u64> {
+    WAITLIST_OFFERS.with(|offers| {
+        offers
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .filter(|(_, offer)| offer.decode().is_ok_and(|offer| matches(&offer)))
+            .map(|((_, ticket_id), _)| ticket_id)
+            .collect()
+    })
+}
+
+// Function to offer the tickets an event has free to the users at the head of its waitlist, in the
+// order they joined. Users waiting for a tier that is still sold out keep their place, as does a user
+// whose ticket fails to be reserved, and entries that can no longer be served are dropped
+pub(crate) fn promote(event_id: u64) {
+    let Ok(Some(event)) = _get_event(&event_id) else {
+        return;
+    };
+    if event.cancelled_at.is_some() || seating::is_seated(event_id) {
+        return;
+    }
+    let entries: Vec<((u64, u64), Versioned<WaitlistEntry>)> = WAITLIST.with(|waitlist| {
+        waitlist
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .collect()
+    });
+    // The capacity is counted once and each offer takes one of the tickets left
+    let mut remaining = _remaining_capacity(&event);
+    let now = time();
+    for (key, entry) in entries {
+        if remaining == 0 {
+            break;
+        }
+        let Ok(entry) = entry.decode() else {
+            WAITLIST.with(|waitlist| waitlist.borrow_mut().remove(&key));
+            continue;
+        };
+        let tier = match _resolve_tier(event_id, entry.tier_id) {
+            Err(Error::SoldOut { .. }) => continue,
+            resolved => resolved,
+        };
+        let servable = tier.is_ok_and(|tier| tier.is_none_or(|tier| now < tier.sales_end))
+            && _get_user(&entry.user_id).is_ok_and(|user| user.is_some());
+        if !servable {
+            WAITLIST.with(|waitlist| waitlist.borrow_mut().remove(&key));
+            continue;
+        }
+
+        // Reserve a ticket for the user, which takes its seat until the offer is accepted or
+        // expires; a user whose ticket cannot be reserved keeps their place at the head of the
+        // waitlist until the next promotion
+        let payload = TicketPayload {
+            event_id,
+            user_id: entry.user_id,
+            tier_id: entry.tier_id,
+        };
+        let Ok(ticket) = _mint_ticket(
+            &payload,
+            None,
+            0,
+            None,
+            TicketStatus::Reserved,
+            ic_cdk::id(),
+        ) else {
+            break;
+        };
+        WAITLIST.with(|waitlist| waitlist.borrow_mut().remove(&key));
+        remaining -= 1;
+        let expires_at = now.saturating_add(OFFER_DURATION.as_nanos() as u64);
+        WAITLIST_OFFERS.with(|offers| {
+            offers.borrow_mut().insert(
+                (event_id, ticket.id),
+                Versioned::new(&WaitlistOffer {
+                    user_id: entry.user_id,
+                    offered_at: now,
+                    expires_at,
+                }),
+            )
+        });
+        schedule_expiry(event_id, OFFER_DURATION);
+    }
+}
+
+// Function to expire the offers of an event once their time is up
+fn schedule_expiry(event_id: u64, delay: Duration) {
+    ic_cdk_timers::set_timer(delay, move || expire_offers(event_id));
+}
+
+// Function to restart the expiry of every outstanding offer; timers do not survive upgrades, so it
+// runs after every upgrade
+pub(crate) fn schedule_expiries() {
+    let now = time();
+    let offers: Vec<(u64, u64)> = WAITLIST_OFFERS.with(|offers| {
+        offers
+            .borrow()
+            .iter()
+            .filter_map(|((event_id, _), offer)| Some((event_id, offer.decode().ok()?.expires_at)))
+            .collect()
+    });
+    for (event_id, expires_at) in offers {
+        schedule_expiry(
+            event_id,
+            Duration::from_nanos(expires_at.saturating_sub(now)),
+        );
+    }
+}
+
+// Function to expire the offers of an event that ran out, rolling their tickets on to the next users
+// in line. Offers being paid for are looked at again shortly, once their payment has settled
+fn expire_offers(event_id: u64) {
+    let now = time();
+    let mut retry = false;
+    for ticket_id in offered_ticket_ids(event_id, |offer| offer.expires_at <= now) {
+        if is_pending(ticket_id) {
+            retry = true;
+            continue;
+        }
+        if let Ok(Some(mut ticket)) = _get_ticket(&ticket_id) {
+            if lifecycle::transition(&mut ticket, TicketStatus::Expired, ic_cdk::id()).is_ok() {
+                TICKET_STORAGE.with(|tickets| {
+                    tickets
+                        .borrow_mut()
+                        .insert(ticket.id, Versioned::new(&ticket))
+                });
+            }
+        }
+        WAITLIST_OFFERS.with(|offers| offers.borrow_mut().remove(&(event_id, ticket_id)));
+    }
+    if retry {
+        schedule_expiry(event_id, EXPIRY_RETRY_DELAY);
+    }
+    promote(event_id);
+}
+
+// Function to find where a user stands on the waitlist of an event
+fn status(event_id: u64, user_id: u64) -> WaitlistStatus {
+    let offer = WAITLIST_OFFERS.with(|offers| {
+        offers
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .filter_map(|(key, offer)| Some((key, offer.decode().ok()?)))
+            .find(|(_, offer)| offer.user_id == user_id)
+    });
+    if let Some(((_, ticket_id), offer)) = offer {
+        return WaitlistStatus::Offered {
+            ticket_id,
+            expires_at: offer.expires_at,
+        };
+    }
+    WAITLIST.with(|waitlist| {
+        waitlist
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .enumerate()
+            .filter_map(|(index, (_, entry))| Some((index, entry.decode().ok()?)))
+            .find(|(_, entry)| entry.user_id == user_id)
+            .map_or(WaitlistStatus::NotWaiting, |(index, entry)| {
+                WaitlistStatus::Waiting {
+                    position: index as u64 + 1,
+                    tier_id: entry.tier_id,
+                }
+            })
+    })
+}
+
+// Function to get the user bound to the caller
+fn caller_user_id(caller: &Principal) -> Result<u64, Error> {
+    _get_user_id_by_principal(caller).ok_or(Error::NotFound {
+        msg: "caller is not registered as a user".to_string(),
+    })
+}
+
+#[ic_cdk::update]
+fn join_waitlist(event_id: u64, tier_id: Option<u64>) -> Result<WaitlistStatus, Error> {
+    let caller = _authenticated_caller()?;
+    let user_id = caller_user_id(&caller)?;
+
+    // Retrieve the event, or return a NotFound error if not found
+    let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+    _ensure_on_sale(&event)?;
+    seating::ensure_general_admission(event_id)?;
+
+    // Only sold-out events and tiers have a waitlist; any other refusal of the tier stands
+    let tier_sold_out = match _resolve_tier(event_id, tier_id) {
+        Ok(_) => false,
+        Err(Error::SoldOut { .. }) => true,
+        Err(error) => return Err(error),
+    };
+    if _remaining_capacity(&event) > 0 && !tier_sold_out {
+        return Err(Error::Conflict {
+            msg: format!("event id:{} still has tickets on sale", event_id),
+        });
+    }
+
+    // A user waits in one place per event
+    if !matches!(status(event_id, user_id), WaitlistStatus::NotWaiting) {
+        return Err(Error::Conflict {
+            msg: format!(
+                "user id:{} is already on the waitlist of event id:{}",
+                user_id, event_id
+            ),
+        });
+    }
+
+    // Join at the back of the line
+    WAITLIST.with(|waitlist| {
+        let mut waitlist = waitlist.borrow_mut();
+        let position = waitlist
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .last()
+            .map_or(0, |((_, position), _)| position + 1);
+        waitlist.insert(
+            (event_id, position),
+            Versioned::new(&WaitlistEntry {
+                user_id,
+                tier_id,
+                joined_at: time(),
+            }),
+        )
+    });
+    Ok(status(event_id, user_id))
+}
+
+#[ic_cdk::update]
+fn leave_waitlist(event_id: u64) -> Result<String, Error> {
+    let caller = _authenticated_caller()?;
+    let user_id = caller_user_id(&caller)?;
+
+    // Leave the line, or decline the offer made to the caller, passing the ticket to the next user
+    match status(event_id, user_id) {
+        WaitlistStatus::Waiting { .. } => {
+            remove_entries(|(entry_event_id, _), entry| {
+                *entry_event_id == event_id && entry.user_id == user_id
+            });
+        }
+        WaitlistStatus::Offered { ticket_id, .. } => {
+            let mut ticket = _get_ticket(&ticket_id)?.ok_or(Error::NotFound {
+                msg: format!("ticket id:{} does not exist", ticket_id),
+            })?;
+            lifecycle::transition(&mut ticket, TicketStatus::Cancelled, caller)?;
+            TICKET_STORAGE.with(|tickets| {
+                tickets
+                    .borrow_mut()
+                    .insert(ticket.id, Versioned::new(&ticket))
+            });
+            promote(event_id);
+        }
+        WaitlistStatus::NotWaiting => {
+            return Err(Error::NotFound {
+                msg: format!(
+                    "user id:{} is not on the waitlist of event id:{}",
+                    user_id, event_id
+                ),
+            })
+        }
+    }
+    Ok(format!(
+        "user id: {} left the waitlist of event id: {}",
+        user_id, event_id
+    ))
+}
+
+#[ic_cdk::query]
+fn get_waitlist_status(event_id: u64) -> Result<WaitlistStatus, Error> {
+    let user_id = caller_user_id(&ic_cdk::caller())?;
+    Ok(status(event_id, user_id))
+}
+
+#[ic_cdk::query]
+fn list_waitlist(event_id: u64) -> Result<Vec<WaitlistEntry>, Error> {
+    // Retrieve the event with the given ID, or return a NotFound error if not found
+    let event = _get_event(&event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", event_id),
+    })?;
+
+    // Only the organizer who owns the event, or an admin, may read its waitlist
+    roles::ensure_event_manager(&event, &ic_cdk::caller())?;
+
+    WAITLIST.with(|waitlist| {
+        waitlist
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, entry)| entry.decode())
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+async fn accept_waitlist_offer(ticket_id: u64) -> Result<Ticket, Error> {
+    let caller = _authenticated_caller()?;
+
+    // Retrieve the reserved ticket and the offer made with it, or return a NotFound error
+    let ticket = _get_ticket(&ticket_id)?.ok_or(Error::NotFound {
+        msg: format!("ticket id:{} does not exist", ticket_id),
+    })?;
+    let offer = WAITLIST_OFFERS
+        .with(|offers| offers.borrow().get(&(ticket.event_id, ticket_id)))
+        .map(|offer| offer.decode())
+        .transpose()?
+        .ok_or(Error::NotFound {
+            msg: format!("ticket id:{} is not offered from a waitlist", ticket_id),
+        })?;
+
+    // Only the user the ticket was offered to may accept it, before the offer expires
+    if _get_user_id_by_principal(&caller) != Some(offer.user_id) {
+        return Err(Error::Unauthorized {
+            msg: format!("ticket id:{} was offered to another user", ticket_id),
+        });
+    }
+    if offer.expires_at <= time() {
+        return Err(Error::Conflict {
+            msg: format!("the offer of ticket id:{} has expired", ticket_id),
+        });
+    }
+    let event = _get_event(&ticket.event_id)?.ok_or(Error::NotFound {
+        msg: format!("event id:{} does not exist", ticket.event_id),
+    })?;
+    _ensure_on_sale(&event)?;
+
+    // The seat is already reserved, so the tier's current price is paid without checking its sales
+    // window or capacity again; the offer cannot expire or be withdrawn while the payment is in flight
+    let tier = match ticket.tier_id {
+        Some(tier_id) => tiers::get_tier(ticket.event_id, tier_id)?,
+        None => None,
+    };
+    let hold = AcceptanceHold::new(&ticket)?;
+    let payment = match &tier {
+        Some(tier) => _collect_payment(caller, tier, 1).await?,
+        None => None,
+    };
+
+    // Reload the ticket and issue it with its payment, unless the event was cancelled meanwhile
+    drop(hold);
+    let price_paid_e8s = tier.map_or(0, |tier| tier.price_e8s);
+    let issued = _ensure_event_on_sale(ticket.event_id).and_then(|()| {
+        let ticket = _get_ticket(&ticket_id)?.unwrap_or(ticket);
+        _issue_reserved_ticket(ticket, price_paid_e8s, payment.as_ref(), caller)
+    });
+    _return_payment_on_failure(issued, payment, price_paid_e8s).await
+}
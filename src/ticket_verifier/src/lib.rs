@@ -0,0 +1,298 @@
+// Verification of tickets signed by the e_ticketer backend, for door scanners that work offline.
+//
+// A signed ticket is the encoded claims followed by a 64-byte secp256k1 ECDSA signature (r || s)
+// over the SHA-256 digest of the claims. Scanners fetch the public key once from the backend's
+// `ticket_verification_key` query and can then verify tickets without connectivity.
+use k256::ecdsa::signature::hazmat::PrehashVerifier;
+use k256::ecdsa::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+// Version of the claims layout written by this crate; version 2 added the revision
+pub const CLAIMS_VERSION: u8 = 2;
+
+// Length of the signature that ends a signed ticket
+pub const SIGNATURE_LENGTH: usize = 64;
+
+// Longest principal, in bytes
+const MAX_PRINCIPAL_LENGTH: usize = 29;
+
+// Define a struct for what a signed ticket asserts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TicketClaims {
+    pub event_id: u64,
+    pub ticket_id: u64,
+    // Revision of the ticket when it was signed; the backend revises a ticket whenever it changes
+    // status, holder, event or tier, revoking the signatures of earlier revisions
+    pub revision: u32,
+    // Raw bytes of the holder's principal
+    pub holder: Vec<u8>,
+    // Tier the ticket was issued in, or None for tickets of events without tiers
+    pub tier_id: Option<u64>,
+    // The ticket admits from `valid_from` until `valid_until`, in nanoseconds since epoch
+    pub valid_from: u64,
+    pub valid_until: u64,
+}
+
+// Define an enum for the reasons a signed ticket is rejected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    // The bytes are not a signed ticket
+    Malformed,
+    // The claims were written in a layout this crate does not read
+    UnsupportedVersion(u8),
+    // The public key is not a SEC1-encoded secp256k1 key
+    InvalidKey,
+    // The signature does not match the claims and the key
+    InvalidSignature,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::Malformed => write!(f, "signed ticket is malformed"),
+            VerifyError::UnsupportedVersion(version) => {
+                write!(f, "signed ticket has unsupported version {}", version)
+            }
+            VerifyError::InvalidKey => write!(f, "verification key is not a secp256k1 key"),
+            VerifyError::InvalidSignature => write!(f, "signed ticket has an invalid signature"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl TicketClaims {
+    // Function to encode the claims: the version, the event and ticket ids, the revision, a presence
+    // flag and the tier id, the validity window, and the length-prefixed holder, all integers big-endian
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![CLAIMS_VERSION];
+        bytes.extend_from_slice(&self.event_id.to_be_bytes());
+        bytes.extend_from_slice(&self.ticket_id.to_be_bytes());
+        bytes.extend_from_slice(&self.revision.to_be_bytes());
+        bytes.push(u8::from(self.tier_id.is_some()));
+        bytes.extend_from_slice(&self.tier_id.unwrap_or_default().to_be_bytes());
+        bytes.extend_from_slice(&self.valid_from.to_be_bytes());
+        bytes.extend_from_slice(&self.valid_until.to_be_bytes());
+        bytes.push(self.holder.len() as u8);
+        bytes.extend_from_slice(&self.holder);
+        bytes
+    }
+
+    // Function to decode claims written by `encode`
+    pub fn decode(bytes: &[u8]) -> Result<Self, VerifyError> {
+        let mut reader = Reader { bytes };
+        let version = reader.u8()?;
+        if version != CLAIMS_VERSION {
+            return Err(VerifyError::UnsupportedVersion(version));
+        }
+        let event_id = reader.u64()?;
+        let ticket_id = reader.u64()?;
+        let revision = reader.u32()?;
+        let has_tier = reader.u8()?;
+        let tier_id = reader.u64()?;
+        let valid_from = reader.u64()?;
+        let valid_until = reader.u64()?;
+        let holder_length = reader.u8()? as usize;
+        if holder_length > MAX_PRINCIPAL_LENGTH {
+            return Err(VerifyError::Malformed);
+        }
+        let holder = reader.take(holder_length)?.to_vec();
+        if !reader.bytes.is_empty() {
+            return Err(VerifyError::Malformed);
+        }
+        Ok(TicketClaims {
+            event_id,
+            ticket_id,
+            revision,
+            holder,
+            tier_id: match has_tier {
+                0 => None,
+                1 => Some(tier_id),
+                _ => return Err(VerifyError::Malformed),
+            },
+            valid_from,
+            valid_until,
+        })
+    }
+
+    // Function to check whether the ticket admits at the given instant, in nanoseconds since epoch
+    pub fn is_valid_at(&self, now: u64) -> bool {
+        self.valid_from <= now && now < self.valid_until
+    }
+
+    // Function to check whether the signature was revoked, given the revision the backend's
+    // `get_revoked_signatures` query lists for the ticket
+    pub fn is_revoked(&self, revoked_below: u32) -> bool {
+        self.revision < revoked_below
+    }
+}
+
+// Define a struct reading encoded claims front to back
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], VerifyError> {
+        if self.bytes.len() < length {
+            return Err(VerifyError::Malformed);
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, VerifyError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, VerifyError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into().expect("four bytes")))
+    }
+
+    fn u64(&mut self) -> Result<u64, VerifyError> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_be_bytes(bytes.try_into().expect("eight bytes")))
+    }
+}
+
+// Function to get the digest that is signed for the given encoded claims
+pub fn message_hash(claims: &[u8]) -> [u8; 32] {
+    Sha256::digest(claims).into()
+}
+
+// Function to join encoded claims and their signature into a signed ticket
+pub fn signed_ticket(claims: &[u8], signature: &[u8]) -> Vec<u8> {
+    [claims, signature].concat()
+}
+
+// Function to verify a signed ticket against the backend's SEC1-encoded public key and return its
+// claims; callers still check `is_valid_at`, `is_revoked` and the holder
+pub fn verify(public_key: &[u8], signed_ticket: &[u8]) -> Result<TicketClaims, VerifyError> {
+    let key = VerifyingKey::from_sec1_bytes(public_key).map_err(|_| VerifyError::InvalidKey)?;
+    if signed_ticket.len() < SIGNATURE_LENGTH {
+        return Err(VerifyError::Malformed);
+    }
+    let (claims, signature) = signed_ticket.split_at(signed_ticket.len() - SIGNATURE_LENGTH);
+    let signature = Signature::from_slice(signature).map_err(|_| VerifyError::InvalidSignature)?;
+    key.verify_prehash(&message_hash(claims), &signature)
+        .map_err(|_| VerifyError::InvalidSignature)?;
+    TicketClaims::decode(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::signature::hazmat::PrehashSigner;
+    use k256::ecdsa::SigningKey;
+
+    fn signing_key(seed: u8) -> SigningKey {
+        SigningKey::from_slice(&[seed; 32]).expect("valid secret key")
+    }
+
+    fn public_key(key: &SigningKey) -> Vec<u8> {
+        key.verifying_key()
+            .to_encoded_point(true)
+            .as_bytes()
+            .to_vec()
+    }
+
+    fn claims() -> TicketClaims {
+        TicketClaims {
+            event_id: 7,
+            ticket_id: 42,
+            revision: 3,
+            holder: vec![1, 2, 3, 4],
+            tier_id: Some(2),
+            valid_from: 1_000,
+            valid_until: 2_000,
+        }
+    }
+
+    // Function to sign claims the way the backend does
+    fn sign(key: &SigningKey, claims: &TicketClaims) -> Vec<u8> {
+        let claims = claims.encode();
+        let signature: Signature = key
+            .sign_prehash(&message_hash(&claims))
+            .expect("prehash is 32 bytes");
+        signed_ticket(&claims, &signature.to_bytes())
+    }
+
+    #[test]
+    fn signed_ticket_round_trips() {
+        let key = signing_key(1);
+        let signed = sign(&key, &claims());
+
+        assert_eq!(verify(&public_key(&key), &signed), Ok(claims()));
+
+        let untiered = TicketClaims {
+            tier_id: None,
+            ..claims()
+        };
+        assert_eq!(
+            verify(&public_key(&key), &sign(&key, &untiered)),
+            Ok(untiered)
+        );
+    }
+
+    #[test]
+    fn tampered_payload_is_rejected() {
+        let key = signing_key(1);
+        let mut signed = sign(&key, &claims());
+
+        // Byte 9 is the first byte of the ticket id
+        signed[9] ^= 1;
+        assert_eq!(
+            verify(&public_key(&key), &signed),
+            Err(VerifyError::InvalidSignature)
+        );
+
+        let truncated = &signed[..SIGNATURE_LENGTH - 1];
+        assert_eq!(
+            verify(&public_key(&key), truncated),
+            Err(VerifyError::Malformed)
+        );
+    }
+
+    #[test]
+    fn ticket_signed_with_another_key_is_rejected() {
+        let signed = sign(&signing_key(1), &claims());
+
+        assert_eq!(
+            verify(&public_key(&signing_key(2)), &signed),
+            Err(VerifyError::InvalidSignature)
+        );
+        assert_eq!(verify(&[4; 33], &signed), Err(VerifyError::InvalidKey));
+    }
+
+    #[test]
+    fn ticket_only_admits_within_its_window() {
+        let claims = claims();
+
+        assert!(!claims.is_valid_at(999));
+        assert!(claims.is_valid_at(1_000));
+        assert!(claims.is_valid_at(1_999));
+        assert!(!claims.is_valid_at(2_000));
+    }
+
+    #[test]
+    fn earlier_revisions_are_revoked() {
+        let claims = claims();
+
+        assert!(!claims.is_revoked(0));
+        assert!(!claims.is_revoked(3));
+        assert!(claims.is_revoked(4));
+    }
+
+    #[test]
+    fn claims_of_another_layout_are_rejected() {
+        let mut encoded = claims().encode();
+        encoded[0] = 1;
+
+        assert_eq!(
+            TicketClaims::decode(&encoded),
+            Err(VerifyError::UnsupportedVersion(1))
+        );
+    }
+}